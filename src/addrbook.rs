@@ -0,0 +1,191 @@
+// Every peer address a node has heard of, with when it last heard of it, kept
+// in the data directory so a restarted node knows where to look for the
+// network. One peer per line: "<ip>:<port> <unix seconds>".
+
+const BOOK_FILE: &str = "peers";
+const MAX_PEERS: usize = 256;
+
+pub struct AddressBook {
+    //None keeps the book in memory only
+    path: std::option::Option<std::path::PathBuf>,
+    peers: std::collections::BTreeMap<std::net::SocketAddr, u64>,
+    dirty: bool,
+}
+
+impl AddressBook {
+    pub fn in_memory() -> Self {
+        AddressBook {
+            path: None,
+            peers: std::collections::BTreeMap::new(),
+            dirty: false,
+        }
+    }
+
+    // Reads the book kept in the data directory, an empty one if there is none yet.
+    pub fn load(dir: &std::path::Path) -> std::io::Result<Self> {
+        let path: std::path::PathBuf = dir.join(BOOK_FILE);
+        let mut peers: std::collections::BTreeMap<std::net::SocketAddr, u64> = std::collections::BTreeMap::new();
+        match std::fs::read_to_string(&path) {
+            Ok(text) => {
+                //lines that don't parse are skipped, the rest of the book is still good
+                for line in text.lines() {
+                    let mut parts = line.split_whitespace();
+                    let addr: std::option::Option<std::net::SocketAddr> = parts.next().and_then(|addr| addr.parse().ok());
+                    let seen: std::option::Option<u64> = parts.next().and_then(|seen| seen.parse().ok());
+                    if let (Some(addr), Some(seen)) = (addr, seen) {
+                        peers.insert(addr, seen);
+                    }
+                }
+            },
+            Err(ref error) if error.kind() == std::io::ErrorKind::NotFound => {},
+            Err(error) => return Err(error),
+        };
+
+        Ok(AddressBook {
+            path: Some(path),
+            peers,
+            dirty: false,
+        })
+    }
+
+    // Marks addr as seen just now, dropping the stalest peer if the book is full.
+    pub fn insert(&mut self, addr: std::net::SocketAddr) {
+        let now: u64 = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).map(|since| since.as_secs()).unwrap_or(0);
+        if self.peers.insert(addr, now) == Some(now) {
+            return;
+        }
+        if self.peers.len() > MAX_PEERS {
+            if let Some(stalest) = self.peers.iter().min_by_key(|(_, seen)| **seen).map(|(addr, _)| *addr) {
+                self.peers.remove(&stalest);
+            }
+        }
+        self.dirty = true;
+    }
+
+    pub fn remove(&mut self, addr: &std::net::SocketAddr) {
+        if self.peers.remove(addr).is_some() {
+            self.dirty = true;
+        }
+    }
+
+    // In address order.
+    pub fn addrs(&self) -> Vec<std::net::SocketAddr> {
+        self.peers.keys().copied().collect()
+    }
+
+    // Most recently seen first.
+    pub fn recent(&self) -> Vec<std::net::SocketAddr> {
+        let mut peers: Vec<(&std::net::SocketAddr, &u64)> = self.peers.iter().collect();
+        peers.sort_by(|a, b| b.1.cmp(a.1).then(a.0.cmp(b.0)));
+        peers.into_iter().map(|(addr, _)| *addr).collect()
+    }
+
+    // Writes the book out if it changed since the last save. A book that
+    // can't be written stays in memory from then on.
+    pub fn save(&mut self) -> std::io::Result<()> {
+        let path: std::path::PathBuf = match (&self.path, self.dirty) {
+            (Some(path), true) => path.clone(),
+            _ => return Ok(()),
+        };
+
+        let mut text: String = String::new();
+        for (addr, seen) in &self.peers {
+            text.push_str(&format!("{} {}\n", addr, seen));
+        }
+        match write_atomic(&path, &text) {
+            Ok(_) => {
+                self.dirty = false;
+                Ok(())
+            },
+            Err(error) => {
+                self.path = None;
+                Err(error)
+            },
+        }
+    }
+}
+
+// Writes to a temporary file first, so a crash never leaves half a book behind.
+pub fn write_atomic(path: &std::path::Path, text: &str) -> std::io::Result<()> {
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    let partial: std::path::PathBuf = path.with_extension("tmp");
+    std::fs::write(&partial, text)?;
+    std::fs::rename(&partial, path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn scratch(test: &str) -> std::path::PathBuf {
+        let dir: std::path::PathBuf = std::env::temp_dir().join(format!("prism-addrbook-{}-{}", test, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        dir
+    }
+
+    fn addr(text: &str) -> std::net::SocketAddr {
+        text.parse().unwrap()
+    }
+
+    #[test]
+    fn saved_books_load_back_the_same() {
+        let dir: std::path::PathBuf = scratch("round-trip");
+        let mut book: AddressBook = AddressBook::load(&dir).unwrap();
+        assert!(book.addrs().is_empty());
+        book.insert(addr("10.0.0.2:7000"));
+        book.insert(addr("[fe80::1]:7001"));
+        book.insert(addr("127.0.0.1:7002"));
+        book.remove(&addr("127.0.0.1:7002"));
+        book.save().unwrap();
+
+        let loaded: AddressBook = AddressBook::load(&dir).unwrap();
+        assert_eq!(loaded.peers, book.peers);
+        assert_eq!(loaded.addrs(), vec![addr("10.0.0.2:7000"), addr("[fe80::1]:7001")]);
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn unchanged_books_are_not_written() {
+        let dir: std::path::PathBuf = scratch("unchanged");
+        let mut book: AddressBook = AddressBook::load(&dir).unwrap();
+        book.save().unwrap();
+        assert!(!dir.join(BOOK_FILE).exists());
+
+        let mut memory: AddressBook = AddressBook::in_memory();
+        memory.insert(addr("10.0.0.2:7000"));
+        memory.save().unwrap();
+        assert_eq!(memory.addrs(), vec![addr("10.0.0.2:7000")]);
+    }
+
+    #[test]
+    fn bootstrap_order_is_most_recent_first() {
+        let dir: std::path::PathBuf = scratch("order");
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join(BOOK_FILE), "10.0.0.1:7000 100\n\
+                                             10.0.0.2:7000 300\n\
+                                             not an address 400\n\
+                                             10.0.0.3:7000 soon\n\
+                                             10.0.0.4:7000 200\n\
+                                             10.0.0.0:7000 300\n").unwrap();
+
+        let book: AddressBook = AddressBook::load(&dir).unwrap();
+        //lines that don't parse are dropped, ties go in address order
+        assert_eq!(book.recent(), vec![addr("10.0.0.0:7000"), addr("10.0.0.2:7000"), addr("10.0.0.4:7000"), addr("10.0.0.1:7000")]);
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn full_books_drop_the_stalest_peer() {
+        let mut book: AddressBook = AddressBook::in_memory();
+        for port in 0..MAX_PEERS as u16 {
+            book.peers.insert(addr(&format!("10.0.0.1:{}", port + 1)), 1000 + port as u64);
+        }
+        book.insert(addr("10.0.0.2:7000"));
+
+        assert_eq!(book.peers.len(), MAX_PEERS);
+        assert!(!book.peers.contains_key(&addr("10.0.0.1:1")));
+        assert_eq!(book.recent()[0], addr("10.0.0.2:7000"));
+    }
+}
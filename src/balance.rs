@@ -0,0 +1,139 @@
+// How a node that already has all the children it takes picks which child a
+// newcomer is sent on to. Children report the size and depth of the subtree
+// under them with SUBTREE frames, so the choice can look past the children
+// themselves.
+pub const DEFAULT_MAX_CHILDREN: usize = 3;
+
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub enum Rebalance {
+    // Each child in turn.
+    RoundRobin,
+    // The child with the fewest nodes under it.
+    #[default]
+    LeastLoaded,
+    // The child whose subtree is shallowest.
+    Shallowest,
+}
+
+impl std::str::FromStr for Rebalance {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        match name {
+            "round-robin" => Ok(Rebalance::RoundRobin),
+            "least-loaded" => Ok(Rebalance::LeastLoaded),
+            "shallowest" => Ok(Rebalance::Shallowest),
+            _ => Err(format!("unknown rebalance strategy {}", name)),
+        }
+    }
+}
+
+// A node and everything below it. A node with no children is size 1, depth 1.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Subtree {
+    pub size: u32,
+    pub depth: u32,
+}
+
+impl Subtree {
+    pub fn leaf() -> Self {
+        Subtree {
+            size: 1,
+            depth: 1,
+        }
+    }
+
+    // The subtree of a node with these children.
+    pub fn above<I: Iterator<Item = Subtree>>(children: I) -> Self {
+        children.fold(Subtree::leaf(), |total, child| Subtree {
+            size: total.size.saturating_add(child.size),
+            depth: total.depth.max(child.depth.saturating_add(1)),
+        })
+    }
+
+    pub fn to_bytes(self) -> [u8; 8] {
+        let mut buf = [0u8; 8];
+        buf[..4].copy_from_slice(&self.size.to_be_bytes());
+        buf[4..].copy_from_slice(&self.depth.to_be_bytes());
+        buf
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> std::option::Option<Self> {
+        if bytes.len() != 8 {
+            return None;
+        }
+        Some(Subtree {
+            size: u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]),
+            depth: u32::from_be_bytes([bytes[4], bytes[5], bytes[6], bytes[7]]),
+        })
+    }
+}
+
+// Index of the child to send a newcomer to. turn counts round-robin picks.
+pub fn pick(policy: Rebalance, children: &[Subtree], turn: &mut usize) -> std::option::Option<usize> {
+    if children.is_empty() {
+        return None;
+    }
+
+    match policy {
+        Rebalance::RoundRobin => {
+            let index: usize = *turn % children.len();
+            *turn = turn.wrapping_add(1);
+            Some(index)
+        },
+        Rebalance::LeastLoaded => (0..children.len()).min_by_key(|index| (children[*index].size, children[*index].depth)),
+        Rebalance::Shallowest => (0..children.len()).min_by_key(|index| (children[*index].depth, children[*index].size)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn subtree(size: u32, depth: u32) -> Subtree {
+        Subtree { size, depth }
+    }
+
+    #[test]
+    fn round_robin_takes_each_child_in_turn() {
+        let children: Vec<Subtree> = vec![subtree(9, 4), Subtree::leaf(), Subtree::leaf()];
+        let mut turn: usize = 0;
+        let picks: Vec<std::option::Option<usize>> = (0..4).map(|_| pick(Rebalance::RoundRobin, &children, &mut turn)).collect();
+        assert_eq!(picks, vec![Some(0), Some(1), Some(2), Some(0)]);
+        assert_eq!(turn, 4);
+
+        //a child going away doesn't stop the turns
+        assert_eq!(pick(Rebalance::RoundRobin, &children[..2], &mut turn), Some(0));
+        let mut turn: usize = usize::MAX;
+        assert_eq!(pick(Rebalance::RoundRobin, &children, &mut turn), Some(usize::MAX % 3));
+        assert_eq!(turn, 0);
+    }
+
+    #[test]
+    fn least_loaded_takes_the_smallest_subtree_then_the_shallowest() {
+        let mut turn: usize = 0;
+        assert_eq!(pick(Rebalance::LeastLoaded, &[subtree(5, 2), subtree(3, 3), subtree(4, 2)], &mut turn), Some(1));
+        assert_eq!(pick(Rebalance::LeastLoaded, &[subtree(3, 3), subtree(3, 2), subtree(4, 1)], &mut turn), Some(1));
+        //a full tie goes to the first child
+        assert_eq!(pick(Rebalance::LeastLoaded, &[subtree(5, 2), subtree(3, 2), subtree(3, 2)], &mut turn), Some(1));
+        assert_eq!(turn, 0);
+    }
+
+    #[test]
+    fn shallowest_takes_the_shallowest_subtree_then_the_smallest() {
+        let mut turn: usize = 0;
+        assert_eq!(pick(Rebalance::Shallowest, &[subtree(3, 3), subtree(9, 2), subtree(4, 4)], &mut turn), Some(1));
+        assert_eq!(pick(Rebalance::Shallowest, &[subtree(9, 2), subtree(5, 2), subtree(1, 3)], &mut turn), Some(1));
+        assert_eq!(pick(Rebalance::Shallowest, &[Subtree::leaf(), Subtree::leaf()], &mut turn), Some(0));
+        assert_eq!(turn, 0);
+    }
+
+    #[test]
+    fn nobody_is_picked_without_children() {
+        for policy in [Rebalance::RoundRobin, Rebalance::LeastLoaded, Rebalance::Shallowest] {
+            let mut turn: usize = 7;
+            assert_eq!(pick(policy, &[], &mut turn), None);
+            assert_eq!(turn, 7);
+        }
+    }
+}
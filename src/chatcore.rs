@@ -0,0 +1,2246 @@
+use std::cmp::Ordering;
+
+use crate::chatlib;
+use crate::identity;
+use crate::secure;
+use crate::groupkey;
+use crate::heartbeat;
+use crate::balance;
+use crate::addrbook;
+use crate::discovery;
+use crate::invite;
+use crate::history;
+use crate::mailbox;
+use crate::event;
+use crate::error::ChatError;
+
+const MAX_SEEN: usize = 4096;
+
+// Backup parents handed to a child, and how long each may take to answer.
+const MAX_FALLBACKS: usize = 8;
+const DEFAULT_CONNECT_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(5);
+
+// How often a root probes the peers it knows of for other islands, and how
+// deep a tree may get before we take it for a loop.
+const DEFAULT_PROBE_INTERVAL: std::time::Duration = std::time::Duration::from_secs(30);
+const MAX_DEPTH: u32 = 255;
+
+// How many recent chat frames we keep to bring new neighbors up to date, and
+// how many, from how far back, we ask a new neighbor for.
+const MAX_BACKLOG: usize = 200;
+const DEFAULT_SYNC_MESSAGES: usize = 50;
+const DEFAULT_SYNC_WINDOW: std::time::Duration = std::time::Duration::from_secs(60 * 60);
+
+// Every message carries its sender's name, so names are kept short enough to
+// leave room for what's said.
+const MAX_NAME_LEN: usize = 256;
+
+// How far ahead of our own clock a message may be stamped. Logical clocks
+// never run far ahead of the wall clock, so anything past this is a broken
+// clock or someone trying to make everything after it look late.
+const MAX_CLOCK_SKEW: std::time::Duration = std::time::Duration::from_secs(10 * 60);
+
+// Identifies one connection of a node. The core hands them out and the driver
+// maps them to whatever it actually talks through.
+pub type ConnId = i32;
+
+// What the core wants done with its connections, carried out by the driver in
+// order. Writes to a connection that is still connecting wait for it.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Output {
+    Connect(ConnId, std::net::SocketAddr),
+    Write(ConnId, Vec<u8>),
+    Close(ConnId),
+    //a datagram for the discovery group
+    Announce(Vec<u8>),
+}
+
+// The whole protocol without any I/O: drivers feed it accepted connections,
+// received bytes and timer ticks, and carry out the Output it queues.
+pub struct ChatCore {
+
+    //self and children
+    host_port: u16,
+    down_streams: Vec<chatlib::InfoStream>,
+    name: std::option::Option<String>,
+    next_conn: ConnId,
+
+    //parent
+    up_stream: Option<ConnId>,
+    up_stream_port: u16,
+    up_stream_info: Option<chatlib::Peer>,
+    up_stream_buf: chatlib::FrameBuffer,
+    up_stream_link: secure::SecureLink,
+    up_stream_liveness: heartbeat::Liveness,
+
+    //backup parents from our parent, best first, and while failing over the
+    //ones not tried yet; bootstrapping walks the address book the same way
+    fallbacks: Vec<chatlib::Peer>,
+    failing_over: Option<Vec<chatlib::Peer>>,
+    bootstrapping: bool,
+
+    //the key of the node whose invite we're following, the walk only ends
+    //once an address of the invite proves it holds that key
+    invited: Option<identity::PublicKey>,
+    //private channels we joined with a token whose owner hasn't heard from us yet
+    joining: Vec<String>,
+
+    //the outgoing connection we're waiting on, and when we stop waiting
+    connecting: Option<(ConnId, std::net::SocketAddr, std::time::Instant)>,
+    connect_timeout: std::time::Duration,
+
+    //the instance id of our tree's root and how far below it we are
+    root: (String, u32),
+    //random per run, so a loop is found even between nodes sharing a key
+    instance: String,
+
+    //every peer address we heard of, which the root probes in case they
+    //ended up in another island; probing is set while the upstream slot
+    //holds such a probe rather than a parent
+    peers: addrbook::AddressBook,
+    probing: bool,
+    probe_interval: std::time::Duration,
+    next_probe: std::time::Instant,
+    probe_turn: usize,
+
+    //nodes heard announcing themselves on the local network, None while
+    //discovery is off
+    discovery: Option<discovery::Neighbors>,
+    next_announce: std::time::Instant,
+
+    //fan-out, and what we last told our parent about our subtree
+    max_children: usize,
+    rebalance: balance::Rebalance,
+    rebalance_turn: usize,
+    reported: Option<balance::Subtree>,
+
+    //channels
+    known_channels: std::collections::BTreeSet<String>,
+    joined_channels: std::collections::BTreeSet<String>,
+    current_channel: std::option::Option<String>,
+
+    //duplicate suppression
+    seen: chatlib::SeenSet,
+
+    //our logical clock (see chatlib::Stamp), and the highest clock shown so
+    //far in each conversation: "" for the lobby, "#<channel>", "@<name>"
+    logical: u64,
+    shown: std::collections::HashMap<String, u64>,
+
+    //every message shown or sent, by channel
+    history: history::History,
+
+    //the chat frames we flooded lately with when we saw them, what we ask a
+    //new neighbor for, and whether the parent we announced ourselves to still
+    //has to be asked once it takes us in
+    backlog: std::collections::VecDeque<(u64, Vec<u8>)>,
+    sync: (usize, std::time::Duration),
+    sync_pending: bool,
+
+    //private messages kept for recipients that may be offline
+    mailbox: mailbox::Mailbox,
+
+    //name -> fd of the neighbor it was last heard through
+    routes: std::collections::HashMap<String, i32>,
+
+    //signing key, and name -> the key first seen using it
+    identity: identity::Identity,
+    bindings: std::collections::HashMap<String, identity::PublicKey>,
+
+    //private channel -> group key
+    private_channels: std::collections::HashMap<String, groupkey::GroupKey>,
+
+    //link encryption
+    keys: secure::Keys,
+    plaintext: bool,
+
+    //keepalive, timed by the driver's clock
+    heartbeat: heartbeat::Heartbeat,
+    next_tick: std::time::Instant,
+    clock: Box<dyn Fn() -> std::time::Instant + Send>,
+
+    //waiting to be handed to the driver
+    events: Vec<event::ChatEvent>,
+    output: Vec<Output>,
+}
+
+impl ChatCore {
+    // Keeps its identity, address book and history in dir.
+    pub fn new(port: u16, dir: &std::path::Path) -> Self {
+        let mut core: ChatCore = match identity::Identity::load_or_create(dir, port) {
+            Ok(identity) => ChatCore::with_identity(port, identity),
+            Err(error) => {
+                let mut core: ChatCore = ChatCore::with_identity(port, identity::Identity::generate());
+                core.emit(event::ChatEvent::Warning(format!("Couldn't Load Identity, Using A Temporary One: {:?}", error)));
+                core
+            },
+        };
+        match addrbook::AddressBook::load(dir) {
+            Ok(peers) => core.peers = peers,
+            Err(error) => core.emit(event::ChatEvent::Warning(format!("Couldn't Load Address Book, Starting Without One: {:?}", error))),
+        };
+        core.history = history::History::open(dir);
+        core
+    }
+
+    // Picks the id this run goes by in the tree, before it joins one. Lets the
+    // simulation keep every run of a seed the same.
+    pub(crate) fn set_instance(&mut self, instance: u64) {
+        self.instance = format!("{:016x}", instance);
+        self.root = (self.instance.clone(), 0);
+    }
+
+    pub(crate) fn with_identity(port: u16, identity: identity::Identity) -> Self {
+        let now: std::time::Instant = std::time::Instant::now();
+        let instance: String = format!("{:016x}", rand::random::<u64>());
+        ChatCore {
+            host_port: port,
+            down_streams: Vec::new(),
+            name: None,
+            next_conn: 1,
+            up_stream: None,
+            up_stream_port: 0,
+            up_stream_info: None,
+            up_stream_buf: chatlib::FrameBuffer::new(),
+            up_stream_link: secure::SecureLink::plain(),
+            up_stream_liveness: heartbeat::Liveness::new(now),
+            fallbacks: Vec::new(),
+            failing_over: None,
+            bootstrapping: false,
+            invited: None,
+            joining: Vec::new(),
+            connecting: None,
+            connect_timeout: DEFAULT_CONNECT_TIMEOUT,
+            root: (instance.clone(), 0),
+            instance,
+            peers: addrbook::AddressBook::in_memory(),
+            probing: false,
+            probe_interval: DEFAULT_PROBE_INTERVAL,
+            next_probe: now + DEFAULT_PROBE_INTERVAL,
+            probe_turn: 0,
+            discovery: None,
+            next_announce: now,
+            max_children: balance::DEFAULT_MAX_CHILDREN,
+            rebalance: balance::Rebalance::default(),
+            rebalance_turn: 0,
+            reported: None,
+            known_channels: std::collections::BTreeSet::new(),
+            joined_channels: std::collections::BTreeSet::new(),
+            current_channel: None,
+            seen: chatlib::SeenSet::new(MAX_SEEN),
+            logical: 0,
+            shown: std::collections::HashMap::new(),
+            history: history::History::in_memory(),
+            backlog: std::collections::VecDeque::new(),
+            sync: (DEFAULT_SYNC_MESSAGES, DEFAULT_SYNC_WINDOW),
+            sync_pending: false,
+            mailbox: mailbox::Mailbox::default(),
+            routes: std::collections::HashMap::new(),
+            keys: secure::Keys::new(&identity),
+            identity,
+            bindings: std::collections::HashMap::new(),
+            private_channels: std::collections::HashMap::new(),
+            plaintext: false,
+            heartbeat: heartbeat::Heartbeat::default(),
+            next_tick: now + heartbeat::Heartbeat::default().interval,
+            clock: Box::new(std::time::Instant::now),
+            events: Vec::new(),
+            output: Vec::new(),
+        }
+    }
+
+    // Skips the encrypted handshake on new connections, both ends have to agree.
+    pub fn set_plaintext(&mut self, plaintext: bool) {
+        self.plaintext = plaintext;
+    }
+
+    // How often quiet links are pinged and how many pings can go unanswered.
+    pub fn set_heartbeat(&mut self, interval: std::time::Duration, max_missed: u32) {
+        self.heartbeat = heartbeat::Heartbeat::new(interval, max_missed);
+        self.next_tick = self.now() + interval;
+    }
+
+    // How many children we take before sending newcomers further down, at least one.
+    pub fn set_max_children(&mut self, max_children: usize) {
+        self.max_children = max_children.max(1);
+    }
+
+    // How the child a newcomer is sent on to gets picked.
+    pub fn set_rebalance(&mut self, rebalance: balance::Rebalance) {
+        self.rebalance = rebalance;
+    }
+
+    // Replaces the wall clock, for drivers that keep their own time.
+    pub fn set_clock<F: Fn() -> std::time::Instant + Send + 'static>(&mut self, clock: F) {
+        self.clock = Box::new(clock);
+        self.next_tick = self.now() + self.heartbeat.interval;
+    }
+
+    fn now(&self) -> std::time::Instant {
+        (self.clock)()
+    }
+
+    fn new_link(&self, initiator: bool) -> Result<(secure::SecureLink, Vec<u8>), ChatError> {
+        if self.plaintext {
+            return Ok((secure::SecureLink::plain(), Vec::new()));
+        }
+
+        let link = match initiator {
+            true => secure::SecureLink::initiator(&self.keys),
+            false => secure::SecureLink::responder(&self.keys).map(|link| (link, Vec::new())),
+        };
+        Ok(link?)
+    }
+
+    fn set_peer(&mut self, fd: i32, portno: u16) {
+        for stream in &mut self.down_streams {
+            if stream.0 == fd {
+                stream.2 = true;
+                stream.3 = portno;
+            }
+        }
+    }
+
+    pub fn set_name(&mut self, name: &str) {
+        if name.len() > MAX_NAME_LEN {
+            self.emit(event::ChatEvent::Notice(format!("Names Can Be At Most {} Bytes Long!", MAX_NAME_LEN)));
+        }
+        else if self.name.is_none() {
+            self.name = Some(String::from(name));
+            self.bindings.insert(String::from(name), self.identity.public_key());
+            self.emit(event::ChatEvent::NameSet { name: String::from(name), node_id: self.identity.node_id() });
+            self.send_name();
+            self.send_joins();
+        }
+        else{
+            self.emit(event::ChatEvent::Notice(String::from("Setting Name Again Not Allowed!")));
+        }
+    }
+
+    fn set_stream_name(&mut self, fd: i32, name: &str) {
+        for stream in self.down_streams.iter_mut() {
+            if stream.0 == fd {
+                stream.4 = name.to_string();
+            }
+        }
+    }
+
+    fn broadcast(&mut self, buf: &[u8], fd: i32, only_peer: bool) {
+        let mut targets: Vec<i32> = Vec::new();
+        for stream in &self.down_streams {
+            if only_peer && !stream.2 { continue; }
+            else if stream.0 != fd {
+                targets.push(stream.0);
+            }
+        }
+
+        if let Some(up_stream) = self.up_stream {
+            if fd != up_stream && !self.probing {
+                targets.push(up_stream);
+            }
+        }
+
+        for target in targets {
+            self.send_to(target, buf);
+        }
+    }
+    
+    fn handle_recv(&mut self, buf: &[u8], fd: i32) -> Result<(), ChatError> {
+        match buf.len() {
+            0 => {},
+            _ => {
+                match chatlib::parse_raw(buf) {
+                    Err(error) => { self.emit(event::ChatEvent::Warning(format!("Dropping Frame: {}", error))); },
+                    Ok((hdr, payload)) => {
+                        if self.probing && self.is_up_stream(fd) && !matches!(hdr.chat_t, chatlib::ChatType::ROOT | chatlib::ChatType::PING | chatlib::ChatType::PONG) {
+                            return Ok(());
+                        }
+
+                        if hdr.chat_t.is_flooded() {
+                            match hdr.id {
+                                Some(id) => {
+                                    if !self.seen.insert(id) {
+                                        return Ok(());
+                                    }
+                                    let horizon: u64 = history::now_millis().saturating_add(MAX_CLOCK_SKEW.as_millis() as u64);
+                                    if hdr.stamp.is_some_and(|stamp| stamp.time > horizon || stamp.clock > horizon) {
+                                        self.emit(event::ChatEvent::Warning(format!("Dropping Message Stamped In The Future From {}", self.get_name(fd))));
+                                        return Ok(());
+                                    }
+                                    if hdr.chat_t.is_chat() {
+                                        self.keep(buf);
+                                    }
+                                },
+                                None => {
+                                    self.emit(event::ChatEvent::Warning(format!("Dropping Message Without An Id From {}", self.get_name(fd))));
+                                    return Ok(());
+                                },
+                            };
+                        }
+
+                        match hdr.chat_t {
+                            chatlib::ChatType::PORT => {
+                                let children: usize = self.down_streams.iter().filter(|stream| stream.2 && stream.0 != fd).count();
+                                let rebalanced: bool = match children.cmp(&self.max_children) {
+                                    Ordering::Equal | Ordering::Greater => self.send_rebalance(fd),
+                                    _ => false,
+                                };
+                                if !rebalanced {
+                                    let portno: u16 = hdr.peer.as_ref().ok_or(ChatError::Protocol("port announcement without a port"))?.port;
+                                    self.set_peer(fd, portno);
+                                    if let Some(index) = self.get_stream_idx(fd) {
+                                        let peer: chatlib::Peer = child_peer(&self.down_streams[index]);
+                                        self.learn_peer(&peer);
+                                    }
+                                    self.send_root(fd);
+                                    self.send_failover();
+                                    self.report_subtree();
+                                    self.send_sync(fd);
+                                }
+                            },
+                            chatlib::ChatType::SUBTREE => {
+                                let subtree: balance::Subtree = payload.and_then(balance::Subtree::from_bytes)
+                                                                       .ok_or(ChatError::Protocol("malformed subtree report"))?;
+                                if let Some(index) = self.get_stream_idx(fd) {
+                                    self.down_streams[index].8 = subtree;
+                                    self.report_subtree();
+                                }
+                            },
+                            chatlib::ChatType::REGULAR => {
+                                if let Some(load) = payload {
+                                    match chatlib::unpack_fields(load) {
+                                        Ok(ref fields) if fields.len() == 2 => {
+                                            let from: String = String::from_utf8_lossy(fields[0]).to_string();
+                                            let trust: identity::Trust = self.verify_sender(&hdr, payload, &from);
+                                            if trust == identity::Trust::Verified {
+                                                self.routes.entry(from.clone()).or_insert(fd);
+                                            }
+                                            let text: String = String::from_utf8_lossy(fields[1]).to_string();
+                                            self.remember(None, &hdr, &from, trust, &text);
+                                            let late: bool = self.mark_shown(String::new(), &hdr);
+                                            self.emit(event::ChatEvent::Message { from, text, trust, sent: hdr.stamp, late });
+                                            self.broadcast(buf, fd, false);
+                                        },
+                                        _ => { self.emit(event::ChatEvent::Warning(String::from("Dropping Malformed Message"))); },
+                                    };
+                                }
+                            },
+                            chatlib::ChatType::FAILOVER => {
+                                if self.is_up_stream(fd) {
+                                    self.fallbacks = chatlib::unpack_peers(payload.unwrap_or_default())?;
+                                    for peer in self.fallbacks.clone() {
+                                        self.learn_peer(&peer);
+                                    }
+                                    self.send_failover();
+                                }
+                            },
+                            chatlib::ChatType::PING => {
+                                self.send_to(fd, &chatlib::to_raw(&chatlib::ChatHeader::from_pong(), None));
+                            },
+                            chatlib::ChatType::PONG => {/* receive_raw already marked the link alive */},
+                            chatlib::ChatType::REBALANCE => {
+                                let peer: chatlib::Peer = hdr.peer.ok_or(ChatError::Protocol("rebalance without a peer"))?;
+                                self.learn_peer(&peer);
+                                self.reconnect(&peer)?;
+                            },
+                            chatlib::ChatType::ROOT => {
+                                let fields: Vec<&[u8]> = chatlib::unpack_fields(payload.unwrap_or_default())?;
+                                let (root, depth) = match fields.as_slice() {
+                                    [root, depth] if depth.len() == 4 => (String::from_utf8_lossy(root).to_string(), u32::from_be_bytes([depth[0], depth[1], depth[2], depth[3]])),
+                                    _ => return Err(ChatError::Protocol("malformed root announcement")),
+                                };
+                                if self.is_up_stream(fd) {
+                                    self.heard_root(fd, root, depth);
+                                }
+                            },
+                            chatlib::ChatType::PROBE => {
+                                self.send_root(fd);
+                            },
+                            chatlib::ChatType::SYNC => {
+                                let fields: Vec<&[u8]> = chatlib::unpack_fields(payload.unwrap_or_default())?;
+                                match fields.as_slice() {
+                                    [count, window] if count.len() == 4 && window.len() == 4 => {
+                                        let count: u32 = u32::from_be_bytes([count[0], count[1], count[2], count[3]]);
+                                        let window: u32 = u32::from_be_bytes([window[0], window[1], window[2], window[3]]);
+                                        self.send_backlog(fd, count as usize, std::time::Duration::from_secs(window as u64));
+                                    },
+                                    _ => return Err(ChatError::Protocol("malformed sync request")),
+                                };
+                            },
+                            chatlib::ChatType::BACKLOG => {
+                                self.catch_up(fd, payload.unwrap_or_default())?;
+                            },
+                            chatlib::ChatType::MERGE => {
+                                let peer: chatlib::Peer = hdr.peer.ok_or(ChatError::Protocol("merge without a peer"))?;
+                                let addr: std::net::SocketAddr = match (peer.addr, self.down_streams.iter().find(|stream| stream.0 == fd)) {
+                                    (Some(addr), _) => std::net::SocketAddr::new(addr.ip(), peer.port),
+                                    (None, Some(stream)) => std::net::SocketAddr::new(stream.1.ip(), peer.port),
+                                    (None, None) => return Err(ChatError::Protocol("merge without an address")),
+                                };
+                                if !self.is_up_stream(fd) {
+                                    self.merge_toward(addr);
+                                }
+                            },
+                            chatlib::ChatType::CHANNEL => {
+                                if let Some(load) = payload {
+                                    match chatlib::unpack_fields(load) {
+                                        Ok(ref fields) if fields.len() == 3 => {
+                                            let channel: String = String::from_utf8_lossy(fields[0]).to_string();
+                                            if self.joined_channels.contains(&channel) {
+                                                let from: String = String::from_utf8_lossy(fields[1]).to_string();
+                                                let trust: identity::Trust = self.verify_sender(&hdr, payload, &from);
+                                                if trust == identity::Trust::Verified {
+                                                    self.routes.entry(from.clone()).or_insert(fd);
+                                                }
+                                                let text: String = String::from_utf8_lossy(fields[2]).to_string();
+                                                self.remember(Some(&channel), &hdr, &from, trust, &text);
+                                                let late: bool = self.mark_shown(format!("#{}", channel), &hdr);
+                                                self.emit(event::ChatEvent::ChannelMessage { channel: channel.clone(), from, text, trust, sent: hdr.stamp, late });
+                                            }
+                                            self.known_channels.insert(channel);
+                                            self.broadcast(buf, fd, false);
+                                        },
+                                        _ => { self.emit(event::ChatEvent::Warning(String::from("Dropping Malformed Channel Message"))); },
+                                    };
+                                }
+                            },
+                            chatlib::ChatType::CREATE => {
+                                if let Some(load) = payload {
+                                    let channel: String = String::from_utf8_lossy(load).to_string();
+                                    if self.known_channels.insert(channel.clone()) {
+                                        self.emit(event::ChatEvent::ChannelCreated { channel: channel.clone() });
+                                    }
+                                    self.broadcast(buf, fd, false);
+                                }
+                            },
+                            chatlib::ChatType::NAME => {
+                                if let Some(load) = payload {
+                                    let name: String = String::from_utf8_lossy(load).to_string();
+                                    let trust: identity::Trust = self.verify_sender(&hdr, payload, &name);
+                                    if !hdr.relayed {
+                                        self.set_stream_name(fd, &name);
+                                    }
+                                    if trust == identity::Trust::Verified {
+                                        self.routes.insert(name.clone(), fd);
+                                    }
+                                    let node_id: std::option::Option<String> = hdr.auth.as_ref().map(|auth| identity::node_id(&auth.public_key));
+                                    if let (identity::Trust::Verified, Some(node_id)) = (trust, node_id.as_ref()) {
+                                        self.pass_on_held(fd, &name, node_id);
+                                    }
+                                    self.emit(event::ChatEvent::PeerJoined { name, node_id, trust });
+
+                                    let mut relay: Vec<u8> = buf.to_vec();
+                                    chatlib::mark_relayed(&mut relay);
+                                    self.broadcast(&relay, fd, false);
+                                }
+                            },
+                            chatlib::ChatType::SEALED => {
+                                if let Some(load) = payload {
+                                    match chatlib::unpack_fields(load) {
+                                        Ok(ref fields) if fields.len() == 4 && fields[1].len() == 4 => {
+                                            let channel: String = String::from_utf8_lossy(fields[0]).to_string();
+                                            if self.joined_channels.contains(&channel) {
+                                                let epoch: u32 = u32::from_be_bytes([fields[1][0], fields[1][1], fields[1][2], fields[1][3]]);
+                                                let opened: std::option::Option<Vec<u8>> = self.private_channels.get(&channel)
+                                                                                               .and_then(|group| group.decrypt(&channel, epoch, fields[2], fields[3]));
+                                                match opened.as_ref().map(|plain| chatlib::unpack_fields(plain)) {
+                                                    Some(Ok(ref inner)) if inner.len() == 2 => {
+                                                        let from: String = String::from_utf8_lossy(inner[0]).to_string();
+                                                        let trust: identity::Trust = self.verify_sender(&hdr, payload, &from);
+                                                        if trust == identity::Trust::Verified {
+                                                            self.routes.entry(from.clone()).or_insert(fd);
+                                                        }
+                                                        let text: String = String::from_utf8_lossy(inner[1]).to_string();
+                                                        self.remember(Some(&channel), &hdr, &from, trust, &text);
+                                                        let late: bool = self.mark_shown(format!("#{}", channel), &hdr);
+                                                        self.emit(event::ChatEvent::ChannelMessage { channel: channel.clone(), from, text, trust, sent: hdr.stamp, late });
+                                                    },
+                                                    _ => { self.emit(event::ChatEvent::Warning(format!("Couldn't Decrypt A Message In #{}, Stale Or Missing Key", channel))); },
+                                                };
+                                            }
+                                            self.broadcast(buf, fd, false);
+                                        },
+                                        _ => { self.emit(event::ChatEvent::Warning(String::from("Dropping Malformed Channel Message"))); },
+                                    };
+                                }
+                            },
+                            chatlib::ChatType::INVITE => {
+                                if let Some(load) = payload {
+                                    match chatlib::unpack_fields(load) {
+                                        Ok(ref fields) if fields.len() == 3 => {
+                                            let to: String = String::from_utf8_lossy(fields[0]).to_string();
+                                            let from: String = String::from_utf8_lossy(fields[1]).to_string();
+                                            if self.name.as_ref() == Some(&to) {
+                                                let trust: identity::Trust = self.verify_sender(&hdr, payload, &from);
+                                                let opened = groupkey::open(&self.identity, fields[2]).and_then(|plain| groupkey::GroupKey::from_bytes(&plain));
+                                                match (trust, opened) {
+                                                    (identity::Trust::Verified, Some((channel, group))) => self.accept_invite(&from, &channel, group),
+                                                    (identity::Trust::Verified, None) => self.emit(event::ChatEvent::Warning(format!("Couldn't Open Invite From {}", from))),
+                                                    _ => self.emit(event::ChatEvent::Warning(format!("Ignoring Invite From {}{}", trust.tag(), from))),
+                                                };
+                                            }
+                                            else {
+                                                self.route(&to, buf, fd);
+                                            }
+                                        },
+                                        _ => { self.emit(event::ChatEvent::Warning(String::from("Dropping Malformed Invite"))); },
+                                    };
+                                }
+                            },
+                            chatlib::ChatType::PART => {
+                                if let Some(load) = payload {
+                                    match chatlib::unpack_fields(load) {
+                                        Ok(ref fields) if fields.len() == 3 => {
+                                            let to: String = String::from_utf8_lossy(fields[0]).to_string();
+                                            let from: String = String::from_utf8_lossy(fields[1]).to_string();
+                                            if self.name.as_ref() == Some(&to) {
+                                                let channel: String = String::from_utf8_lossy(fields[2]).to_string();
+                                                if self.verify_sender(&hdr, payload, &from) == identity::Trust::Verified {
+                                                    self.member_left(&from, &channel);
+                                                }
+                                            }
+                                            else {
+                                                self.route(&to, buf, fd);
+                                            }
+                                        },
+                                        _ => { self.emit(event::ChatEvent::Warning(String::from("Dropping Malformed Part Notice"))); },
+                                    };
+                                }
+                            },
+                            chatlib::ChatType::JOIN => {
+                                if let Some(load) = payload {
+                                    match chatlib::unpack_fields(load) {
+                                        Ok(ref fields) if fields.len() == 6 && fields[3].len() == 4 => {
+                                            let to: String = String::from_utf8_lossy(fields[0]).to_string();
+                                            let from: String = String::from_utf8_lossy(fields[1]).to_string();
+                                            if self.name.as_ref() == Some(&to) {
+                                                let channel: String = String::from_utf8_lossy(fields[2]).to_string();
+                                                let epoch: u32 = u32::from_be_bytes([fields[3][0], fields[3][1], fields[3][2], fields[3][3]]);
+                                                if self.verify_sender(&hdr, payload, &from) == identity::Trust::Verified {
+                                                    self.member_joined(&from, &channel, epoch, fields[4], fields[5]);
+                                                }
+                                            }
+                                            else {
+                                                self.route(&to, buf, fd);
+                                            }
+                                        },
+                                        _ => { self.emit(event::ChatEvent::Warning(String::from("Dropping Malformed Join Notice"))); },
+                                    };
+                                }
+                            },
+                            chatlib::ChatType::DIRECT => {
+                                if let Some(load) = payload {
+                                    match chatlib::unpack_fields(load) {
+                                        Ok(ref fields) if fields.len() == 3 => {
+                                            let to: String = String::from_utf8_lossy(fields[0]).to_string();
+                                            let from: String = String::from_utf8_lossy(fields[1]).to_string();
+                                            //only a sender holding the key pinned to its name gets replies routed back its way
+                                            let trust: identity::Trust = self.verify_sender(&hdr, payload, &from);
+                                            if trust == identity::Trust::Verified {
+                                                self.routes.entry(from.clone()).or_insert(fd);
+                                            }
+
+                                            if self.name.as_ref() == Some(&to) {
+                                                let late: bool = self.mark_shown(format!("@{}", from), &hdr);
+                                                self.emit(event::ChatEvent::DirectMessage { from, text: String::from_utf8_lossy(fields[2]).to_string(), trust, sent: hdr.stamp, late });
+                                            }
+                                            else {
+                                                self.route(&to, buf, fd);
+                                            }
+                                        },
+                                        _ => { self.emit(event::ChatEvent::Warning(String::from("Dropping Malformed Private Message"))); },
+                                    };
+                                }
+                            },
+                            chatlib::ChatType::MAIL => {
+                                if let Some(load) = payload {
+                                    match chatlib::unpack_fields(load) {
+                                        Ok(ref fields) if fields.len() == 2 => {
+                                            let node_id: String = String::from_utf8_lossy(fields[0]).to_string();
+                                            if node_id == self.identity.node_id() {
+                                                let opened: std::option::Option<Vec<u8>> = groupkey::open(&self.identity, fields[1]);
+                                                match opened.as_ref().map(|plain| chatlib::unpack_fields(plain)) {
+                                                    Some(Ok(ref inner)) if inner.len() == 2 => {
+                                                        let from: String = String::from_utf8_lossy(inner[0]).to_string();
+                                                        let trust: identity::Trust = self.verify_sender(&hdr, payload, &from);
+                                                        if trust == identity::Trust::Verified {
+                                                            self.routes.entry(from.clone()).or_insert(fd);
+                                                        }
+                                                        let late: bool = self.mark_shown(format!("@{}", from), &hdr);
+                                                        self.emit(event::ChatEvent::DirectMessage { from, text: String::from_utf8_lossy(inner[1]).to_string(), trust, sent: hdr.stamp, late });
+                                                        if let Some(id) = hdr.id {
+                                                            self.send_receipt(&id);
+                                                        }
+                                                    },
+                                                    _ => { self.emit(event::ChatEvent::Warning(String::from("Couldn't Open A Private Message"))); },
+                                                };
+                                            }
+                                            else {
+                                                match self.route_to_node(&node_id, fd) {
+                                                    Some(next_hop) => self.send_to(next_hop, buf),
+                                                    None => {
+                                                        if let Some(id) = hdr.id {
+                                                            self.hold_mail(&node_id, id, buf);
+                                                        }
+                                                        self.broadcast(buf, fd, false);
+                                                    },
+                                                };
+                                            }
+                                        },
+                                        _ => { self.emit(event::ChatEvent::Warning(String::from("Dropping Malformed Private Message"))); },
+                                    };
+                                }
+                            },
+                            chatlib::ChatType::RECEIPT => {
+                                if let (Some(load), Some(auth), Some(id)) = (payload, hdr.auth, hdr.id) {
+                                    if load.len() == 16 && identity::verify(&auth.public_key, &chatlib::signed_bytes(&hdr, &id, payload), &auth.signature) {
+                                        let mut delivered: chatlib::MsgId = [0u8; 16];
+                                        delivered.copy_from_slice(load);
+                                        self.mailbox.delivered(&identity::node_id(&auth.public_key), &delivered);
+                                    }
+                                }
+                                self.broadcast(buf, fd, false);
+                            },
+                            chatlib::ChatType::HELD => {
+                                let fields: Vec<&[u8]> = chatlib::unpack_fields(payload.unwrap_or_default())?;
+                                let (name, node_id, frame) = match fields.as_slice() {
+                                    [name, node_id, frame] => (String::from_utf8_lossy(name).to_string(), String::from_utf8_lossy(node_id).to_string(), *frame),
+                                    _ => return Err(ChatError::Protocol("malformed held message")),
+                                };
+                                if node_id == self.identity.node_id() {
+                                    match chatlib::parse_raw(frame) {
+                                        //we got it before but our receipt went missing, so say so again
+                                        Ok((chatlib::ChatHeader { chat_t: chatlib::ChatType::MAIL, id: Some(id), .. }, _)) if self.seen.contains(&id) => {
+                                            self.send_receipt(&id);
+                                        },
+                                        Ok((inner, _)) if inner.chat_t == chatlib::ChatType::MAIL => self.handle_recv(frame, fd)?,
+                                        _ => return Err(ChatError::Protocol("held message that isn't private mail")),
+                                    };
+                                }
+                                else {
+                                    //only along a route the recipient's name took, never flooded
+                                    match self.routes.get(&name) {
+                                        Some(&next_hop) if next_hop != fd && self.is_stream(next_hop) => self.send_to(next_hop, buf),
+                                        _ => {},
+                                    };
+                                }
+                            },
+                        };
+                    },
+                };
+            },
+        };
+        Ok(())
+    }
+
+    // Sends a message to the channel we're in, or to the lobby.
+    pub fn send(&mut self, msg: &str) {
+        if self.name.is_none() {
+            self.emit(event::ChatEvent::Notice(String::from("Please Set Your Name First!\n/name <Name>")));
+            return;
+        }
+
+        let sent: Result<(), chatlib::FrameError> = match self.current_channel.clone() {
+            Some(channel) if self.private_channels.contains_key(&channel) => self.send_sealed(-1, &channel, msg),
+            Some(channel) => self.send_channel_msg(-1, &channel, msg),
+            None => self.send_msg(-1, msg),
+        };
+        if let Err(error) = sent {
+            self.not_sent(error);
+        }
+    }
+
+    // Tells the user something they said was too big to go out. Nothing of it
+    // was sent, so no neighbor ends up dropping us over it.
+    fn not_sent(&mut self, error: chatlib::FrameError) {
+        self.emit(event::ChatEvent::Notice(format!("Message Not Sent, It's Too Long: {}", error)));
+    }
+
+    // Makes addr our upstream, replacing the current one.
+    pub fn connect(&mut self, addr: std::net::SocketAddr) -> Result<(), ChatError> {
+        self.failing_over = None;
+        self.bootstrapping = false;
+        self.invited = None;
+        self.peers.insert(addr);
+        self.reconnect(&chatlib::Peer::new(Some(addr), addr.port()))
+    }
+
+    pub fn name(&self) -> std::option::Option<&str> {
+        self.name.as_deref()
+    }
+
+    pub fn node_id(&self) -> String {
+        self.identity.node_id()
+    }
+
+    pub fn current_channel(&self) -> std::option::Option<&str> {
+        self.current_channel.as_deref()
+    }
+
+    pub fn channels(&self) -> Vec<event::ChannelInfo> {
+        self.known_channels.iter().map(|channel| event::ChannelInfo {
+            name: channel.clone(),
+            joined: self.joined_channels.contains(channel),
+            current: self.current_channel.as_ref() == Some(channel),
+            owner: self.private_channels.get(channel).map(|group| group.owner.clone()),
+        }).collect()
+    }
+
+    fn emit(&mut self, event: event::ChatEvent) {
+        self.events.push(event);
+    }
+
+    pub fn take_events(&mut self) -> Vec<event::ChatEvent> {
+        std::mem::take(&mut self.events)
+    }
+
+    pub fn take_output(&mut self) -> Vec<Output> {
+        std::mem::take(&mut self.output)
+    }
+
+    fn new_conn(&mut self) -> ConnId {
+        let id: ConnId = self.next_conn;
+        self.next_conn += 1;
+        id
+    }
+
+    // A connection the driver accepted, returns the id to report its bytes under.
+    pub fn accept(&mut self, addr: std::net::SocketAddr) -> ConnId {
+        let id: ConnId = self.new_conn();
+        self.emit(event::ChatEvent::Accepted { addr });
+        match self.new_link(false) {
+            Ok((link, _)) => {
+                self.down_streams.push(chatlib::InfoStream(id, addr, false, 0, format!("Client {}", id), chatlib::FrameBuffer::new(), link, heartbeat::Liveness::new(self.now()), balance::Subtree::leaf()));
+            },
+            Err(error) => {
+                self.emit(event::ChatEvent::Warning(format!("Couldn't Accept New Connection: {}", error)));
+                self.output.push(Output::Close(id));
+            },
+        };
+        id
+    }
+
+    // Bytes read from a connection. Anything wrong with them drops the connection.
+    pub fn receive(&mut self, fd: ConnId, bytes: &[u8]) {
+        if let Err(error) = self.read_stream(fd, bytes) {
+            self.drop_stream(fd, error);
+        }
+    }
+
+    // The other end closed the connection.
+    pub fn closed(&mut self, fd: ConnId) {
+        if self.is_stream(fd) {
+            if let Err(error) = self.close_client(fd) {
+                let peer: String = self.get_name(fd);
+                self.report(&peer, error);
+            }
+        }
+    }
+
+    // Reading from or writing to the connection failed. Failures of
+    // connections the core already closed are ignored.
+    pub fn failed(&mut self, fd: ConnId, error: ChatError) {
+        if self.is_stream(fd) {
+            self.drop_stream(fd, error);
+        }
+    }
+
+    // Something went wrong in the driver that isn't tied to one connection.
+    pub fn warn(&mut self, text: String) {
+        self.emit(event::ChatEvent::Warning(text));
+    }
+
+    // The driver opened a connection asked for with Output::Connect.
+    pub fn connected(&mut self, fd: ConnId, addr: std::net::SocketAddr) {
+        if self.connecting.map(|(connecting, _, _)| connecting) == Some(fd) {
+            self.connecting = None;
+        }
+        if self.probing && self.is_up_stream(fd) {
+            return;
+        }
+        self.emit(event::ChatEvent::Connected { addr });
+        self.peers.insert(addr);
+        if self.is_up_stream(fd) {
+            //a plaintext link proves nothing, so there's no key to wait for
+            if self.invited.is_some() && !self.plaintext {
+                return;
+            }
+            self.invited = None;
+            if self.failing_over.take().is_some() && !std::mem::take(&mut self.bootstrapping) {
+                self.emit(event::ChatEvent::FailedOver { addr });
+            }
+            self.send_failover();
+            self.send_joins();
+        }
+    }
+
+    // The driver couldn't open a connection asked for with Output::Connect.
+    pub fn connect_failed(&mut self, fd: ConnId, addr: std::net::SocketAddr, error: std::io::Error) {
+        let up_stream: bool = self.is_up_stream(fd);
+        let probing: bool = up_stream && self.probing;
+        self.forget(fd);
+        //peers that are gone for good get probed over and over, keep quiet about them
+        if !probing {
+            self.report(&addr.to_string(), ChatError::Connect { addr, source: error });
+        }
+        if up_stream {
+            self.next_fallback();
+        }
+    }
+
+    // The connection to our parent in the tree, if we have one.
+    pub fn up_stream(&self) -> std::option::Option<ConnId> {
+        self.up_stream
+    }
+
+    // How long a connection may take to open before the core gives up on it.
+    pub fn connect_timeout(&self) -> std::time::Duration {
+        self.connect_timeout
+    }
+
+    pub fn set_connect_timeout(&mut self, timeout: std::time::Duration) {
+        self.connect_timeout = timeout;
+    }
+
+    // How often a node that finds itself the root probes the peers it knows
+    // of, to merge back with any island the network split into.
+    pub fn set_probe_interval(&mut self, interval: std::time::Duration) {
+        self.next_probe = self.now() + interval;
+        self.probe_interval = interval;
+    }
+
+    // Announces us to the local network with Output::Announce and listens
+    // for others doing the same, fed in through discovered().
+    pub fn set_discovery(&mut self, discovery: bool) {
+        self.discovery = match discovery {
+            true => Some(discovery::Neighbors::default()),
+            false => None,
+        };
+        self.next_announce = self.now();
+    }
+
+    // How many of the messages said before we linked up, from how far back,
+    // we ask each new neighbor for. Zero doesn't ask.
+    pub fn set_sync(&mut self, messages: usize, window: std::time::Duration) {
+        self.sync = (messages, window);
+    }
+
+    // How long we keep private messages for recipients that may be offline,
+    // handing them on when they announce their name again. Zero keeps none.
+    pub fn set_hold(&mut self, hold: std::time::Duration) {
+        self.mailbox.set_hold(hold);
+    }
+
+    // A datagram from the discovery group.
+    pub fn discovered(&mut self, from: std::net::SocketAddr, bytes: &[u8]) {
+        let neighbor: discovery::Neighbor = match discovery::parse(from, bytes) {
+            Some(neighbor) if neighbor.node_id != self.identity.node_id() => neighbor,
+            _ => return,
+        };
+        let now: std::time::Instant = self.now();
+        if let Some(neighbors) = self.discovery.as_mut() {
+            if neighbors.heard(neighbor.clone(), now) {
+                self.emit(event::ChatEvent::Discovered { name: neighbor.name, addr: neighbor.addr });
+            }
+        }
+    }
+
+    // The nodes announcing themselves on the local network, empty while discovery is off.
+    pub fn neighbors(&self) -> Vec<discovery::Neighbor> {
+        self.discovery.as_ref().map(|neighbors| neighbors.list()).unwrap_or_default()
+    }
+
+    // The names we have a route to through a connection that is still up, in name order.
+    pub fn peers(&self) -> Vec<String> {
+        let mut peers: Vec<String> = self.routes.iter()
+                                               .filter(|(name, fd)| self.name.as_ref() != Some(*name) && self.is_stream(**fd))
+                                               .map(|(name, _)| name.clone())
+                                               .collect();
+        peers.sort();
+        peers
+    }
+
+    // When tick() next has work to do.
+    pub fn next_tick(&self) -> std::time::Instant {
+        let mut next: std::time::Instant = self.next_tick.min(self.next_probe);
+        if let Some((_, _, deadline)) = self.connecting {
+            next = next.min(deadline);
+        }
+        if self.discovery.is_some() {
+            next = next.min(self.next_announce);
+        }
+        next
+    }
+
+    pub fn tick(&mut self) {
+        if let Some((fd, addr, deadline)) = self.connecting {
+            if self.now() >= deadline {
+                self.output.push(Output::Close(fd));
+                self.connect_failed(fd, addr, std::io::Error::from(std::io::ErrorKind::TimedOut));
+            }
+        }
+
+        if self.now() >= self.next_tick {
+            self.check_heartbeats();
+            self.next_tick = self.now() + self.heartbeat.interval;
+            if let Err(error) = self.peers.save() {
+                self.emit(event::ChatEvent::Warning(format!("Couldn't Save Address Book: {}", error)));
+            }
+        }
+
+        if self.now() >= self.next_probe {
+            self.next_probe = self.now() + self.probe_interval;
+            self.probe_known_peer();
+        }
+
+        if self.discovery.is_some() && self.now() >= self.next_announce {
+            self.next_announce = self.now() + discovery::ANNOUNCE_INTERVAL;
+            let now: std::time::Instant = self.now();
+            if let Some(neighbors) = self.discovery.as_mut() {
+                neighbors.expire(now);
+            }
+            match discovery::announcement(self.host_port, &self.identity.node_id(), self.name.as_deref()) {
+                Ok(announcement) => self.output.push(Output::Announce(announcement)),
+                Err(error) => self.emit(event::ChatEvent::Warning(format!("Couldn't Announce Us On The Local Network: {}", error))),
+            };
+        }
+    }
+
+    fn get_frame_buffer(&mut self, fd: i32) -> std::option::Option<&mut chatlib::FrameBuffer> {
+        if let Some(index) = self.get_stream_idx(fd) {
+            return Some(&mut self.down_streams[index].5);
+        }
+
+        if self.is_up_stream(fd) {
+            return Some(&mut self.up_stream_buf);
+        }
+
+        None
+    }
+
+    fn get_liveness(&mut self, fd: i32) -> std::option::Option<&mut heartbeat::Liveness> {
+        if let Some(index) = self.get_stream_idx(fd) {
+            return Some(&mut self.down_streams[index].7);
+        }
+
+        if self.is_up_stream(fd) {
+            return Some(&mut self.up_stream_liveness);
+        }
+
+        None
+    }
+
+    fn get_link(&mut self, fd: i32) -> std::option::Option<&mut secure::SecureLink> {
+        if let Some(index) = self.get_stream_idx(fd) {
+            return Some(&mut self.down_streams[index].6);
+        }
+
+        if self.is_up_stream(fd) {
+            return Some(&mut self.up_stream_link);
+        }
+
+        None
+    }
+
+    // Runs raw socket bytes through the link, answering handshake messages,
+    // and queues whatever plaintext comes out for frame reassembly.
+    fn receive_raw(&mut self, fd: i32, bytes: &[u8]) -> Result<(), ChatError> {
+        let now: std::time::Instant = self.now();
+        if let Some(liveness) = self.get_liveness(fd) {
+            liveness.heard(now);
+        }
+
+        let (was_established, (plain, reply)) = match self.get_link(fd) {
+            Some(link) => (link.is_established(), link.read(bytes)?),
+            None => return Err(ChatError::UnknownStream(fd)),
+        };
+
+        let probe: bool = self.probing && self.is_up_stream(fd);
+        let invited: Option<identity::PublicKey> = self.invited.filter(|_| self.is_up_stream(fd));
+        let mut remote: Option<identity::PublicKey> = None;
+        if let Some(link) = self.get_link(fd) {
+            if !was_established && link.is_established() {
+                remote = link.remote_identity();
+            }
+        }
+        //checked before our last handshake message goes out, so a stranger
+        //never gets to see what we queued for the node we meant to reach
+        if let (Some(key), Some(remote)) = (invited, remote) {
+            if remote != key {
+                return self.wrong_invitee(fd);
+            }
+            self.invited = None;
+            self.failing_over = None;
+            self.send_failover();
+        }
+        self.write_raw(fd, &reply);
+        //the link to the node whose token we followed is up, safe to use now
+        self.send_joins();
+        if let (Some(key), false) = (remote, probe) {
+            let peer: String = self.get_name(fd);
+            self.emit(event::ChatEvent::LinkEstablished { peer, node_id: identity::node_id(&key) });
+        }
+        if let Some(frames) = self.get_frame_buffer(fd) {
+            frames.extend(&plain);
+        }
+        Ok(())
+    }
+
+    // The node at an invite's address isn't the one that sent it, so we
+    // hang up before telling it anything, forget we ever reached it and try
+    // the invite's next address.
+    fn wrong_invitee(&mut self, fd: ConnId) -> Result<(), ChatError> {
+        if let Some(addr) = self.up_stream_info.and_then(|peer| peer.addr.map(|addr| std::net::SocketAddr::new(addr.ip(), peer.port))) {
+            self.peers.remove(&addr);
+            self.emit(event::ChatEvent::Warning(format!("{} Isn't The Node That Invited You", addr)));
+        }
+        self.forget(fd);
+        self.output.push(Output::Close(fd));
+        self.next_fallback();
+        Ok(())
+    }
+
+    // Handles the complete frames in what was received. An error means the
+    // connection can't be trusted anymore and should be dropped.
+    fn read_stream(&mut self, fd: ConnId, bytes: &[u8]) -> Result<(), ChatError> {
+        self.receive_raw(fd, bytes)?;
+        loop {
+            let next: std::option::Option<Vec<u8>> = match self.get_frame_buffer(fd) {
+                Some(frames) => frames.next_frame()?,
+                None => return Ok(()),
+            };
+            match next {
+                Some(frame) => self.handle_recv(&frame, fd)?,
+                None => return Ok(()),
+            };
+        }
+    }
+
+    // Reports a failed connection and drops it, the rest of the node carries on.
+    fn drop_stream(&mut self, fd: i32, error: ChatError) {
+        let peer: String = self.get_name(fd);
+        self.report(&peer, error);
+        if self.is_stream(fd) {
+            if let Err(error) = self.close_client(fd) {
+                self.report(&peer, error);
+            }
+        }
+    }
+
+    fn report(&mut self, peer: &str, error: ChatError) {
+        match error {
+            ChatError::Connect { addr, .. } => self.emit(event::ChatEvent::ConnectFailed { addr }),
+            error => self.emit(event::ChatEvent::Warning(format!("Dropping {}: {}", peer, error))),
+        };
+    }
+
+    fn is_up_stream(&self, fd: i32) -> bool {
+        self.up_stream == Some(fd)
+    }
+
+    fn is_stream(&self, fd: i32) -> bool {
+        for client in &self.down_streams {
+            if client.0 == fd {
+                return true;
+            }
+        }
+
+        self.is_up_stream(fd)
+    }
+
+
+    fn get_stream_idx(&mut self, fd: i32) -> std::option::Option<usize> {
+        self.down_streams.iter().position(|stream| stream.0 == fd)
+    }
+
+    fn get_name(&self, fd: i32) -> String {
+        for stream in &self.down_streams {
+            if stream.0 == fd {
+                return String::from(&stream.4);
+            }
+        }
+
+        if self.is_up_stream(fd) {
+            return String::from("Upstream");
+        }
+
+        String::from("UnKnown")
+    }
+
+    fn reconnect(&mut self, peer: &chatlib::Peer) -> Result<(), ChatError> {
+        let fd: ConnId = self.open_up_stream(peer)?;
+        self.send_peer(fd);
+        self.reported = None;
+        self.report_subtree();
+        self.send_name();
+        Ok(())
+    }
+
+    // Opens a connection to addr only to ask which tree it is in.
+    fn probe(&mut self, addr: std::net::SocketAddr) {
+        match self.open_up_stream(&chatlib::Peer::new(Some(addr), addr.port())) {
+            Ok(fd) => {
+                self.probing = true;
+                self.send_to(fd, &chatlib::to_raw(&chatlib::ChatHeader::from_probe(), None));
+            },
+            Err(error) => self.report(&addr.to_string(), error),
+        };
+    }
+
+    // Replaces our upstream with a connection to peer, with the link
+    // handshake started but nothing said yet.
+    fn open_up_stream(&mut self, peer: &chatlib::Peer) -> Result<ConnId, ChatError> {
+        let addr: std::net::SocketAddr = match peer.addr {
+            Some(addr) => std::net::SocketAddr::new(addr.ip(), peer.port),
+            None => return Err(ChatError::Protocol("peer without an address")),
+        };
+
+        if let Some(fd) = self.up_stream {
+            self.emit(event::ChatEvent::Notice(String::from("Closing connection with UpStream")));
+            self.forget(fd);
+            self.output.push(Output::Close(fd));
+        }
+
+        let fd: ConnId = self.new_conn();
+        self.output.push(Output::Connect(fd, addr));
+        self.connecting = Some((fd, addr, self.now() + self.connect_timeout));
+        self.up_stream = Some(fd);
+        self.up_stream_port = peer.port;
+        self.up_stream_info = Some(*peer);
+
+        let (link, hello) = self.new_link(true)?;
+        self.up_stream_link = link;
+        self.up_stream_liveness = heartbeat::Liveness::new(self.now());
+        self.up_stream_buf.clear();
+        self.write_raw(fd, &hello);
+        Ok(fd)
+    }
+
+    // The answer to our probe. We join the other tree if its root outranks
+    // ours and otherwise ask it to join us, so two islands probing each other
+    // can't both move.
+    fn probed(&mut self, fd: ConnId, root: String, depth: u32) {
+        self.probing = false;
+        let addr: std::net::SocketAddr = match self.up_stream_info.and_then(|peer| peer.addr.map(|addr| std::net::SocketAddr::new(addr.ip(), peer.port))) {
+            Some(addr) => addr,
+            None => return,
+        };
+
+        match root.cmp(&self.root.0) {
+            Ordering::Equal => {
+                //the root of our own tree answering is us
+                if depth == 0 {
+                    self.peers.remove(&addr);
+                }
+                self.forget(fd);
+                self.output.push(Output::Close(fd));
+            },
+            Ordering::Less => {
+                self.send_to(fd, &chatlib::to_raw(&chatlib::ChatHeader::from_merge(chatlib::Peer::new(None, self.host_port)), None));
+                self.emit(event::ChatEvent::Notice(format!("Found Another Network At {}, Asked It To Join Ours", addr)));
+                self.forget(fd);
+                self.output.push(Output::Close(fd));
+            },
+            Ordering::Greater => {
+                self.emit(event::ChatEvent::Merged { addr });
+                self.send_peer(fd);
+                self.reported = None;
+                self.report_subtree();
+                self.send_name();
+                self.send_failover();
+            },
+        };
+    }
+
+    // Another island's root wants ours to join it. The request climbs to our
+    // root, which probes it first in case things changed on the way.
+    fn merge_toward(&mut self, addr: std::net::SocketAddr) {
+        self.peers.insert(addr);
+        match self.up_stream {
+            Some(up_stream) if !self.probing => {
+                self.send_to(up_stream, &chatlib::to_raw(&chatlib::ChatHeader::from_merge(chatlib::Peer::new(Some(addr), addr.port())), None));
+            },
+            Some(_) => {},
+            None => self.probe(addr),
+        };
+    }
+
+    // While we're a root, tries the next peer we know of that isn't one of
+    // our children, in case it sits in another island.
+    fn probe_known_peer(&mut self) {
+        if self.probing {
+            //the last probe never got an answer
+            if let Some(fd) = self.up_stream {
+                self.forget(fd);
+                self.output.push(Output::Close(fd));
+            }
+        }
+        if self.up_stream.is_some() {
+            return;
+        }
+
+        let children: Vec<std::net::SocketAddr> = self.down_streams.iter().filter(|stream| stream.2).filter_map(|stream| child_peer(stream).addr).collect();
+        let candidates: Vec<std::net::SocketAddr> = self.peers.addrs().into_iter().filter(|addr| !children.contains(addr)).collect();
+        if candidates.is_empty() {
+            return;
+        }
+        let addr: std::net::SocketAddr = candidates[self.probe_turn % candidates.len()];
+        self.probe_turn = self.probe_turn.wrapping_add(1);
+        self.probe(addr);
+    }
+
+    // Our parent told us which tree we're in. Our own instance id coming back down
+    // means we connected into our own subtree, and a depth past MAX_DEPTH
+    // that some other node did; either way the loop has no root, so we
+    // drop our upstream and become one.
+    fn heard_root(&mut self, fd: ConnId, root: String, depth: u32) {
+        if self.probing {
+            return self.probed(fd, root, depth);
+        }
+
+        if root == self.instance || depth >= MAX_DEPTH {
+            self.emit(event::ChatEvent::Notice(String::from("Found A Loop In The Tree, Dropping Upstream")));
+            self.forget(fd);
+            self.output.push(Output::Close(fd));
+            return;
+        }
+        //the first root a new parent tells us about means it took us in
+        if std::mem::take(&mut self.sync_pending) {
+            self.send_sync(fd);
+        }
+        self.set_root(root, depth + 1);
+    }
+
+    // Asks a new neighbor for what was said before we were linked.
+    fn send_sync(&mut self, fd: i32) {
+        let (count, window) = self.sync;
+        if count == 0 {
+            return;
+        }
+        if let Ok(payload) = chatlib::pack_fields(&[&(count.min(u32::MAX as usize) as u32).to_be_bytes(), &(window.as_secs().min(u32::MAX as u64) as u32).to_be_bytes()]) {
+            self.send_to(fd, &chatlib::to_raw(&chatlib::ChatHeader::from_sync(), Some(&payload)));
+        }
+    }
+
+    // Remembers a chat frame we flooded, for neighbors that ask later.
+    fn keep(&mut self, buf: &[u8]) {
+        self.backlog.push_back((history::now_millis(), buf.to_vec()));
+        if self.backlog.len() > MAX_BACKLOG {
+            self.backlog.pop_front();
+        }
+    }
+
+    // Answers a sync request with the newest count frames from the window,
+    // as many as fit in one frame.
+    fn send_backlog(&mut self, fd: i32, count: usize, window: std::time::Duration) {
+        let cutoff: u64 = history::now_millis().saturating_sub(window.as_millis() as u64);
+        let mut room: usize = chatlib::MAX_FRAME_SIZE - chatlib::HEADER_SIZE - 1;
+        let mut frames: Vec<&[u8]> = Vec::new();
+        for (seen, frame) in self.backlog.iter().rev().take(count) {
+            if *seen < cutoff || frame.len() + 2 > room {
+                break;
+            }
+            room -= frame.len() + 2;
+            frames.push(frame);
+        }
+        if frames.is_empty() {
+            return;
+        }
+
+        frames.reverse();
+        if let Ok(payload) = chatlib::pack_fields(&frames) {
+            self.send_to(fd, &chatlib::to_raw(&chatlib::ChatHeader::from_backlog(), Some(&payload)));
+        }
+    }
+
+    // Handles each frame of a backlog as if it had just been flooded to us,
+    // so whatever we saw already is dropped and the rest is shown and relayed.
+    fn catch_up(&mut self, fd: i32, payload: &[u8]) -> Result<(), ChatError> {
+        let frames: Vec<Vec<u8>> = chatlib::unpack_fields(payload)?.into_iter().map(|frame| frame.to_vec()).collect();
+        let mut missed: Vec<Vec<u8>> = Vec::new();
+        for frame in frames {
+            match chatlib::parse_raw(&frame) {
+                Ok((hdr, _)) if hdr.chat_t.is_chat() && hdr.id.is_some_and(|id| !self.seen.contains(&id)) => missed.push(frame),
+                Ok(_) => {},
+                Err(_) => return Err(ChatError::Protocol("malformed backlog")),
+            };
+        }
+        if missed.is_empty() {
+            return Ok(());
+        }
+
+        self.emit(event::ChatEvent::Notice(format!("Catching Up On {} Earlier Message(s)", missed.len())));
+        for frame in missed {
+            self.handle_recv(&frame, fd)?;
+        }
+        Ok(())
+    }
+
+    // Passes a change of root on to our children.
+    fn set_root(&mut self, root: String, depth: u32) {
+        if self.root.0 == root && self.root.1 == depth {
+            return;
+        }
+        self.root = (root, depth);
+        let children: Vec<i32> = self.down_streams.iter().filter(|stream| stream.2).map(|stream| stream.0).collect();
+        for fd in children {
+            self.send_root(fd);
+        }
+    }
+
+    fn send_root(&mut self, fd: i32) {
+        if let Ok(payload) = chatlib::pack_fields(&[self.root.0.as_bytes(), &self.root.1.to_be_bytes()]) {
+            self.send_to(fd, &chatlib::to_raw(&chatlib::ChatHeader::from_root(), Some(&payload)));
+        }
+    }
+
+    // Remembers where a peer listens, for probing if the network splits.
+    fn learn_peer(&mut self, peer: &chatlib::Peer) {
+        if let Some(addr) = peer.addr {
+            self.peers.insert(std::net::SocketAddr::new(addr.ip(), peer.port));
+        }
+    }
+
+    fn close_client(&mut self, fd: ConnId) -> Result<(), ChatError> {
+        let peer: String = self.get_name(fd);
+        self.emit(event::ChatEvent::Disconnected { peer });
+        if !self.is_stream(fd) {
+            return Err(ChatError::UnknownStream(fd));
+        }
+
+        //losing a probe isn't losing a parent
+        let up_stream: bool = self.is_up_stream(fd) && !self.probing;
+        let fallbacks: Vec<chatlib::Peer> = match up_stream {
+            true => std::mem::take(&mut self.fallbacks),
+            false => Vec::new(),
+        };
+        self.forget(fd);
+        self.output.push(Output::Close(fd));
+        //an invite we're still following has addresses of its own left
+        if up_stream && self.invited.is_some() {
+            self.next_fallback();
+            return Ok(());
+        }
+        if up_stream {
+            if !fallbacks.is_empty() {
+                self.emit(event::ChatEvent::Notice(format!("Lost Upstream, Trying {} Backup Parent(s)", fallbacks.len())));
+            }
+            self.failing_over = Some(fallbacks);
+            self.bootstrapping = false;
+            self.next_fallback();
+        }
+        Ok(())
+    }
+
+    // Tries the peers in the address book, most recently seen first, until
+    // one of them takes us in.
+    pub fn bootstrap(&mut self) {
+        let host_port: u16 = self.host_port;
+        let peers: Vec<chatlib::Peer> = self.peers.recent().into_iter()
+                                            .filter(|addr| !(addr.port() == host_port && addr.ip().is_loopback()))
+                                            .map(|addr| chatlib::Peer::new(Some(addr), addr.port()))
+                                            .collect();
+        if peers.is_empty() {
+            return;
+        }
+
+        self.emit(event::ChatEvent::Notice(format!("Trying {} Known Peer(s)", peers.len())));
+        self.failing_over = Some(peers);
+        self.bootstrapping = true;
+        self.next_fallback();
+    }
+
+    // Dials the next backup parent while failing over. Once none is left we
+    // stay the root of whatever is still below us.
+    fn next_fallback(&mut self) {
+        let peer: chatlib::Peer = match self.failing_over.as_mut() {
+            None => return,
+            Some(remaining) if remaining.is_empty() => {
+                self.failing_over = None;
+                match (std::mem::take(&mut self.bootstrapping), self.invited.take()) {
+                    (_, Some(_)) => self.emit(event::ChatEvent::Notice(String::from("Couldn't Reach The Node That Invited You At Any Of Its Addresses"))),
+                    (true, None) => self.emit(event::ChatEvent::Notice(String::from("None Of The Known Peers Answered, Waiting For Connections"))),
+                    (false, None) => self.emit(event::ChatEvent::Stranded),
+                };
+                self.send_failover();
+                return;
+            },
+            Some(remaining) => remaining.remove(0),
+        };
+
+        if let Err(error) = self.reconnect(&peer) {
+            self.report("Backup Parent", error);
+            self.next_fallback();
+        }
+    }
+
+    // Signs a message we originate and remembers its id so it isn't relayed
+    // back to us. Fails if the frame came out bigger than any node would take.
+    fn seal(&mut self, hdr: &mut chatlib::ChatHeader, payload: std::option::Option<&[u8]>) -> Result<Vec<u8>, chatlib::FrameError> {
+        if let Some(id) = hdr.id {
+            self.seen.insert(id);
+            let time: u64 = history::now_millis();
+            self.logical = self.logical.saturating_add(1).max(time);
+            hdr.stamp = Some(chatlib::Stamp { time, clock: self.logical });
+            hdr.auth = Some(chatlib::Auth {
+                public_key: self.identity.public_key(),
+                signature: self.identity.sign(&chatlib::signed_bytes(hdr, &id, payload)),
+            });
+        }
+        let buf: Vec<u8> = chatlib::to_raw(hdr, payload);
+        if buf.len() > chatlib::MAX_FRAME_SIZE {
+            return Err(chatlib::FrameError::TooLarge(buf.len()));
+        }
+        Ok(buf)
+    }
+
+    fn verify_sender(&mut self, hdr: &chatlib::ChatHeader, payload: std::option::Option<&[u8]>, name: &str) -> identity::Trust {
+        let (auth, id) = match (hdr.auth, hdr.id) {
+            (Some(auth), Some(id)) => (auth, id),
+            _ => return identity::Trust::Unsigned,
+        };
+
+        if !identity::verify(&auth.public_key, &chatlib::signed_bytes(hdr, &id, payload), &auth.signature) {
+            return identity::Trust::Forged;
+        }
+
+        let trust: identity::Trust = match self.bindings.get(name) {
+            Some(bound) if *bound != auth.public_key => identity::Trust::Forged,
+            Some(_) => identity::Trust::Verified,
+            None => {
+                self.bindings.insert(String::from(name), auth.public_key);
+                identity::Trust::Verified
+            },
+        };
+        //the signature covers the stamp, so only now can our clock follow it
+        if let (identity::Trust::Verified, Some(stamp)) = (trust, hdr.stamp) {
+            self.logical = self.logical.max(stamp.clock);
+        }
+        trust
+    }
+
+    // Returns the header as sent, signature included.
+    fn send_flood(&mut self, fd: i32, mut hdr: chatlib::ChatHeader, payload: std::option::Option<&[u8]>) -> Result<chatlib::ChatHeader, chatlib::FrameError> {
+        let buf: Vec<u8> = self.seal(&mut hdr, payload)?;
+        if hdr.chat_t.is_chat() {
+            self.keep(&buf);
+        }
+        self.broadcast(&buf, fd, false);
+        Ok(hdr)
+    }
+
+    fn send_msg(&mut self, fd: i32, msg: &str) -> Result<(), chatlib::FrameError> {
+        let from: String = self.name.clone().unwrap_or_default();
+        let payload: Vec<u8> = chatlib::pack_fields(&[from.as_bytes(), msg.as_bytes()])?;
+        let hdr: chatlib::ChatHeader = self.send_flood(fd, chatlib::ChatHeader::from_msg(), Some(&payload))?;
+        self.remember(None, &hdr, &from, identity::Trust::Verified, msg);
+        self.mark_shown(String::new(), &hdr);
+        Ok(())
+    }
+
+    fn send_channel_msg(&mut self, fd: i32, channel: &str, msg: &str) -> Result<(), chatlib::FrameError> {
+        let from: String = self.name.clone().unwrap_or_default();
+        let payload: Vec<u8> = chatlib::pack_fields(&[channel.as_bytes(), from.as_bytes(), msg.as_bytes()])?;
+        let hdr: chatlib::ChatHeader = self.send_flood(fd, chatlib::ChatHeader::from_channel(), Some(&payload))?;
+        self.remember(Some(channel), &hdr, &from, identity::Trust::Verified, msg);
+        self.mark_shown(format!("#{}", channel), &hdr);
+        Ok(())
+    }
+
+    // Keeps a message we showed or sent in the history.
+    fn remember(&mut self, channel: std::option::Option<&str>, hdr: &chatlib::ChatHeader, from: &str, trust: identity::Trust, text: &str) {
+        let entry: history::HistoryEntry = history::HistoryEntry {
+            channel: channel.map(String::from),
+            time: history::now_millis(),
+            clock: hdr.stamp.map(|stamp| stamp.clock),
+            id: hdr.id.unwrap_or_default(),
+            from: from.to_string(),
+            node_id: hdr.auth.as_ref().map(|auth| identity::node_id(&auth.public_key)),
+            trust,
+            text: text.to_string(),
+        };
+        if let Err(error) = self.history.record(entry) {
+            self.emit(event::ChatEvent::Warning(format!("Couldn't Write History, Keeping It In Memory Only: {}", error)));
+        }
+    }
+
+    // Notes that a message was shown in a conversation (ours count too), true
+    // if something sent after it was shown there first.
+    fn mark_shown(&mut self, conversation: String, hdr: &chatlib::ChatHeader) -> bool {
+        match hdr.stamp {
+            Some(stamp) => {
+                let highest: &mut u64 = self.shown.entry(conversation).or_insert(0);
+                let late: bool = stamp.clock < *highest;
+                *highest = (*highest).max(stamp.clock);
+                late
+            },
+            None => false,
+        }
+    }
+
+    // The newest count messages of a channel, or of the lobby, oldest first.
+    pub fn history(&mut self, channel: std::option::Option<&str>, count: usize) -> Vec<history::HistoryEntry> {
+        match self.history.recent(channel, count) {
+            Ok(entries) => entries,
+            Err(error) => {
+                self.emit(event::ChatEvent::Warning(format!("Couldn't Read History: {}", error)));
+                Vec::new()
+            },
+        }
+    }
+
+    pub fn create_channel(&mut self, channel: &str) {
+        if self.known_channels.contains(channel) {
+            self.emit(event::ChatEvent::Notice(format!("Channel #{} Already Exists, Use /join {}", channel, channel)));
+            return;
+        }
+
+        if let Err(error) = self.send_flood(-1, chatlib::ChatHeader::from_create(), Some(channel.as_bytes())) {
+            self.emit(event::ChatEvent::Notice(format!("Channel Not Created, Its Name Is Too Long: {}", error)));
+            return;
+        }
+        self.known_channels.insert(channel.to_string());
+        self.join_channel(channel);
+    }
+
+    pub fn join_channel(&mut self, channel: &str) {
+        self.known_channels.insert(channel.to_string());
+        self.joined_channels.insert(channel.to_string());
+        self.current_channel = Some(channel.to_string());
+        self.emit(event::ChatEvent::Joined { channel: channel.to_string() });
+    }
+
+    pub fn leave_channel(&mut self, channel: &str) {
+        if !self.joined_channels.remove(channel) {
+            self.emit(event::ChatEvent::Notice(format!("You Are Not In #{}", channel)));
+            return;
+        }
+
+        if self.current_channel.as_deref() == Some(channel) {
+            self.current_channel = None;
+        }
+        if let Some(group) = self.private_channels.remove(channel) {
+            self.known_channels.remove(channel);
+            self.leave_private_channel(channel, group);
+        }
+        self.emit(event::ChatEvent::Left { channel: channel.to_string() });
+    }
+
+    pub fn create_private_channel(&mut self, channel: &str) {
+        let me: String = match &self.name {
+            Some(name) => name.clone(),
+            None => {
+                self.emit(event::ChatEvent::Notice(String::from("Please Set Your Name First!\n/name <Name>")));
+                return;
+            },
+        };
+        if self.known_channels.contains(channel) {
+            self.emit(event::ChatEvent::Notice(format!("Channel #{} Already Exists", channel)));
+            return;
+        }
+
+        self.private_channels.insert(channel.to_string(), groupkey::GroupKey::create(&me));
+        self.join_channel(channel);
+        self.emit(event::ChatEvent::Notice(format!("#{} Is Private, Use /invite <NAME> {} To Add Members", channel, channel)));
+    }
+
+    pub fn invite_member(&mut self, to: &str, channel: &str) {
+        let me: String = self.name.clone().unwrap_or_default();
+        let mut group: groupkey::GroupKey = match self.private_channels.get(channel) {
+            Some(group) if group.owner == me => group.clone(),
+            Some(group) => {
+                self.emit(event::ChatEvent::Notice(format!("Only {} Can Invite To #{}", group.owner, channel)));
+                return;
+            },
+            None => {
+                self.emit(event::ChatEvent::Notice(format!("You Are Not In A Private Channel Named #{}", channel)));
+                return;
+            },
+        };
+        if group.members.contains(to) {
+            self.emit(event::ChatEvent::Notice(format!("{} Is Already In #{}", to, channel)));
+            return;
+        }
+
+        group.members.insert(to.to_string());
+        if self.send_invite(to, channel, &group) {
+            self.emit(event::ChatEvent::Notice(format!("Invited {} To #{}", to, channel)));
+            self.private_channels.insert(channel.to_string(), group);
+        }
+    }
+
+    // A token for joining the network through us at addrs, see invite.rs.
+    // With a channel the token joins it too, and carries its key if it's
+    // private, so only the owner can hand those out.
+    pub fn invite(&mut self, addrs: &[std::net::IpAddr], channel: std::option::Option<&str>) -> std::option::Option<String> {
+        let channel: std::option::Option<(String, std::option::Option<groupkey::GroupKey>)> = match channel {
+            None => None,
+            Some(channel) => match self.private_channels.get(channel) {
+                Some(group) if Some(&group.owner) == self.name.as_ref() => {
+                    //the member list only matters to the owner, leave it out of the token
+                    let mut group: groupkey::GroupKey = group.clone();
+                    group.members = std::iter::once(group.owner.clone()).collect();
+                    Some((channel.to_string(), Some(group)))
+                },
+                Some(group) => {
+                    self.emit(event::ChatEvent::Notice(format!("Only {} Can Invite To #{}", group.owner, channel)));
+                    return None;
+                },
+                None if self.known_channels.contains(channel) => Some((channel.to_string(), None)),
+                None => {
+                    self.emit(event::ChatEvent::Notice(format!("No Channel Named #{}", channel)));
+                    return None;
+                },
+            },
+        };
+
+        let invite: invite::Invite = invite::Invite {
+            addrs: addrs.to_vec(),
+            port: self.host_port,
+            public_key: self.identity.public_key(),
+            channel,
+        };
+        match invite.to_token() {
+            Ok(token) => Some(token),
+            Err(error) => {
+                self.emit(event::ChatEvent::Notice(format!("Couldn't Make An Invite: {}", error)));
+                None
+            },
+        }
+    }
+
+    // Follows a token from invite(): joins its channel, then tries its
+    // addresses in turn until one turns out to be the node that made it.
+    pub fn join_invite(&mut self, token: &str) {
+        let invite: invite::Invite = match invite::Invite::from_token(token) {
+            Some(invite) => invite,
+            None => {
+                self.emit(event::ChatEvent::Notice(String::from("That Invite Isn't Valid")));
+                return;
+            },
+        };
+        if invite.public_key == self.identity.public_key() {
+            self.emit(event::ChatEvent::Notice(String::from("That Invite Is Your Own")));
+            return;
+        }
+
+        match invite.channel {
+            Some((channel, Some(group))) => {
+                let newer: bool = self.private_channels.get(&channel).is_none_or(|current| current.epoch < group.epoch);
+                if newer {
+                    self.private_channels.insert(channel.clone(), group);
+                }
+                if !self.joining.contains(&channel) {
+                    self.joining.push(channel.clone());
+                }
+                self.join_channel(&channel);
+            },
+            Some((channel, None)) => self.join_channel(&channel),
+            None => {},
+        };
+
+        let port: u16 = invite.port;
+        let peers: Vec<chatlib::Peer> = invite.addrs.into_iter()
+                                            .map(|addr| chatlib::Peer::new(Some(std::net::SocketAddr::new(addr, port)), port))
+                                            .collect();
+        self.failing_over = Some(peers);
+        self.bootstrapping = false;
+        self.invited = Some(invite.public_key);
+        self.next_fallback();
+    }
+
+    // Seals the channel key to the member's identity key and routes it to them.
+    fn send_invite(&mut self, to: &str, channel: &str, group: &groupkey::GroupKey) -> bool {
+        let public_key: identity::PublicKey = match self.bindings.get(to) {
+            Some(public_key) => *public_key,
+            None => {
+                self.emit(event::ChatEvent::Notice(format!("Don't Know {}'s Key Yet, They Have To Say Something First", to)));
+                return false;
+            },
+        };
+        let blob: std::option::Option<Vec<u8>> = group.to_bytes(channel).ok().and_then(|bytes| groupkey::seal_to(&public_key, &bytes));
+        let blob: Vec<u8> = match blob {
+            Some(blob) => blob,
+            None => {
+                self.emit(event::ChatEvent::Warning(format!("Couldn't Seal Invite For {}", to)));
+                return false;
+            },
+        };
+
+        let from: String = self.name.clone().unwrap_or_default();
+        let mut hdr: chatlib::ChatHeader = chatlib::ChatHeader::from_invite();
+        let sealed: Result<Vec<u8>, chatlib::FrameError> = chatlib::pack_fields(&[to.as_bytes(), from.as_bytes(), &blob]).and_then(|payload| self.seal(&mut hdr, Some(&payload)));
+        match sealed {
+            Ok(buf) => {
+                self.route(to, &buf, -1);
+                true
+            },
+            Err(error) => {
+                self.emit(event::ChatEvent::Warning(format!("Couldn't Send Invite To {}: {}", to, error)));
+                false
+            },
+        }
+    }
+
+    fn accept_invite(&mut self, from: &str, channel: &str, group: groupkey::GroupKey) {
+        match self.private_channels.get(channel) {
+            Some(current) if current.owner != from || current.epoch >= group.epoch => {
+                return;
+            },
+            Some(_) => {
+                self.emit(event::ChatEvent::KeyRotated { channel: channel.to_string() });
+            },
+            None if group.owner != from => {
+                return;
+            },
+            None => {
+                self.emit(event::ChatEvent::Invited { from: from.to_string(), channel: channel.to_string() });
+            },
+        };
+
+        if self.name.as_ref() == Some(&group.owner) && group.owner != from {
+            self.emit(event::ChatEvent::Notice(format!("You Now Own #{}", channel)));
+        }
+        self.known_channels.insert(channel.to_string());
+        self.private_channels.insert(channel.to_string(), group);
+    }
+
+    // New key for everyone still in the channel, whoever left can't read on.
+    fn rekey_channel(&mut self, channel: &str, mut group: groupkey::GroupKey) {
+        group.rotate();
+        let me: String = self.name.clone().unwrap_or_default();
+        for member in group.members.clone() {
+            if member != me {
+                self.send_invite(&member, channel, &group);
+            }
+        }
+        if group.members.contains(&me) {
+            self.private_channels.insert(channel.to_string(), group);
+        }
+    }
+
+    fn leave_private_channel(&mut self, channel: &str, mut group: groupkey::GroupKey) {
+        let me: String = self.name.clone().unwrap_or_default();
+        if group.owner == me {
+            group.members.remove(&me);
+            if let Some(next) = group.members.iter().next().cloned() {
+                group.owner = next;
+                self.rekey_channel(channel, group);
+            }
+        }
+        else {
+            let mut hdr: chatlib::ChatHeader = chatlib::ChatHeader::from_part();
+            match chatlib::pack_fields(&[group.owner.as_bytes(), me.as_bytes(), channel.as_bytes()]).and_then(|payload| self.seal(&mut hdr, Some(&payload))) {
+                Ok(buf) => self.route(&group.owner, &buf, -1),
+                Err(error) => self.emit(event::ChatEvent::Warning(format!("Couldn't Tell {} You Left #{}: {}", group.owner, channel, error))),
+            };
+        }
+    }
+
+    // Tells the owners of private channels we joined with a token that we're
+    // in, once we have a name and are linked to the node that made the token.
+    // Our name goes encrypted under the channel key, which only a token holder
+    // has, so nobody can talk their way onto the member list without one.
+    fn send_joins(&mut self) {
+        let me: String = match &self.name {
+            Some(name) if self.up_stream.is_some() && self.invited.is_none() => name.clone(),
+            _ => return,
+        };
+
+        for channel in std::mem::take(&mut self.joining) {
+            let group: groupkey::GroupKey = match self.private_channels.get(&channel) {
+                Some(group) if group.owner != me => group.clone(),
+                _ => continue,
+            };
+            let (nonce, ciphertext) = group.encrypt(&channel, me.as_bytes());
+            let mut hdr: chatlib::ChatHeader = chatlib::ChatHeader::from_join();
+            match chatlib::pack_fields(&[group.owner.as_bytes(), me.as_bytes(), channel.as_bytes(), &group.epoch.to_be_bytes(), &nonce, &ciphertext]).and_then(|payload| self.seal(&mut hdr, Some(&payload))) {
+                Ok(buf) => self.route(&group.owner, &buf, -1),
+                Err(error) => self.emit(event::ChatEvent::Warning(format!("Couldn't Tell {} You Joined #{}: {}", group.owner, channel, error))),
+            };
+        }
+    }
+
+    fn member_joined(&mut self, member: &str, channel: &str, epoch: u32, nonce: &[u8], ciphertext: &[u8]) {
+        let me: String = self.name.clone().unwrap_or_default();
+        let group: &mut groupkey::GroupKey = match self.private_channels.get_mut(channel) {
+            Some(group) if group.owner == me => group,
+            _ => return,
+        };
+        match group.decrypt(channel, epoch, nonce, ciphertext) {
+            Some(plain) if plain == member.as_bytes() => {
+                if group.members.insert(member.to_string()) {
+                    self.emit(event::ChatEvent::Notice(format!("{} Joined #{} With An Invite", member, channel)));
+                }
+            },
+            _ => self.emit(event::ChatEvent::Warning(format!("Ignoring {} Joining #{}, Their Key Is Stale Or Wrong", member, channel))),
+        };
+    }
+
+    fn member_left(&mut self, member: &str, channel: &str) {
+        let me: String = self.name.clone().unwrap_or_default();
+        if let Some(mut group) = self.private_channels.get(channel).cloned() {
+            if group.owner == me && group.members.remove(member) {
+                self.emit(event::ChatEvent::Notice(format!("{} Left #{}, Rotating Its Key", member, channel)));
+                self.rekey_channel(channel, group);
+            }
+        }
+    }
+
+    fn send_sealed(&mut self, fd: i32, channel: &str, msg: &str) -> Result<(), chatlib::FrameError> {
+        let from: String = self.name.clone().unwrap_or_default();
+        let plain: Vec<u8> = chatlib::pack_fields(&[from.as_bytes(), msg.as_bytes()])?;
+        let (epoch, nonce, ciphertext) = match self.private_channels.get(channel) {
+            Some(group) => {
+                let (nonce, ciphertext) = group.encrypt(channel, &plain);
+                (group.epoch, nonce, ciphertext)
+            },
+            None => return Ok(()),
+        };
+        let payload: Vec<u8> = chatlib::pack_fields(&[channel.as_bytes(), &epoch.to_be_bytes(), &nonce, &ciphertext])?;
+        let hdr: chatlib::ChatHeader = self.send_flood(fd, chatlib::ChatHeader::from_sealed(), Some(&payload))?;
+        self.remember(Some(channel), &hdr, &from, identity::Trust::Verified, msg);
+        self.mark_shown(format!("#{}", channel), &hdr);
+        Ok(())
+    }
+
+    pub fn switch_channel(&mut self, channel: std::option::Option<String>) {
+        match channel {
+            None => {
+                self.current_channel = None;
+                self.emit(event::ChatEvent::Switched { channel: None });
+            },
+            Some(channel) => {
+                if self.joined_channels.contains(&channel) {
+                    self.current_channel = Some(channel.clone());
+                    self.emit(event::ChatEvent::Switched { channel: Some(channel) });
+                }
+                else {
+                    self.emit(event::ChatEvent::Notice(format!("Join #{} First!", channel)));
+                }
+            },
+        };
+    }
+
+    pub fn send_direct(&mut self, to: &str, msg: &str) {
+        let from: String = match &self.name {
+            Some(name) if name == to => {
+                self.emit(event::ChatEvent::Notice(String::from("You Can't Message Yourself!")));
+                return;
+            },
+            Some(name) => name.clone(),
+            None => {
+                self.emit(event::ChatEvent::Notice(String::from("Please Set Your Name First!\n/name <Name>")));
+                return;
+            },
+        };
+
+        if let Err(error) = self.send_private(to, &from, msg) {
+            self.not_sent(error);
+        }
+    }
+
+    fn send_private(&mut self, to: &str, from: &str, msg: &str) -> Result<(), chatlib::FrameError> {
+        let plain: Vec<u8> = chatlib::pack_fields(&[from.as_bytes(), msg.as_bytes()])?;
+        //once we know their key, the message is sealed to it and can be held for them
+        let sealed: std::option::Option<(String, Vec<u8>)> = self.bindings.get(to).and_then(|key| {
+            groupkey::seal_to(key, &plain).map(|blob| (identity::node_id(key), blob))
+        });
+        match sealed {
+            Some((node_id, blob)) => {
+                let mut hdr: chatlib::ChatHeader = chatlib::ChatHeader::from_mail();
+                let buf: Vec<u8> = self.seal(&mut hdr, Some(&chatlib::pack_fields(&[node_id.as_bytes(), &blob])?))?;
+                self.mark_shown(format!("@{}", to), &hdr);
+                match self.route_to_node(&node_id, -1) {
+                    Some(next_hop) => self.send_to(next_hop, &buf),
+                    None => {
+                        if let Some(id) = hdr.id {
+                            self.hold_mail(&node_id, id, &buf);
+                        }
+                        self.broadcast(&buf, -1, false);
+                    },
+                };
+            },
+            None => {
+                let mut hdr: chatlib::ChatHeader = chatlib::ChatHeader::from_direct();
+                let buf: Vec<u8> = self.seal(&mut hdr, Some(&chatlib::pack_fields(&[to.as_bytes(), from.as_bytes(), msg.as_bytes()])?))?;
+                self.mark_shown(format!("@{}", to), &hdr);
+                self.route(to, &buf, -1);
+            },
+        };
+        Ok(())
+    }
+
+    // The neighbor towards a node, through the route the name bound to its key
+    // was last heard on. Private mail follows it instead of being flooded.
+    fn route_to_node(&self, node_id: &str, fd: i32) -> std::option::Option<ConnId> {
+        self.bindings.iter()
+            .filter(|(_, key)| identity::node_id(key) == node_id)
+            .filter_map(|(name, _)| self.routes.get(name).copied())
+            .find(|&next_hop| next_hop != fd && self.is_stream(next_hop))
+    }
+
+    // Keeps a MAIL for a recipient we have no route to. One that wouldn't fit
+    // a HELD frame once it's passed on, next to a name as long as names get,
+    // isn't kept at all.
+    fn hold_mail(&mut self, node_id: &str, id: chatlib::MsgId, frame: &[u8]) {
+        let held: usize = chatlib::HEADER_SIZE + 1 + 2 + MAX_NAME_LEN + 2 + node_id.len() + 2 + frame.len();
+        if held > chatlib::MAX_FRAME_SIZE {
+            return;
+        }
+        let now: std::time::Instant = self.now();
+        self.mailbox.hold(node_id, id, frame, now);
+    }
+
+    // Hands the messages we kept for a node on towards it, through the
+    // neighbor its name announcement just came from.
+    fn pass_on_held(&mut self, fd: i32, name: &str, node_id: &str) {
+        let now: std::time::Instant = self.now();
+        let held: Vec<Vec<u8>> = self.mailbox.held_for(node_id, now);
+        if held.is_empty() {
+            return;
+        }
+
+        self.emit(event::ChatEvent::Notice(format!("Passing On {} Held Message(s) For {}", held.len(), name)));
+        for frame in held {
+            if let Ok(payload) = chatlib::pack_fields(&[name.as_bytes(), node_id.as_bytes(), &frame]) {
+                let buf: Vec<u8> = chatlib::to_raw(&chatlib::ChatHeader::from_held(), Some(&payload));
+                //a name longer than ours can be could still push it over
+                if buf.len() <= chatlib::MAX_FRAME_SIZE {
+                    self.send_to(fd, &buf);
+                }
+            }
+        }
+    }
+
+    // Sends towards the neighbor a name was last heard through, or to everyone
+    // but the sender when there's no route yet.
+    fn route(&mut self, to: &str, buf: &[u8], fd: i32) {
+        match self.routes.get(to) {
+            Some(&next_hop) if next_hop != fd && self.is_stream(next_hop) => {
+                self.send_to(next_hop, buf);
+            },
+            _ => {
+                self.broadcast(buf, fd, false);
+            },
+        };
+    }
+
+    fn send_to(&mut self, fd: i32, buf: &[u8]) {
+        let wire: Vec<u8> = match self.get_link(fd).map(|link| link.write(buf)) {
+            Some(Ok(wire)) => wire,
+            Some(Err(error)) => {
+                self.emit(event::ChatEvent::Warning(format!("In send_to(), Encryption Failure: {}", error)));
+                return;
+            },
+            None => return,
+        };
+        self.write_raw(fd, &wire);
+    }
+
+    fn write_raw(&mut self, fd: ConnId, bytes: &[u8]) {
+        if bytes.is_empty() || !self.is_stream(fd) {
+            return;
+        }
+        self.output.push(Output::Write(fd, bytes.to_vec()));
+    }
+
+    fn send_name(&mut self) {
+        if let Some(name) = self.name.clone() {
+            //set_name keeps names short enough to always fit
+            let _ = self.send_flood(-1, chatlib::ChatHeader::from_name(), Some(name.as_bytes()));
+        }
+    }
+
+    // Tells whoever holds a private message for us that we got it.
+    fn send_receipt(&mut self, id: &chatlib::MsgId) {
+        //just a message id, it always fits
+        let _ = self.send_flood(-1, chatlib::ChatHeader::from_receipt(), Some(id));
+    }
+
+    fn send_peer(&mut self, fd: i32) {
+        self.sync_pending = true;
+        let send_port: u16 = self.host_port;
+        let buf: Vec<u8> = chatlib::to_raw(&chatlib::ChatHeader::from_port(send_port), None);
+        self.send_to(fd, &buf);
+    }
+
+    // Tells every child where to go if we disappear: our parent and its
+    // backups first, then the siblings that joined before it. Nobody is sent
+    // into its own subtree, and the first child, having no one to go to once
+    // the ancestors are gone, becomes the new root the others fall back to.
+    fn send_failover(&mut self) {
+        let mut ancestors: Vec<chatlib::Peer> = Vec::new();
+        if self.up_stream.is_some() && self.connecting.is_none() && !self.probing {
+            ancestors.extend(self.up_stream_info);
+            ancestors.extend(self.fallbacks.iter().copied());
+        }
+
+        let children: Vec<(i32, chatlib::Peer)> = self.down_streams.iter().filter(|stream| stream.2).map(|stream| (stream.0, child_peer(stream))).collect();
+        for (rank, (fd, _)) in children.iter().enumerate() {
+            let mut fallbacks: Vec<chatlib::Peer> = ancestors.clone();
+            fallbacks.extend(children[..rank].iter().map(|(_, peer)| *peer));
+            fallbacks.truncate(MAX_FALLBACKS);
+            self.send_to(*fd, &chatlib::to_raw(&chatlib::ChatHeader::from_failover(), Some(&chatlib::pack_peers(&fallbacks))));
+        }
+    }
+
+    // Address of the child a newcomer on fd should go to instead.
+    fn pick_down_stream(&mut self, fd: i32) -> std::option::Option<(std::net::SocketAddr, u16)> {
+        let children: Vec<&chatlib::InfoStream> = self.down_streams.iter().filter(|stream| stream.2 && stream.0 != fd).collect();
+        let subtrees: Vec<balance::Subtree> = children.iter().map(|stream| stream.8).collect();
+        let index: usize = balance::pick(self.rebalance, &subtrees, &mut self.rebalance_turn)?;
+        Some((children[index].1, children[index].3))
+    }
+
+    // Sends the newcomer on fd to one of our children, false if there's none to send it to.
+    fn send_rebalance(&mut self, fd: i32) -> bool {
+        match self.pick_down_stream(fd) {
+            Some((addr, portno)) => {
+                self.send_to(fd, &chatlib::to_raw(&chatlib::ChatHeader::from_rebalance(addr, portno), None));
+                true
+            },
+            None => false,
+        }
+    }
+
+    fn subtree(&self) -> balance::Subtree {
+        balance::Subtree::above(self.down_streams.iter().filter(|stream| stream.2).map(|stream| stream.8))
+    }
+
+    // Tells our parent how big our subtree is, whenever that changed.
+    fn report_subtree(&mut self) {
+        let subtree: balance::Subtree = self.subtree();
+        if let Some(up_stream) = self.up_stream {
+            if self.reported != Some(subtree) {
+                self.reported = Some(subtree);
+                self.send_to(up_stream, &chatlib::to_raw(&chatlib::ChatHeader::from_subtree(), Some(&subtree.to_bytes())));
+            }
+        }
+    }
+
+    // Forgets everything about a connection.
+    fn forget(&mut self, fd: ConnId) {
+        if let Some(index) = self.get_stream_idx(fd) {
+            let stream: chatlib::InfoStream = self.down_streams.remove(index);
+            self.report_subtree();
+            if stream.2 {
+                self.send_failover();
+            }
+        };
+
+        self.routes.retain(|_, next_hop| *next_hop != fd);
+
+        if self.connecting.map(|(connecting, _, _)| connecting) == Some(fd) {
+            self.connecting = None;
+        }
+
+        if self.is_up_stream(fd) {
+            self.up_stream = None;
+            self.up_stream_info = None;
+            self.up_stream_port = 0;
+            self.fallbacks.clear();
+            self.probing = false;
+            let instance: String = self.instance.clone();
+            self.set_root(instance, 0);
+        }
+    }
+
+    // Pings links that went quiet and drops the ones that stopped answering,
+    // the same way a closed connection is handled so failover kicks in.
+    fn check_heartbeats(&mut self) {
+        let now: std::time::Instant = self.now();
+        let mut fds: Vec<i32> = self.down_streams.iter().map(|stream| stream.0).collect();
+        if let Some(up_stream) = self.up_stream {
+            fds.push(up_stream);
+        }
+
+        let heartbeat: heartbeat::Heartbeat = self.heartbeat;
+        for fd in fds {
+            let pulse: heartbeat::Pulse = match self.get_liveness(fd) {
+                Some(liveness) => liveness.tick(now, &heartbeat),
+                None => continue,
+            };
+            match pulse {
+                heartbeat::Pulse::Alive => {},
+                heartbeat::Pulse::Ping => {
+                    self.send_to(fd, &chatlib::to_raw(&chatlib::ChatHeader::from_ping(), None));
+                },
+                heartbeat::Pulse::Dead => {
+                    let peer: String = self.get_name(fd);
+                    self.emit(event::ChatEvent::Unresponsive { peer: peer.clone() });
+                    if let Err(error) = self.close_client(fd) {
+                        self.report(&peer, error);
+                    }
+                },
+            };
+        }
+    }
+}
+
+// Where a child listens.
+fn child_peer(stream: &chatlib::InfoStream) -> chatlib::Peer {
+    chatlib::Peer::new(Some(std::net::SocketAddr::new(stream.1.ip(), stream.3)), stream.3)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A lobby message from name, stamped with time and clock and signed by key if there is one.
+    fn message(key: std::option::Option<&identity::Identity>, name: &str, text: &str, time: u64, clock: u64) -> Vec<u8> {
+        let payload: Vec<u8> = chatlib::pack_fields(&[name.as_bytes(), text.as_bytes()]).unwrap();
+        let mut hdr: chatlib::ChatHeader = chatlib::ChatHeader::from_msg();
+        let id: chatlib::MsgId = hdr.id.unwrap();
+        hdr.stamp = Some(chatlib::Stamp { time, clock });
+        if let Some(key) = key {
+            hdr.auth = Some(chatlib::Auth {
+                public_key: key.public_key(),
+                signature: key.sign(&chatlib::signed_bytes(&hdr, &id, Some(&payload))),
+            });
+        }
+        chatlib::to_raw(&hdr, Some(&payload))
+    }
+
+    fn shown(core: &mut ChatCore) -> Vec<(String, bool)> {
+        core.take_events().into_iter().filter_map(|event| match event {
+            event::ChatEvent::Message { text, late, .. } => Some((text, late)),
+            _ => None,
+        }).collect()
+    }
+
+    #[test]
+    fn messages_stamped_in_the_future_are_dropped() {
+        let mut core: ChatCore = ChatCore::with_identity(7000, identity::Identity::from_secret([1u8; 32]));
+        let peer: identity::Identity = identity::Identity::from_secret([2u8; 32]);
+        let now: u64 = history::now_millis();
+
+        core.handle_recv(&message(Some(&peer), "n2", "from the far future", now, u64::MAX), 1).unwrap();
+        core.handle_recv(&message(Some(&peer), "n2", "an hour early", now + 60 * 60 * 1000, now), 1).unwrap();
+        let warnings: usize = core.take_events().iter().filter(|event| matches!(event, event::ChatEvent::Warning(text) if text.contains("Future"))).count();
+        assert_eq!(warnings, 2);
+        assert!(core.logical < now + 1000);
+
+        //and so they can't make what comes after look late
+        core.handle_recv(&message(Some(&peer), "n2", "on time", now, now), 1).unwrap();
+        assert_eq!(shown(&mut core), vec![(String::from("on time"), false)]);
+    }
+
+    #[test]
+    fn only_verified_stamps_move_our_clock() {
+        let mut core: ChatCore = ChatCore::with_identity(7000, identity::Identity::from_secret([1u8; 32]));
+        let peer: identity::Identity = identity::Identity::from_secret([2u8; 32]);
+        let impostor: identity::Identity = identity::Identity::from_secret([3u8; 32]);
+        let now: u64 = history::now_millis();
+        let ahead: u64 = now + 60 * 1000;
+
+        core.handle_recv(&message(Some(&peer), "n2", "hello", now, now), 1).unwrap();
+        core.handle_recv(&message(None, "n3", "unsigned", now, ahead), 1).unwrap();
+        core.handle_recv(&message(Some(&impostor), "n2", "forged", now, ahead + 1), 1).unwrap();
+        assert_eq!(shown(&mut core).len(), 3);
+        assert!(core.logical < ahead);
+
+        core.handle_recv(&message(Some(&peer), "n2", "verified", now, ahead + 2), 1).unwrap();
+        assert_eq!(core.logical, ahead + 2);
+    }
+}
@@ -1,12 +1,208 @@
+// Wire format (all integers big-endian)
+//
+//  offset  size  field
+//  0       4     magic "PRSM"
+//  4       1     protocol version
+//  5       1     message type (ChatType)
+//  6       1     flags
+//  7       4     body length in bytes
+//  11      n     body = [message id] + [signature] + peer section + payload
+//
+// Flags:
+//  0x01  body starts with a 16 byte message id
+//  0x02  signed: the sender's ed25519 public key (32) and signature (64)
+//        follow the message id. The signature covers the type byte, the
+//        message id and the payload.
+//  0x04  relayed: the frame was forwarded by a node other than its sender.
+//        Relays may set this, so it is not covered by the signature.
+//  0x08  stamped: the sender's clock follows the signature, its unix time in
+//        millis (8) and its logical clock (8), see Stamp. The signature
+//        covers it between the message id and the payload. Frames stamped
+//        more than a few minutes ahead of the receiver's clock are dropped.
+//        Added in version 3, along with JOIN.
+//
+// Unknown flag bits are rejected.
+//
+// Peer section, first byte is a tag:
+//  0  no peer
+//  1  port only:  port(2)
+//  4  IPv4:       ip(4) addr_port(2) port(2)
+//  6  IPv6:       ip(16) addr_port(2) port(2)
+//
+// Everything after the peer section up to the body length is the payload.
+//
+// FAILOVER has no peer section of its own, its payload is a run of peer
+// sections: the backup parents to try in order when the sender goes away.
+//
+// ROOT carries fields (the root's node id, the sender's depth below it as a
+// u32). MERGE carries the address of a root to connect to as its peer.
+//
+// SYNC asks a neighbor for the chat messages it saw lately, its payload is
+// fields (at most how many as a u32, from at most how many seconds back as a
+// u32). BACKLOG is the answer: fields, each one a whole REGULAR, CHANNEL or
+// SEALED frame exactly as it was flooded, oldest first.
+//
+// MAIL is a private message sealed to its recipient's identity key (see
+// groupkey::seal_to), its payload is fields (the recipient's node id, the
+// sealed fields (sender name, text)). It goes along the route to the
+// recipient when there is one, and is only flooded and held when there
+// isn't. RECEIPT is the recipient's signed word that it got one, its payload
+// is the MAIL's message id. HELD hands a MAIL a node kept for an absent
+// recipient on towards it once it's back: fields (recipient name, recipient
+// node id, the MAIL frame exactly as it was flooded).
+//
+// JOIN tells the owner of a private channel that someone joined it with an
+// invite token, so they get the key when it's next rotated. It is routed to
+// the owner like PART: fields (owner name, joiner name, channel, epoch as a
+// u32, nonce, the joiner's name encrypted under that epoch's channel key),
+// the last one proving the joiner holds the key from the token.
+
+pub const MAGIC: [u8; 4] = *b"PRSM";
+pub const PROTOCOL_VERSION: u8 = 3;
+pub const HEADER_SIZE: usize = 11;
+pub const MAX_FRAME_SIZE: usize = 64 * 1024;
+pub const MAX_FIELD_SIZE: usize = u16::MAX as usize;
+
+pub const FLAG_ID: u8 = 0x01;
+pub const FLAG_SIGNED: u8 = 0x02;
+pub const FLAG_RELAYED: u8 = 0x04;
+pub const FLAG_STAMPED: u8 = 0x08;
+const KNOWN_FLAGS: u8 = FLAG_ID | FLAG_SIGNED | FLAG_RELAYED | FLAG_STAMPED;
+
+pub type MsgId = [u8; 16];
+
+const PEER_NONE: u8 = 0;
+const PEER_PORT: u8 = 1;
+const PEER_V4: u8 = 4;
+const PEER_V6: u8 = 6;
+
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Copy, Clone, Debug, PartialEq)]
 pub enum ChatType {
     REGULAR,
     PORT,
     REBALANCE,
     FAILOVER,
     NAME,
+    CHANNEL,
+    CREATE,
+    DIRECT,
+    INVITE,
+    PART,
+    SEALED,
+    PING,
+    PONG,
+    SUBTREE,
+    ROOT,
+    PROBE,
+    MERGE,
+    SYNC,
+    BACKLOG,
+    MAIL,
+    RECEIPT,
+    HELD,
+    JOIN,
 }
 
-#[derive(Copy, Clone)]
+impl ChatType {
+    fn to_byte(self) -> u8 {
+        match self {
+            ChatType::REGULAR => 0,
+            ChatType::PORT => 1,
+            ChatType::REBALANCE => 2,
+            ChatType::FAILOVER => 3,
+            ChatType::NAME => 4,
+            ChatType::CHANNEL => 5,
+            ChatType::CREATE => 6,
+            ChatType::DIRECT => 7,
+            ChatType::INVITE => 8,
+            ChatType::PART => 9,
+            ChatType::SEALED => 10,
+            ChatType::PING => 11,
+            ChatType::PONG => 12,
+            ChatType::SUBTREE => 13,
+            ChatType::ROOT => 14,
+            ChatType::PROBE => 15,
+            ChatType::MERGE => 16,
+            ChatType::SYNC => 17,
+            ChatType::BACKLOG => 18,
+            ChatType::MAIL => 19,
+            ChatType::RECEIPT => 20,
+            ChatType::HELD => 21,
+            ChatType::JOIN => 22,
+        }
+    }
+
+    // Messages that are relayed beyond the neighbor rather than consumed by it,
+    // these always carry a message id.
+    pub fn is_flooded(self) -> bool {
+        !matches!(self, ChatType::PORT | ChatType::REBALANCE | ChatType::FAILOVER | ChatType::PING | ChatType::PONG | ChatType::SUBTREE
+                      | ChatType::ROOT | ChatType::PROBE | ChatType::MERGE | ChatType::SYNC | ChatType::BACKLOG | ChatType::HELD)
+    }
+
+    // What users say, the only frames a BACKLOG may carry.
+    pub fn is_chat(self) -> bool {
+        matches!(self, ChatType::REGULAR | ChatType::CHANNEL | ChatType::SEALED)
+    }
+
+    fn from_byte(b: u8) -> std::option::Option<Self> {
+        match b {
+            0 => Some(ChatType::REGULAR),
+            1 => Some(ChatType::PORT),
+            2 => Some(ChatType::REBALANCE),
+            3 => Some(ChatType::FAILOVER),
+            4 => Some(ChatType::NAME),
+            5 => Some(ChatType::CHANNEL),
+            6 => Some(ChatType::CREATE),
+            7 => Some(ChatType::DIRECT),
+            8 => Some(ChatType::INVITE),
+            9 => Some(ChatType::PART),
+            10 => Some(ChatType::SEALED),
+            11 => Some(ChatType::PING),
+            12 => Some(ChatType::PONG),
+            13 => Some(ChatType::SUBTREE),
+            14 => Some(ChatType::ROOT),
+            15 => Some(ChatType::PROBE),
+            16 => Some(ChatType::MERGE),
+            17 => Some(ChatType::SYNC),
+            18 => Some(ChatType::BACKLOG),
+            19 => Some(ChatType::MAIL),
+            20 => Some(ChatType::RECEIPT),
+            21 => Some(ChatType::HELD),
+            22 => Some(ChatType::JOIN),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum FrameError {
+    Incomplete,
+    BadMagic,
+    UnsupportedVersion(u8),
+    UnknownType(u8),
+    UnknownFlags(u8),
+    TooLarge(usize),
+    FieldTooLarge(usize),
+    Malformed,
+}
+
+impl std::fmt::Display for FrameError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            FrameError::Incomplete => write!(f, "incomplete frame"),
+            FrameError::BadMagic => write!(f, "bad magic bytes"),
+            FrameError::UnsupportedVersion(v) => write!(f, "unsupported protocol version {}", v),
+            FrameError::UnknownType(t) => write!(f, "unknown message type {}", t),
+            FrameError::UnknownFlags(b) => write!(f, "unknown flags {:#04x}", b),
+            FrameError::TooLarge(n) => write!(f, "frame of {} bytes exceeds the {} byte limit", n, MAX_FRAME_SIZE),
+            FrameError::FieldTooLarge(n) => write!(f, "field of {} bytes exceeds the {} byte limit", n, MAX_FIELD_SIZE),
+            FrameError::Malformed => write!(f, "malformed frame"),
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
 pub struct Peer{
     pub addr: std::option::Option<std::net::SocketAddr>,
     pub port: u16,
@@ -21,86 +217,618 @@ impl Peer {
     }
 }
 
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Auth {
+    pub public_key: [u8; 32],
+    pub signature: [u8; 64],
+}
+
+// When a message was sent. The logical clock never runs behind the wall clock
+// or any clock the sender heard before, so if one message was sent after the
+// sender saw another, its clock is the higher one.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Stamp {
+    pub time: u64,
+    pub clock: u64,
+}
+
+#[derive(Debug, PartialEq)]
 pub struct ChatHeader {
     pub chat_t: ChatType,
+    pub id: std::option::Option<MsgId>,
+    pub auth: std::option::Option<Auth>,
+    pub stamp: std::option::Option<Stamp>,
+    pub relayed: bool,
     pub peer: std::option::Option<Peer>,
 }
 
+pub fn new_msg_id() -> MsgId {
+    rand::random::<MsgId>()
+}
+
 impl ChatHeader {
+    // A header of the given type, with a fresh message id if it's flooded and
+    // nothing else set yet.
+    fn new(chat_t: ChatType) -> Self {
+        ChatHeader {
+            chat_t,
+            id: chat_t.is_flooded().then(new_msg_id),
+            auth: None,
+            stamp: None,
+            relayed: false,
+            peer: None,
+        }
+    }
+
     pub fn from_port(portno: u16) -> Self {
         ChatHeader {
-            chat_t: ChatType::PORT, 
             peer: Some(Peer::new(None, portno)),
+            ..ChatHeader::new(ChatType::PORT)
         }
     }
 
     pub fn from_msg() -> Self {
-        ChatHeader {
-            chat_t: ChatType::REGULAR,
-            peer: None,
-        }
+        ChatHeader::new(ChatType::REGULAR)
     }
 
     pub fn from_rebalance(addr: std::net::SocketAddr, portno: u16) -> Self {
         ChatHeader {
-            chat_t: ChatType::REBALANCE,
             peer: Some(Peer::new(Some(addr), portno)),
+            ..ChatHeader::new(ChatType::REBALANCE)
         }
     }
 
-    pub fn from_failover(addr: std::net::SocketAddr, portno: u16) -> Self {
-        ChatHeader {
-            chat_t: ChatType::FAILOVER,
-            peer: Some(Peer::new(Some(addr), portno)),
-        }
+    pub fn from_failover() -> Self {
+        ChatHeader::new(ChatType::FAILOVER)
     }
-    
+
     pub fn from_name() -> Self {
+        ChatHeader::new(ChatType::NAME)
+    }
+
+    pub fn from_channel() -> Self {
+        ChatHeader::new(ChatType::CHANNEL)
+    }
+
+    pub fn from_create() -> Self {
+        ChatHeader::new(ChatType::CREATE)
+    }
+
+    pub fn from_direct() -> Self {
+        ChatHeader::new(ChatType::DIRECT)
+    }
+
+    pub fn from_invite() -> Self {
+        ChatHeader::new(ChatType::INVITE)
+    }
+
+    pub fn from_join() -> Self {
+        ChatHeader::new(ChatType::JOIN)
+    }
+
+    pub fn from_part() -> Self {
+        ChatHeader::new(ChatType::PART)
+    }
+
+    pub fn from_sealed() -> Self {
+        ChatHeader::new(ChatType::SEALED)
+    }
+
+    pub fn from_ping() -> Self {
+        ChatHeader::new(ChatType::PING)
+    }
+
+    pub fn from_pong() -> Self {
+        ChatHeader::new(ChatType::PONG)
+    }
+
+    pub fn from_subtree() -> Self {
+        ChatHeader::new(ChatType::SUBTREE)
+    }
+
+    pub fn from_root() -> Self {
+        ChatHeader::new(ChatType::ROOT)
+    }
+
+    pub fn from_probe() -> Self {
+        ChatHeader::new(ChatType::PROBE)
+    }
+
+    pub fn from_merge(peer: Peer) -> Self {
         ChatHeader {
-            chat_t: ChatType::NAME,
-            peer: None,
+            peer: Some(peer),
+            ..ChatHeader::new(ChatType::MERGE)
         }
     }
 
-    pub fn from(t: ChatType, p: Peer) -> Self {
-        ChatHeader {
-            chat_t: t,
-            peer: Some(p),
+    pub fn from_sync() -> Self {
+        ChatHeader::new(ChatType::SYNC)
+    }
+
+    pub fn from_backlog() -> Self {
+        ChatHeader::new(ChatType::BACKLOG)
+    }
+
+    pub fn from_mail() -> Self {
+        ChatHeader::new(ChatType::MAIL)
+    }
+
+    pub fn from_receipt() -> Self {
+        ChatHeader::new(ChatType::RECEIPT)
+    }
+
+    pub fn from_held() -> Self {
+        ChatHeader::new(ChatType::HELD)
+    }
+}
+
+fn encode_peer(peer: &std::option::Option<Peer>, buf: &mut Vec<u8>) {
+    match peer {
+        None => buf.push(PEER_NONE),
+        Some(p) => {
+            match p.addr {
+                None => buf.push(PEER_PORT),
+                Some(std::net::SocketAddr::V4(a)) => {
+                    buf.push(PEER_V4);
+                    buf.extend_from_slice(&a.ip().octets());
+                    buf.extend_from_slice(&a.port().to_be_bytes());
+                },
+                Some(std::net::SocketAddr::V6(a)) => {
+                    buf.push(PEER_V6);
+                    buf.extend_from_slice(&a.ip().octets());
+                    buf.extend_from_slice(&a.port().to_be_bytes());
+                },
+            };
+            buf.extend_from_slice(&p.port.to_be_bytes());
+        },
+    };
+}
+
+fn read_u16(buf: &[u8], at: usize) -> std::option::Option<u16> {
+    buf.get(at..at + 2).map(|b| u16::from_be_bytes([b[0], b[1]]))
+}
+
+fn decode_peer(body: &[u8]) -> Result<(std::option::Option<Peer>, usize), FrameError> {
+    let ip_len: usize = match body.first() {
+        None => return Err(FrameError::Malformed),
+        Some(&PEER_NONE) => return Ok((None, 1)),
+        Some(&PEER_PORT) => 0,
+        Some(&PEER_V4) => 4,
+        Some(&PEER_V6) => 16,
+        Some(_) => return Err(FrameError::Malformed),
+    };
+
+    let mut at: usize = 1;
+    let mut addr: std::option::Option<std::net::SocketAddr> = None;
+    if ip_len > 0 {
+        let ip: std::net::IpAddr = match body.get(at..at + ip_len) {
+            Some(b) if ip_len == 4 => std::net::IpAddr::from([b[0], b[1], b[2], b[3]]),
+            Some(b) => {
+                let mut octets = [0u8; 16];
+                octets.copy_from_slice(b);
+                std::net::IpAddr::from(octets)
+            },
+            None => return Err(FrameError::Malformed),
+        };
+        at += ip_len;
+        let addr_port: u16 = read_u16(body, at).ok_or(FrameError::Malformed)?;
+        at += 2;
+        addr = Some(std::net::SocketAddr::new(ip, addr_port));
+    }
+
+    let port: u16 = read_u16(body, at).ok_or(FrameError::Malformed)?;
+    Ok((Some(Peer::new(addr, port)), at + 2))
+}
+
+pub fn to_raw(head: &ChatHeader, buffer: std::option::Option<&[u8]>) -> Vec<u8> {
+    let mut flags: u8 = 0;
+    let mut body: Vec<u8> = Vec::new();
+    if let Some(id) = head.id {
+        flags |= FLAG_ID;
+        body.extend_from_slice(&id);
+    }
+    if let Some(auth) = head.auth {
+        flags |= FLAG_SIGNED;
+        body.extend_from_slice(&auth.public_key);
+        body.extend_from_slice(&auth.signature);
+    }
+    if let Some(stamp) = head.stamp {
+        flags |= FLAG_STAMPED;
+        body.extend_from_slice(&stamp.time.to_be_bytes());
+        body.extend_from_slice(&stamp.clock.to_be_bytes());
+    }
+    if head.relayed {
+        flags |= FLAG_RELAYED;
+    }
+    encode_peer(&head.peer, &mut body);
+    if let Some(bytes) = buffer {
+        body.extend_from_slice(bytes);
+    };
+
+    let mut buf: Vec<u8> = Vec::with_capacity(HEADER_SIZE + body.len());
+    buf.extend_from_slice(&MAGIC);
+    buf.push(PROTOCOL_VERSION);
+    buf.push(head.chat_t.to_byte());
+    buf.push(flags);
+    buf.extend_from_slice(&(body.len() as u32).to_be_bytes());
+    buf.extend_from_slice(&body);
+
+    buf
+}
+
+// Payloads that carry several values (e.g. channel + text) are a sequence of
+// fields, each prefixed with its length as a u16, so no field can be longer
+// than MAX_FIELD_SIZE.
+pub fn pack_fields(fields: &[&[u8]]) -> Result<Vec<u8>, FrameError> {
+    let mut buf: Vec<u8> = Vec::new();
+    for field in fields {
+        if field.len() > MAX_FIELD_SIZE {
+            return Err(FrameError::FieldTooLarge(field.len()));
         }
+        buf.extend_from_slice(&(field.len() as u16).to_be_bytes());
+        buf.extend_from_slice(field);
     }
+    Ok(buf)
 }
 
-pub fn to_raw(head: &mut ChatHeader, buffer: std::option::Option<&[u8]>) -> Vec<u8> {
+pub fn unpack_fields(payload: &[u8]) -> Result<Vec<&[u8]>, FrameError> {
+    let mut fields: Vec<&[u8]> = Vec::new();
+    let mut at: usize = 0;
+    while at < payload.len() {
+        let len: usize = read_u16(payload, at).ok_or(FrameError::Malformed)? as usize;
+        at += 2;
+        fields.push(payload.get(at..at + len).ok_or(FrameError::Malformed)?);
+        at += len;
+    }
+    Ok(fields)
+}
+
+// A list of peers, each encoded like a peer section.
+pub fn pack_peers(peers: &[Peer]) -> Vec<u8> {
     let mut buf: Vec<u8> = Vec::new();
-    unsafe{
-        let head_slice: &[u8; std::mem::size_of::<ChatHeader>()] = std::mem::transmute(head);
-        buf.extend_from_slice(head_slice);
+    for peer in peers {
+        encode_peer(&Some(*peer), &mut buf);
     }
+    buf
+}
 
-    if let Some(bytes) = buffer {
+pub fn unpack_peers(payload: &[u8]) -> Result<Vec<Peer>, FrameError> {
+    let mut peers: Vec<Peer> = Vec::new();
+    let mut at: usize = 0;
+    while at < payload.len() {
+        match decode_peer(&payload[at..])? {
+            (Some(peer), used) => {
+                peers.push(peer);
+                at += used;
+            },
+            (None, _) => return Err(FrameError::Malformed),
+        };
+    }
+    Ok(peers)
+}
+
+// The bytes a sender signs: type, message id and payload.
+pub fn signed_bytes(hdr: &ChatHeader, id: &MsgId, payload: std::option::Option<&[u8]>) -> Vec<u8> {
+    let mut buf: Vec<u8> = Vec::new();
+    buf.push(hdr.chat_t.to_byte());
+    buf.extend_from_slice(id);
+    if let Some(stamp) = hdr.stamp {
+        buf.extend_from_slice(&stamp.time.to_be_bytes());
+        buf.extend_from_slice(&stamp.clock.to_be_bytes());
+    }
+    if let Some(bytes) = payload {
         buf.extend_from_slice(bytes);
-    };
-    
+    }
     buf
 }
 
-pub fn parse_raw(buffer: &mut [u8]) -> (std::option::Option<&ChatHeader>, std::option::Option<&[u8]>) {
-    let hdr_size: usize = std::mem::size_of::<ChatHeader>();
-    if buffer.len() < hdr_size {
-        (None, None)
+// Marks an encoded frame as forwarded without re-encoding it.
+pub fn mark_relayed(buffer: &mut [u8]) {
+    if buffer.len() >= HEADER_SIZE {
+        buffer[6] |= FLAG_RELAYED;
+    }
+}
+
+// Validates the fixed header and returns the total frame size it announces.
+pub fn frame_len(buffer: &[u8]) -> Result<usize, FrameError> {
+    if buffer.len() < HEADER_SIZE {
+        return Err(FrameError::Incomplete);
+    }
+    if buffer[0..4] != MAGIC {
+        return Err(FrameError::BadMagic);
     }
-    else{
-        let hdr: &mut ChatHeader;
-        unsafe{
-            hdr = &mut *(buffer as *mut _ as *mut ChatHeader);
+    if buffer[4] != PROTOCOL_VERSION {
+        return Err(FrameError::UnsupportedVersion(buffer[4]));
+    }
+    let body_len: u32 = u32::from_be_bytes([buffer[7], buffer[8], buffer[9], buffer[10]]);
+    Ok(HEADER_SIZE + body_len as usize)
+}
+
+pub fn parse_raw(buffer: &[u8]) -> Result<(ChatHeader, std::option::Option<&[u8]>), FrameError> {
+    let total: usize = frame_len(buffer)?;
+    if buffer.len() < total {
+        return Err(FrameError::Incomplete);
+    }
+
+    let chat_t: ChatType = ChatType::from_byte(buffer[5]).ok_or(FrameError::UnknownType(buffer[5]))?;
+    let flags: u8 = buffer[6];
+    if flags & !KNOWN_FLAGS != 0 {
+        return Err(FrameError::UnknownFlags(flags));
+    }
+
+    let mut body: &[u8] = &buffer[HEADER_SIZE..total];
+    let mut id: std::option::Option<MsgId> = None;
+    if flags & FLAG_ID != 0 {
+        let mut msg_id: MsgId = [0u8; 16];
+        msg_id.copy_from_slice(body.get(..16).ok_or(FrameError::Malformed)?);
+        id = Some(msg_id);
+        body = &body[16..];
+    }
+    let mut auth: std::option::Option<Auth> = None;
+    if flags & FLAG_SIGNED != 0 {
+        let keys: &[u8] = body.get(..96).ok_or(FrameError::Malformed)?;
+        let mut sig = Auth {
+            public_key: [0u8; 32],
+            signature: [0u8; 64],
+        };
+        sig.public_key.copy_from_slice(&keys[..32]);
+        sig.signature.copy_from_slice(&keys[32..]);
+        auth = Some(sig);
+        body = &body[96..];
+    }
+    let mut stamp: std::option::Option<Stamp> = None;
+    if flags & FLAG_STAMPED != 0 {
+        let clocks: &[u8] = body.get(..16).ok_or(FrameError::Malformed)?;
+        let mut time = [0u8; 8];
+        let mut clock = [0u8; 8];
+        time.copy_from_slice(&clocks[..8]);
+        clock.copy_from_slice(&clocks[8..]);
+        stamp = Some(Stamp {
+            time: u64::from_be_bytes(time),
+            clock: u64::from_be_bytes(clock),
+        });
+        body = &body[16..];
+    }
+    let (peer, used) = decode_peer(body)?;
+
+    let hdr = ChatHeader {
+        chat_t,
+        id,
+        auth,
+        stamp,
+        relayed: flags & FLAG_RELAYED != 0,
+        peer,
+    };
+    if body.len() > used {
+        Ok((hdr, Some(&body[used..])))
+    }
+    else {
+        Ok((hdr, None))
+    }
+}
+
+// Accumulates bytes read from one connection and hands back whole frames,
+// keeping any trailing partial frame until the rest of it arrives.
+#[derive(Default)]
+pub struct FrameBuffer {
+    buf: Vec<u8>,
+}
+
+impl FrameBuffer {
+    pub fn new() -> Self {
+        FrameBuffer {
+            buf: Vec::new(),
         }
-        if buffer.len() > hdr_size {
-            (Some(hdr), Some(&buffer[hdr_size..]))
+    }
+
+    pub fn extend(&mut self, bytes: &[u8]) {
+        self.buf.extend_from_slice(bytes);
+    }
+
+    pub fn clear(&mut self) {
+        self.buf.clear();
+    }
+
+    pub fn next_frame(&mut self) -> Result<std::option::Option<Vec<u8>>, FrameError> {
+        let total: usize = match frame_len(&self.buf) {
+            Ok(total) => total,
+            Err(FrameError::Incomplete) => return Ok(None),
+            Err(error) => return Err(error),
+        };
+
+        if total > MAX_FRAME_SIZE {
+            return Err(FrameError::TooLarge(total));
         }
-        else {
-            (Some(hdr), None)
+        if self.buf.len() < total {
+            return Ok(None);
         }
+
+        Ok(Some(self.buf.drain(..total).collect()))
     }
 }
 
-pub struct InfoStream(pub std::net::TcpStream, pub std::net::SocketAddr, pub bool, pub u16, pub String);
+// Remembers the most recent message ids so flooded messages that reach a node
+// a second time (over a cycle in the overlay) are dropped instead of relayed.
+pub struct SeenSet {
+    ids: std::collections::HashSet<MsgId>,
+    order: std::collections::VecDeque<MsgId>,
+    capacity: usize,
+}
+
+impl SeenSet {
+    pub fn new(capacity: usize) -> Self {
+        SeenSet {
+            ids: std::collections::HashSet::with_capacity(capacity),
+            order: std::collections::VecDeque::with_capacity(capacity),
+            capacity,
+        }
+    }
+
+    pub fn contains(&self, id: &MsgId) -> bool {
+        self.ids.contains(id)
+    }
+
+    // Returns false if the id was already seen.
+    pub fn insert(&mut self, id: MsgId) -> bool {
+        if self.ids.contains(&id) {
+            return false;
+        }
+
+        if self.order.len() >= self.capacity {
+            if let Some(oldest) = self.order.pop_front() {
+                self.ids.remove(&oldest);
+            }
+        }
+        self.ids.insert(id);
+        self.order.push_back(id);
+        true
+    }
+}
+
+// Where a node keeps its key and other local state: $PRISM_HOME, or ~/.prism.
+pub fn data_dir() -> std::path::PathBuf {
+    match std::env::var_os("PRISM_HOME") {
+        Some(dir) => std::path::PathBuf::from(dir),
+        None => {
+            match std::env::var_os("HOME") {
+                Some(home) => std::path::PathBuf::from(home).join(".prism"),
+                None => std::path::PathBuf::from(".prism"),
+            }
+        },
+    }
+}
+
+pub struct InfoStream(pub i32, pub std::net::SocketAddr, pub bool, pub u16, pub String, pub FrameBuffer, pub crate::secure::SecureLink, pub crate::heartbeat::Liveness, pub crate::balance::Subtree);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn header(chat_t: ChatType, peer: std::option::Option<Peer>) -> ChatHeader {
+        ChatHeader {
+            chat_t,
+            id: None,
+            auth: None,
+            stamp: None,
+            relayed: false,
+            peer,
+        }
+    }
+
+    #[test]
+    fn frames_round_trip_with_every_kind_of_peer() {
+        let peers: [std::option::Option<Peer>; 4] = [
+            None,
+            Some(Peer::new(None, 4000)),
+            Some(Peer::new(Some("10.0.0.7:4000".parse().unwrap()), 4001)),
+            Some(Peer::new(Some("[2001:db8::7]:4000".parse().unwrap()), 4002)),
+        ];
+        for peer in peers {
+            let hdr: ChatHeader = header(ChatType::REBALANCE, peer);
+            let buf: Vec<u8> = to_raw(&hdr, Some(b"payload"));
+            assert_eq!(parse_raw(&buf), Ok((hdr, Some(&b"payload"[..]))));
+        }
+    }
+
+    #[test]
+    fn ids_signatures_and_stamps_round_trip() {
+        let hdr: ChatHeader = ChatHeader {
+            chat_t: ChatType::CHANNEL,
+            id: Some([7u8; 16]),
+            auth: Some(Auth { public_key: [1u8; 32], signature: [2u8; 64] }),
+            stamp: Some(Stamp { time: 1_700_000_000_000, clock: 1_700_000_000_005 }),
+            relayed: true,
+            peer: None,
+        };
+        let buf: Vec<u8> = to_raw(&hdr, None);
+        assert_eq!(buf[6], FLAG_ID | FLAG_SIGNED | FLAG_RELAYED | FLAG_STAMPED);
+        assert_eq!(parse_raw(&buf), Ok((hdr, None)));
+    }
+
+    #[test]
+    fn peer_lists_round_trip() {
+        let peers: Vec<Peer> = vec![
+            Peer::new(Some("192.168.1.2:5000".parse().unwrap()), 5000),
+            Peer::new(Some("[::1]:5001".parse().unwrap()), 5001),
+        ];
+        assert_eq!(unpack_peers(&pack_peers(&peers)), Ok(peers));
+        assert_eq!(unpack_peers(&[PEER_NONE]), Err(FrameError::Malformed));
+        //an IPv6 section cut short
+        assert_eq!(unpack_peers(&[PEER_V6, 0, 0, 0, 0]), Err(FrameError::Malformed));
+    }
+
+    #[test]
+    fn bad_headers_are_rejected() {
+        let frame: Vec<u8> = to_raw(&header(ChatType::PING, None), None);
+
+        let mut buf: Vec<u8> = frame.clone();
+        buf[0] = b'X';
+        assert_eq!(parse_raw(&buf), Err(FrameError::BadMagic));
+
+        buf = frame.clone();
+        buf[4] = PROTOCOL_VERSION + 1;
+        assert_eq!(parse_raw(&buf), Err(FrameError::UnsupportedVersion(PROTOCOL_VERSION + 1)));
+
+        buf = frame.clone();
+        buf[5] = 0xff;
+        assert_eq!(parse_raw(&buf), Err(FrameError::UnknownType(0xff)));
+
+        buf = frame.clone();
+        buf[6] = 0x80;
+        assert_eq!(parse_raw(&buf), Err(FrameError::UnknownFlags(0x80)));
+
+        assert_eq!(parse_raw(&frame[..frame.len() - 1]), Err(FrameError::Incomplete));
+
+        let mut frames: FrameBuffer = FrameBuffer::new();
+        frames.extend(b"GET / HTTP/1.1\r\n");
+        assert_eq!(frames.next_frame(), Err(FrameError::BadMagic));
+    }
+
+    #[test]
+    fn frames_split_across_reads_are_put_back_together() {
+        let frame: Vec<u8> = to_raw(&header(ChatType::REGULAR, None), Some(b"one byte at a time"));
+        let mut frames: FrameBuffer = FrameBuffer::new();
+        for byte in &frame[..frame.len() - 1] {
+            frames.extend(&[*byte]);
+            assert_eq!(frames.next_frame(), Ok(None));
+        }
+        frames.extend(&frame[frame.len() - 1..]);
+        assert_eq!(frames.next_frame(), Ok(Some(frame)));
+        assert_eq!(frames.next_frame(), Ok(None));
+    }
+
+    #[test]
+    fn frames_merged_in_one_read_come_out_one_by_one() {
+        let first: Vec<u8> = to_raw(&header(ChatType::PING, None), None);
+        let second: Vec<u8> = to_raw(&header(ChatType::PORT, Some(Peer::new(None, 4000))), None);
+        let third: Vec<u8> = to_raw(&header(ChatType::REGULAR, None), Some(b"the rest comes later"));
+
+        let mut frames: FrameBuffer = FrameBuffer::new();
+        frames.extend(&[first.as_slice(), second.as_slice(), &third[..5]].concat());
+        assert_eq!(frames.next_frame(), Ok(Some(first)));
+        assert_eq!(frames.next_frame(), Ok(Some(second)));
+        assert_eq!(frames.next_frame(), Ok(None));
+        frames.extend(&third[5..]);
+        assert_eq!(frames.next_frame(), Ok(Some(third)));
+    }
+
+    #[test]
+    fn oversized_frames_are_refused_from_their_header() {
+        let mut buf: Vec<u8> = to_raw(&header(ChatType::REGULAR, None), None);
+        buf[7..11].copy_from_slice(&(MAX_FRAME_SIZE as u32).to_be_bytes());
+        let mut frames: FrameBuffer = FrameBuffer::new();
+        frames.extend(&buf[..HEADER_SIZE]);
+        assert_eq!(frames.next_frame(), Err(FrameError::TooLarge(HEADER_SIZE + MAX_FRAME_SIZE)));
+    }
+
+    #[test]
+    fn fields_round_trip_and_never_truncate() {
+        let long: Vec<u8> = vec![b'x'; MAX_FIELD_SIZE];
+        let packed: Vec<u8> = pack_fields(&[b"", b"channel", &long]).unwrap();
+        assert_eq!(unpack_fields(&packed), Ok(vec![&b""[..], &b"channel"[..], &long[..]]));
+
+        let too_long: Vec<u8> = vec![b'x'; MAX_FIELD_SIZE + 1];
+        assert_eq!(pack_fields(&[b"name", &too_long]), Err(FrameError::FieldTooLarge(MAX_FIELD_SIZE + 1)));
+        assert_eq!(unpack_fields(&[0, 5, b'a']), Err(FrameError::Malformed));
+    }
+}
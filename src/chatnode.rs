@@ -1,579 +1,515 @@
-extern crate regex;
-extern crate console;
-
 use std::os::unix::io::AsRawFd;
-use std::io::{BufRead};
 use std::io::{Write, Read};
-use std::cmp::Ordering;
-use console::style;
 
 const MAX_POLLS: usize = 5;
-const STD_IN: i32 = 0;
-const MAX_DOWNSTREAM: usize = 3;
-
 
-mod chatlib;
+//epoll keys of the listener and the discovery socket, connection ids start at 1
+const LISTENER: u64 = 0;
+const DISCOVERY: u64 = u64::MAX;
 
-pub struct ChatNode {
 
-    //self and children
-    host_listener: std::net::TcpListener,
-    host_port: u16,
-    down_streams: Vec<chatlib::InfoStream>,
-    name: std::option::Option<String>,
+mod chatlib;
+mod identity;
+mod secure;
+mod groupkey;
+mod heartbeat;
+mod balance;
+mod addrbook;
+mod discovery;
+mod invite;
+mod history;
+mod mailbox;
+mod event;
+mod error;
+mod chatcore;
+mod transport;
+mod sim;
+#[cfg(feature = "tokio")]
+mod tokionode;
+
+pub use event::{ChatEvent, ChannelInfo};
+pub use error::ChatError;
+pub use chatlib::{FrameError, Stamp, data_dir};
+pub use secure::LinkError;
+pub use identity::Trust;
+pub use balance::Rebalance;
+pub use discovery::Neighbor;
+pub use history::HistoryEntry;
+pub use chatcore::{ChatCore, ConnId, Output};
+pub use sim::Simulation;
+pub use transport::{Transport, Connection, TcpTransport, UnixTransport, MemoryTransport, MemoryListener};
+#[cfg(feature = "tokio")]
+pub use tokionode::AsyncChatNode;
+
+// Runs a ChatCore on a single-threaded epoll loop, over TCP unless it's given
+// another transport.
+pub struct ChatNode<T: transport::Transport = transport::TcpTransport> {
+    transport: T,
+    host_listener: T::Listener,
     epoll_fd: i32,
+    streams: std::collections::HashMap<chatcore::ConnId, Conn<T::Stream>>,
+    discovery: std::option::Option<std::net::UdpSocket>,
+    core: chatcore::ChatCore,
+}
 
-    //parent
-    pub up_stream: Option<std::net::TcpStream>,
-    pub up_stream_port: u16,
-    up_stream_info: Option<chatlib::Peer>,
-
-    //failover
-    failover: Option<chatlib::Peer>,
-
-    //successor
-    successor: Option<i32>,
+// One open connection, and what the socket didn't take yet. While anything is
+// left, or while we're still connecting out, it's polled for being writable too.
+struct Conn<S> {
+    stream: S,
+    outbox: Vec<u8>,
+    writable: bool,
+    connecting: std::option::Option<std::net::SocketAddr>,
 }
 
-#[warn(dead_code, unused_assignments)]
-impl ChatNode {
-    pub fn new(addr: std::net::SocketAddr, port: u16) -> Self {
-        ChatNode {
-            host_listener: std::net::TcpListener::bind(addr).unwrap(),
-            host_port: port,
-            down_streams: Vec::new(),
-            name: None,
-            epoll_fd: -1,
-            up_stream: None,
-            up_stream_port: 0,
-            up_stream_info: None,
-            failover: None,
-            successor: None,
+impl<S> Conn<S> {
+    fn new(stream: S) -> Self {
+        Conn {
+            stream,
+            outbox: Vec::new(),
+            writable: false,
+            connecting: None,
         }
     }
+}
 
-    fn set_peer(&mut self, fd: i32, portno: u16) {
-        for mut stream in &mut self.down_streams {
-            if stream.0.as_raw_fd() == fd {
-                stream.2 = true;
-                stream.3 = portno;
-            }
-        }
+impl ChatNode {
+    pub fn new(addr: std::net::SocketAddr, port: u16) -> Result<Self, ChatError> {
+        ChatNode::with_transport(transport::TcpTransport, addr, port, &chatlib::data_dir())
     }
+}
 
-    fn _is_peer(&self, fd: i32) -> bool {
-        for stream in &self.down_streams {
-            if stream.0.as_raw_fd() == fd && stream.2{
-                return true;
-            }
-        }
-        false
-    }
-    
-    fn get_peer_port(&self, fd: i32) -> u16 {
-        for stream in &self.down_streams {
-            if stream.0.as_raw_fd() == fd {
-                return stream.3;
-            }
-        }
-        println!("couldn't find port");
-        0
+impl<T: transport::Transport> ChatNode<T> {
+    // The node keeps its identity, address book and history in dir.
+    pub fn with_transport(mut transport: T, addr: std::net::SocketAddr, port: u16, dir: &std::path::Path) -> Result<Self, ChatError> {
+        let host_listener: T::Listener = transport.listen(addr)?;
+        let epoll_fd: i32 = epoll::create(false).map_err(ChatError::Poll)?;
+
+        let node = ChatNode {
+            transport,
+            host_listener,
+            epoll_fd,
+            streams: std::collections::HashMap::new(),
+            discovery: None,
+            core: chatcore::ChatCore::new(port, dir),
+        };
+        node.add_poll(node.host_listener.as_raw_fd(), LISTENER)?;
+        Ok(node)
     }
 
-    fn set_name(&mut self, name: &str) {
-        if self.name == None {
-            self.name = Some(String::from(name));
-            println!("Welcome {}!", name);
-            self.send_name();
-        }
-        else{
-            println!("Setting Name Again Not Allowed!");
-        }
+    // Skips the encrypted handshake on new connections, both ends have to agree.
+    pub fn set_plaintext(&mut self, plaintext: bool) {
+        self.core.set_plaintext(plaintext);
     }
 
-    fn set_stream_name(&mut self, fd: i32, name: &str) {
-        for stream in self.down_streams.iter_mut() {
-            if stream.0.as_raw_fd() == fd {
-                stream.4 = name.to_string();
-            }
-        }
+    // How often quiet links are pinged and how many pings can go unanswered.
+    pub fn set_heartbeat(&mut self, interval: std::time::Duration, max_missed: u32) {
+        self.core.set_heartbeat(interval, max_missed);
     }
 
-    fn broadcast(&mut self, buf: &mut Vec<u8>, fd: i32, only_peer: bool) {
-        for i in 0..self.down_streams.len() {
-            if only_peer && !self.down_streams[i].2 { continue; }
-            else if self.down_streams[i].0.as_raw_fd() != fd {
-                match self.down_streams[i].0.write(buf){
-                    Ok(_) => {},
-                    Err(error) => {
-                        println!("In broadcast(), Write Failure: {:?}:", error);
-                    },
-                };
-            }
-        }
-
-        if self.up_stream.is_some() && fd != self.up_stream.as_ref().unwrap().as_raw_fd(){
-                match self.up_stream.as_ref().unwrap().write(buf) {
-                    Ok(_) => {},
-                    Err(error) => {
-                        println!("In broadcast(), Write Failure: {:?}:", error);
-                    },
-                };
-        }
+    // How many children we take before sending newcomers further down.
+    pub fn set_max_children(&mut self, max_children: usize) {
+        self.core.set_max_children(max_children);
     }
-    
-    fn handle_recv(&mut self, buf: &mut Vec<u8>, fd: i32) {
-        match buf.len() {
-            0 => { self.close_client(fd); },
-            _ => {
-                match chatlib::parse_raw(buf) {
-                    (None, None) => { println!("error parsing"); },
-                    (Some(hdr), payload) => {
-                        match hdr.chat_t {
-                            chatlib::ChatType::PORT => {
-                                let limit: usize = MAX_DOWNSTREAM;
-                                match self.down_streams.len().cmp(&limit) {
-                                    Ordering::Equal | Ordering::Greater => {
-                                        self.send_rebalance(fd);
-                                    },
-                                     _ => {
-                                        let portno: u16 = hdr.peer.as_ref().unwrap().port;
-                                        self.set_peer(fd, portno);
-                                        self.send_failover();
-                                    },
-                                };
-                                
-                            },
-                            chatlib::ChatType::REGULAR => {
-                                if let Some(load) = payload {
-                                    println!("{}", String::from_utf8(load.to_vec()).unwrap());
-                                    self.broadcast(buf, fd, false);
-                                }
-                            },
-                            chatlib::ChatType::FAILOVER => {
-                                self.failover = Some(hdr.peer.unwrap());
-                            },
-                            chatlib::ChatType::REBALANCE => {
-                                self.reconnect(&hdr.peer.unwrap());
-                            },
-                            chatlib::ChatType::NAME => {
-                                if let Some(load) = payload {
-                                    let name: String = String::from_utf8(load.to_vec()).unwrap();
-                                    self.set_stream_name(fd, &name);
-                                    let msg = format!("{} has Joined the Chat Room", name);
-                                    println!("{}", msg);
-                                    self.send_msg(fd, msg.as_ref());
-                                }
-                            },
-                        };
-                    },
-                    _ => { println!("invalid chat format"); },
-                };
-            },
-        };
-    }
-
-    fn handle_send(&mut self, msg: &str, fd: i32) {
-        let re = regex::Regex::new(r"^/(?P<cmd>[^\s\t\r\n]+)(?x)(?P<arg>[^\r\n]*)").unwrap();
-        let cap = re.captures(msg);
-        match cap {
-            None => {
-                match self.name {
-                    Some(_) => {
-                        let entire_msg: String = String::from(self.name.as_ref().unwrap()) + "> " + msg;
-                        self.send_msg(fd, entire_msg.as_ref());
-                    },
-                    None => println!("Please Set Your Name First!\n/name <Name>"),
-                };
-            },
-            Some(c) => {
-                match c.name("cmd").unwrap().as_str() {
-                    "name" => {
-                        let name: &str = c.name("arg").unwrap().as_str().trim();
-                        match name.len() {
-                            0 => println!("Enter Valid Name!"),
-                            _ => self.set_name(name.as_ref()), 
-                        };
-                    },
-                    "exit" => {
-                        std::process::exit(0);
-                    },
-                    "connect" => {
-                        println!("{}", c.name("arg").unwrap().as_str().trim());
-                        let con_re = regex::Regex::new(r"(?P<ip>[^\s\t\r\n]+)(?:[\s\t\r\n]*)(?P<port>[^\r\n]+)").unwrap();
-                        match con_re.captures(c.name("arg").unwrap().as_str().trim()){
-                            None => {
-                                println!("Please enter in the correct format!");
-                            },
-                            Some(c2) => {
-                                let connect_addr = std::net::SocketAddr::new(c2.name("ip").unwrap().as_str().parse::<std::net::IpAddr>().unwrap(), 
-                                                                             c2.name("port").unwrap().as_str().parse::<u16>().unwrap());
 
-                                self.reconnect(&chatlib::Peer::new(Some(connect_addr), connect_addr.port()));
-                            },
-                        };
-                        
-                    },
-                    "help" => {
-                        help();
-                    },
-                    _ => {/*  Ignore cmd */ },
-                }
-            },
-        };
+    // How the child a newcomer is sent on to gets picked.
+    pub fn set_rebalance(&mut self, rebalance: balance::Rebalance) {
+        self.core.set_rebalance(rebalance);
     }
-    
-    fn is_up_stream(&self, fd: i32) -> bool {
-        if self.up_stream.is_some() && fd == self.up_stream.as_ref().unwrap().as_raw_fd(){
-                return true;
-        }
 
-        false
+    // How long a connect, including each backup parent tried on failover, may take.
+    pub fn set_connect_timeout(&mut self, timeout: std::time::Duration) {
+        self.core.set_connect_timeout(timeout);
     }
 
-    fn is_stream(&self, fd: i32) -> bool {
-        for client in &self.down_streams {
-            if client.0.as_raw_fd() == fd {
-                return true;
-            }
-        }
-
-        self.is_up_stream(fd)
+    // How often the node, while it is a root, looks for other islands to merge with.
+    pub fn set_probe_interval(&mut self, interval: std::time::Duration) {
+        self.core.set_probe_interval(interval);
     }
 
+    // How many of the messages said before we linked up, from how far back,
+    // we ask each new neighbor for. Zero doesn't ask.
+    pub fn set_sync(&mut self, messages: usize, window: std::time::Duration) {
+        self.core.set_sync(messages, window);
+    }
 
-    fn get_stream(&mut self, fd: i32) -> std::option::Option<&std::net::TcpStream> {
-        for stream in &self.down_streams {
-             if stream.0.as_raw_fd() == fd {
-                return Some(&stream.0);
-            }
-        }
-
-         if self.is_up_stream(fd) {
-                return self.up_stream.as_ref();
-        }
-
-        None
+    // How long we keep private messages for recipients that may be offline,
+    // handing them on when they announce their name again. Zero keeps none.
+    pub fn set_hold(&mut self, hold: std::time::Duration) {
+        self.core.set_hold(hold);
     }
 
-    fn get_stream_idx(&mut self, fd: i32) -> std::option::Option<usize> {
-        for i in 0..self.down_streams.len() {
-            if self.down_streams[i].0.as_raw_fd() == fd{
-                return Some(i);
-            }
+    // Announces the node on the local network and listens for others doing
+    // the same, see neighbors().
+    pub fn set_discovery(&mut self, discovery: bool) -> Result<(), ChatError> {
+        //dropping the socket takes it out of epoll
+        self.discovery = None;
+        if discovery {
+            let socket: std::net::UdpSocket = discovery::open()?;
+            self.add_poll(socket.as_raw_fd(), DISCOVERY)?;
+            self.discovery = Some(socket);
         }
-        None
+        self.core.set_discovery(discovery);
+        self.flush();
+        Ok(())
     }
 
-    fn _get_stream_info(&mut self, fd: i32) -> std::option::Option<&chatlib::InfoStream> {
-        for stream in &self.down_streams {
-            if stream.0.as_raw_fd() == fd {
-                return Some(&stream);
-            }
-        }
+    pub fn set_name(&mut self, name: &str) {
+        self.core.set_name(name);
+        self.flush();
+    }
 
-        None
+    // Sends a message to the channel we're in, or to the lobby.
+    pub fn send(&mut self, msg: &str) {
+        self.core.send(msg);
+        self.flush();
     }
 
-    fn get_name(&self, fd: i32) -> String {
-        for stream in &self.down_streams {
-            if stream.0.as_raw_fd() == fd {
-                return String::from(&stream.4);
-            }
-        }
+    // Tries the peers this node heard of in earlier runs, most recent first.
+    pub fn bootstrap(&mut self) {
+        self.core.bootstrap();
+        self.flush();
+    }
 
-        if self.is_up_stream(fd) {
-            return String::from("Upstream");
+    // Makes addr our upstream, replacing the current one.
+    pub fn connect(&mut self, addr: std::net::SocketAddr) -> Result<(), ChatError> {
+        self.core.connect(addr)?;
+        match self.flush() {
+            Some(error) => Err(ChatError::Connect { addr, source: error }),
+            None => Ok(()),
         }
+    }
 
-        String::from("UnKnown")
+    pub fn name(&self) -> std::option::Option<&str> {
+        self.core.name()
     }
 
-    fn reconnect(&mut self, peer: &chatlib::Peer) {
-        if self.up_stream.is_some() {
-            println!("Closing connection with UpStream");
-            match self.up_stream.as_ref().unwrap().shutdown(std::net::Shutdown::Both){
-                Ok(_) => {
+    pub fn node_id(&self) -> String {
+        self.core.node_id()
+    }
 
-                    let fd: i32 = self.up_stream.as_ref().unwrap().as_raw_fd();
-                    self.remove_poll(fd);
-                },
-                Err(error) =>{
-                    println!("Socket Shutdown Failure: {:?}", error);
-                    std::process::exit(-1);
-                },
-            };
-        }
+    pub fn current_channel(&self) -> std::option::Option<&str> {
+        self.core.current_channel()
+    }
 
-        let addr: std::net::SocketAddr = std::net::SocketAddr::new(peer.addr.unwrap().ip(), peer.port);
-        match std::net::TcpStream::connect(addr) {
-            Ok(connection) => {
-                println!("Connected to {}", addr);
-                self.up_stream = Some(connection).take();
-                self.up_stream_port = peer.port;
-                self.up_stream_info = Some(*peer);
+    // The newest count messages of a channel, or of the lobby, oldest first.
+    pub fn history(&mut self, channel: std::option::Option<&str>, count: usize) -> Vec<history::HistoryEntry> {
+        self.core.history(channel, count)
+    }
 
-                self.add_poll(self.up_stream.as_ref().unwrap().as_raw_fd());
+    pub fn neighbors(&self) -> Vec<discovery::Neighbor> {
+        self.core.neighbors()
+    }
 
-                self.up_stream.as_ref().unwrap().set_nonblocking(true).expect("Error in SetNonBlocking(true)");
-                self.up_stream.as_ref().unwrap().set_nodelay(true).expect("set_nodelay failure");
-                self.send_peer();
-                self.send_name();
-            },
-            Err(_) => {
-                println!("Couldn't connect to {:?}", addr);  
-            },
-        };
+    pub fn peers(&self) -> Vec<String> {
+        self.core.peers()
     }
 
-    fn close_client(&mut self, fd: i32) {
-        println!("{} Closed Connection", self.get_name(fd));
-        if self.up_stream.is_some() && self.is_up_stream(fd) {
-            if let Some(peer) = self.failover {
-                self.reconnect(&peer);
-                return;
-            }  
-        }
-        
-        match self.get_stream(fd).unwrap().shutdown(std::net::Shutdown::Both){
-            Ok(_) => {},
-            Err(error) =>{
-                println!("Socket Shutdown Failure: {:?}", error);
-                std::process::exit(-1);
-            },
-        };
+    pub fn channels(&self) -> Vec<event::ChannelInfo> {
+        self.core.channels()
+    }
 
-        self.remove_poll(fd);
+    pub fn create_channel(&mut self, channel: &str) {
+        self.core.create_channel(channel);
+        self.flush();
     }
 
-    fn send_msg(&mut self, fd: i32, msg: &[u8]) {
-        let mut buf = chatlib::to_raw(&mut chatlib::ChatHeader::from_msg(), Some(msg));
-        self.broadcast(&mut buf, fd, false);
+    pub fn create_private_channel(&mut self, channel: &str) {
+        self.core.create_private_channel(channel);
+        self.flush();
     }
 
-    fn send_name(&mut self) {
-        if self.name.is_some() { 
-            let mut buf: Vec<u8> = chatlib::to_raw(&mut chatlib::ChatHeader::from_name(), Some(self.name.as_ref().unwrap().as_ref()));
-            self.broadcast(&mut buf, -1, true);
-        }
+    pub fn invite_member(&mut self, to: &str, channel: &str) {
+        self.core.invite_member(to, channel);
+        self.flush();
     }
 
-    fn send_peer(&mut self) {
-        let send_port: u16 = self.host_port;
-        let buf: Vec<u8> = chatlib::to_raw(&mut chatlib::ChatHeader::from_port(send_port), None);
-        self.up_stream.as_ref().unwrap().write_all(&buf).expect("Write Failure send_peer()");
+    // A token others can join the network through us with, optionally into
+    // channel. None if channel can't be handed out, the reason comes from poll.
+    pub fn invite(&mut self, channel: std::option::Option<&str>) -> std::option::Option<String> {
+        self.core.invite(&invite::local_addrs(), channel)
     }
 
-    fn assign_successor(&mut self) -> std::option::Option<(Vec<u8>, i32)> {
-        let fd: i32;
-        if self.up_stream.as_ref().is_some() {
-            fd = self.up_stream.as_ref().unwrap().as_raw_fd();
-            self.successor = Some(fd);
-            return Some((chatlib::to_raw(&mut chatlib::ChatHeader::from(chatlib::ChatType::FAILOVER, self.up_stream_info.unwrap()), None), fd));
-        }
-        match &self.successor {
-            Some(fd_stream) => {
-                fd = *fd_stream;
-                return Some((chatlib::to_raw(&mut chatlib::ChatHeader::from_failover(self.get_stream(fd).unwrap().peer_addr().unwrap(), self.get_peer_port(fd)), None), fd));
-            },
-            None  => {
-                for stream in &mut self.down_streams {
-                    if stream.2 {
-                        fd = stream.0.as_raw_fd();
-                        self.successor = Some(fd);
-                        let fail_ip: std::net::IpAddr = stream.0.peer_addr().unwrap().ip();
-                        let fail_port: u16 = self.get_peer_port(fd);
-                        return Some((chatlib::to_raw(&mut chatlib::ChatHeader::from_failover(std::net::SocketAddr::new(fail_ip, fail_port), fail_port), None), fd));
-                    }
-                }
-            },
-        };
+    // Joins the network through the node that made token, and its channel if it names one.
+    pub fn join_invite(&mut self, token: &str) {
+        self.core.join_invite(token);
+        self.flush();
+    }
 
-        None
+    pub fn join_channel(&mut self, channel: &str) {
+        self.core.join_channel(channel);
+        self.flush();
     }
 
-    fn send_failover(&mut self) {    
-        match self.assign_successor() {
-            None => {},
-            Some((mut buf, fd)) => {
-                self.broadcast(&mut buf, fd, true);
-            },
-        }
+    pub fn leave_channel(&mut self, channel: &str) {
+        self.core.leave_channel(channel);
+        self.flush();
     }
 
-    fn pick_down_stream(&mut self, fd: i32) -> (std::net::SocketAddr, u16) {
-        let len: usize = self.down_streams.len();
-        let mut index: usize = 0;
+    pub fn switch_channel(&mut self, channel: std::option::Option<String>) {
+        self.core.switch_channel(channel);
+        self.flush();
+    }
 
-        if self.down_streams.get(index).unwrap().0.as_raw_fd() == fd {
-            index = (index + 1) % len;
-        } 
+    pub fn send_direct(&mut self, to: &str, msg: &str) {
+        self.core.send_direct(to, msg);
+        self.flush();
+    }
 
-        let addr: std::net::SocketAddr = self.down_streams.get(index).unwrap().1;
-        let portno: u16 = self.down_streams.get(index).unwrap().3;
+    // Carries out what the core queued. Returns why the last connect failed,
+    // so connect() can report it; the core hears about every failure itself.
+    fn flush(&mut self) -> std::option::Option<std::io::Error> {
+        let mut connect_error: std::option::Option<std::io::Error> = None;
+        loop {
+            let output: Vec<chatcore::Output> = self.core.take_output();
+            if output.is_empty() {
+                return connect_error;
+            }
 
-        (addr, portno)
+            for out in output {
+                match out {
+                    chatcore::Output::Connect(id, addr) => {
+                        //the connection turning writable says how it went
+                        match self.open(id, addr) {
+                            Ok(_) => {},
+                            Err(error) => {
+                                connect_error = Some(std::io::Error::new(error.kind(), error.to_string()));
+                                self.core.connect_failed(id, addr, error);
+                            },
+                        };
+                    },
+                    chatcore::Output::Write(id, bytes) => {
+                        if let Some(conn) = self.streams.get_mut(&id) {
+                            conn.outbox.extend_from_slice(&bytes);
+                            self.write_stream(id);
+                        }
+                    },
+                    chatcore::Output::Close(id) => {
+                        if let Some(conn) = self.streams.remove(&id) {
+                            //dropping the stream closes it and takes it out of epoll
+                            let _ = conn.stream.shutdown();
+                        }
+                    },
+                    chatcore::Output::Announce(bytes) => {
+                        //a network without multicast just means nobody hears us
+                        if let Some(socket) = &self.discovery {
+                            let _ = socket.send_to(&bytes, (discovery::GROUP, discovery::PORT));
+                        }
+                    },
+                };
+            }
+        }
     }
 
-    fn send_rebalance(&mut self, fd: i32) {
-        let (addr, portno) = self.pick_down_stream(fd);
-        match self.get_stream(fd).unwrap().write(chatlib::to_raw(&mut chatlib::ChatHeader::from_rebalance(addr, portno), None).as_ref()){
-            Ok(_) => {},
-            Err(error) => {
-                println!("In broadcast(), Write Failure: {:?}:", error);
-            },
-        };    
+    fn open(&mut self, id: chatcore::ConnId, addr: std::net::SocketAddr) -> std::io::Result<()> {
+        let connection: T::Stream = self.transport.connect(addr)?;
+        epoll::ctl(self.epoll_fd, epoll::ControlOptions::EPOLL_CTL_ADD, connection.as_raw_fd(),
+                   epoll::Event::new(epoll::Events::EPOLLIN | epoll::Events::EPOLLOUT, id as u64))?;
+        let mut conn: Conn<T::Stream> = Conn::new(connection);
+        conn.writable = true;
+        conn.connecting = Some(addr);
+        self.streams.insert(id, conn);
+        Ok(())
     }
 
-    fn add_poll(&self, fd: i32) {
-        match epoll::ctl(   self.epoll_fd, epoll::ControlOptions::EPOLL_CTL_ADD, fd, 
-                            epoll::Event::new(epoll::Events::EPOLLIN, fd as u64)){
-            Ok(_) => {},
-            Err(error) => {
-                println!("Epoll Ctl Failure: {:?}", error);
-                std::process::exit(-1);
+    // A connection we opened turned writable, so it's either up or refused.
+    fn finish_connect(&mut self, id: chatcore::ConnId) {
+        let (addr, failure) = match self.streams.get_mut(&id) {
+            Some(conn) => match conn.connecting.take() {
+                Some(addr) => (addr, conn.stream.take_error()),
+                None => return,
             },
-        }
+            None => return,
+        };
+        match failure {
+            Ok(None) => {
+                self.core.connected(id, addr);
+                //whatever the core queued meanwhile can go out now
+                self.write_stream(id);
+            },
+            Ok(Some(error)) | Err(error) => {
+                self.streams.remove(&id);
+                self.core.connect_failed(id, addr, error);
+            },
+        };
     }
 
-    fn remove_poll(&mut self, fd: i32) {
-        match epoll::ctl(   self.epoll_fd, epoll::ControlOptions::EPOLL_CTL_DEL, fd, 
-                            epoll::Event::new(epoll::Events::EPOLLERR, fd as u64)){
-            Ok(_) => {},
+    fn accept(&mut self) {
+        match self.transport.accept(&self.host_listener) {
+            Ok((down_stream, down_stream_addr)) => {
+                let id: chatcore::ConnId = self.core.accept(down_stream_addr);
+                let registered: Result<(), ChatError> = self.add_poll(down_stream.as_raw_fd(), id as u64);
+                self.streams.insert(id, Conn::new(down_stream));
+                if let Err(error) = registered {
+                    self.core.failed(id, error);
+                }
+            },
+            Err(ref error) if error.kind() == std::io::ErrorKind::WouldBlock => {},
             Err(error) => {
-                println!("Epoll Ctl Failure: {:?}", error);
-                std::process::exit(-1);
+                self.core.warn(format!("Couldn't Accept New Connection: {:?}", error));
             },
         };
+    }
 
-        if let Some(index) = self.get_stream_idx(fd) {
-            self.down_streams.remove(index);
-        };
+    fn read_discovery(&mut self) {
+        let mut datagram = [0u8; 512];
+        loop {
+            let received: std::io::Result<(usize, std::net::SocketAddr)> = match &self.discovery {
+                Some(socket) => socket.recv_from(&mut datagram),
+                None => return,
+            };
+            match received {
+                Ok((count, from)) => self.core.discovered(from, &datagram[..count]),
+                Err(ref error) if error.kind() == std::io::ErrorKind::Interrupted => continue,
+                Err(_) => return,
+            };
+        }
+    }
 
-        if let Some(successor_fd) = self.successor {
-            if successor_fd == fd {
-                self.successor = None;
+    // Reads everything the socket has and hands it to the core.
+    fn read_stream(&mut self, id: chatcore::ConnId) {
+        let mut bytes: Vec<u8> = Vec::new();
+        let mut closed: bool = false;
+        let mut failure: std::option::Option<std::io::Error> = None;
+        if let Some(conn) = self.streams.get_mut(&id) {
+            loop {
+                let mut chunk = [0u8; 1400];
+                match conn.stream.read(&mut chunk) {
+                    Ok(0) => {
+                        closed = true;
+                        break;
+                    },
+                    Ok(count) => bytes.extend_from_slice(&chunk[..count]),
+                    Err(ref error) if error.kind() == std::io::ErrorKind::WouldBlock => { break; },
+                    Err(ref error) if error.kind() == std::io::ErrorKind::Interrupted => { continue; },
+                    Err(error) => {
+                        failure = Some(error);
+                        break;
+                    },
+                };
             }
-        };
+        }
 
-        if self.is_up_stream(fd) {
-            self.up_stream = None;
-            self.up_stream_info = None;
-            self.up_stream_port = 0;
+        if !bytes.is_empty() {
+            self.core.receive(id, &bytes);
+        }
+        if let Some(error) = failure {
+            self.core.failed(id, ChatError::Io(error));
+        }
+        else if closed {
+            self.core.closed(id);
         }
     }
 
-    pub fn start_routine(&mut self) {
-        let host_fd: i32 = self.host_listener.as_raw_fd();
-        
-        //create epoll
-        let fd_poller: i32 = match epoll::create(false) {
-            Ok(fd) => fd,
-            Err(error) => {
-                println!("Epoll Create Failure: {:?}", error);
-                std::process::exit(-1);
-            },
+    // Writes as much of what's queued for a connection as the socket takes
+    // without blocking. A socket that fills up keeps the rest until epoll says
+    // it's writable again, so a busy link is never mistaken for a broken one
+    // and never gets half a message followed by the next.
+    fn write_stream(&mut self, id: chatcore::ConnId) {
+        let conn: &mut Conn<T::Stream> = match self.streams.get_mut(&id) {
+            Some(conn) => conn,
+            None => return,
         };
-        self.epoll_fd = fd_poller;
-
-        //add tcp listener to read set
-        self.add_poll(host_fd);
-        self.add_poll(STD_IN);
-
-        //add upstream to read set
-        if let Some(stream) = &self.up_stream {
-                self.add_poll(stream.as_raw_fd());
-                self.up_stream_info = Some(chatlib::Peer::new(Some(self.up_stream.as_ref().unwrap().peer_addr().unwrap()), self.up_stream_port));
-                self.up_stream.as_ref().unwrap().set_nonblocking(true).expect("Error in SetNonBlocking(true)");
-                self.up_stream.as_ref().unwrap().set_nodelay(true).expect("set_nodelay failure");
-                self.send_peer();
-        };
-
-        loop{
-            let mut all_events: [epoll::Event; MAX_POLLS] = [epoll::Event::new(epoll::Events::EPOLLIN, 0); MAX_POLLS];
-            let num_events = match epoll::wait(fd_poller, -2, &mut all_events){
-                Ok(num) => num,
+        if conn.connecting.is_some() {
+            return;
+        }
+        let mut written: usize = 0;
+        let mut failure: std::option::Option<std::io::Error> = None;
+        while written < conn.outbox.len() {
+            match conn.stream.write(&conn.outbox[written..]) {
+                Ok(0) => {
+                    failure = Some(std::io::Error::from(std::io::ErrorKind::WriteZero));
+                    break;
+                },
+                Ok(count) => written += count,
+                Err(ref error) if error.kind() == std::io::ErrorKind::WouldBlock => { break; },
+                Err(ref error) if error.kind() == std::io::ErrorKind::Interrupted => { continue; },
                 Err(error) => {
-                    println!("Epoll Wait Failure: {:?}", error);
-                    continue
-                }
+                    failure = Some(error);
+                    break;
+                },
             };
+        }
+        conn.outbox.drain(..written);
 
-            for event in all_events.iter().take(num_events) {
-                let ready_fd: i32 = event.data as i32;
-                let _event = match epoll::Events::from_bits(event.events){
-                    Some(ev) => ev,
-                    _ => {
-                        println!("Error in from_bits()...");
-                        continue
-                    },
-                };
+        if let Some(error) = failure {
+            self.core.failed(id, ChatError::Io(error));
+            return;
+        }
+        let waiting: bool = !conn.outbox.is_empty();
+        if waiting != conn.writable {
+            conn.writable = waiting;
+            let fd: i32 = conn.stream.as_raw_fd();
+            if let Err(error) = self.modify_poll(fd, id as u64, waiting) {
+                self.core.failed(id, error);
+            }
+        }
+    }
 
-                match ready_fd {
-                    _ if ready_fd == host_fd => {
-                         match self.host_listener.accept() {
-                            Ok((down_stream, down_stream_addr)) => {
+    fn add_poll(&self, fd: i32, key: u64) -> Result<(), ChatError> {
+        epoll::ctl(self.epoll_fd, epoll::ControlOptions::EPOLL_CTL_ADD, fd,
+                   epoll::Event::new(epoll::Events::EPOLLIN, key)).map_err(ChatError::Poll)
+    }
 
-                                let client_fd = down_stream.as_raw_fd();
-                                down_stream.set_nonblocking(true).expect("Error in SetNonBlocking(true)");
-                                down_stream.set_nodelay(true).expect("set_nodelay failure");
+    // Polls a connection for being writable as well as readable, or stops.
+    fn modify_poll(&self, fd: i32, key: u64, writable: bool) -> Result<(), ChatError> {
+        let events: epoll::Events = match writable {
+            true => epoll::Events::EPOLLIN | epoll::Events::EPOLLOUT,
+            false => epoll::Events::EPOLLIN,
+        };
+        epoll::ctl(self.epoll_fd, epoll::ControlOptions::EPOLL_CTL_MOD, fd,
+                   epoll::Event::new(events, key)).map_err(ChatError::Poll)
+    }
 
-                                println!("Got Connection From {}", down_stream_addr);
-                                self.down_streams.push(chatlib::InfoStream(down_stream, down_stream_addr, false, 0, format!("Client {}", client_fd)));
-                                self.add_poll(client_fd);
-                            },
-                            Err(error) => {
-                                println!("Couldn't Accept New Connection: {:?}", error);
-                                continue;
-                            },
-                        };
-                    },
-                    _ if ready_fd == STD_IN => {
-                        let mut msg: String = String::new();
-                        if let Ok(_count) = std::io::stdin().lock().read_line(&mut msg) {
-                                self.handle_send(msg.trim(), ready_fd);
-                        };
-                    },
-                    _ if self.is_stream(ready_fd) => {
-                        let mut vecbuf: Vec<u8> = Vec::new();
-                        loop {
-                            let mut peakbuf = [0u8; 1400];
-                            let mut _peak_count: usize = 0;
-                            match self.get_stream(ready_fd).unwrap().peek(&mut peakbuf) {
-                                Ok(count) => { _peak_count = count; },
-                                Err(_) => { break; },
-                            };
-
-                            if _peak_count == 0 {
-                                break;
-                            }
-                            
-                            let mut buf = vec![0u8; _peak_count];
-                            match self.get_stream(ready_fd).unwrap().read_exact(&mut buf) {
-                                Ok(()) => {
-                                    vecbuf.extend_from_slice(&buf);
-                                },
-                                Err(_) => {
-                                    println!("BufRead Err");
-                                    self.close_client(ready_fd);
-                                    break;
-                                }
-                            };
-                        }
+    // Milliseconds until the next heartbeat tick, for epoll::wait.
+    fn poll_timeout(&self) -> i32 {
+        let wait: std::time::Duration = self.core.next_tick().saturating_duration_since(std::time::Instant::now());
+        wait.as_millis().min(i32::MAX as u128) as i32
+    }
 
-                        self.handle_recv(&mut vecbuf, ready_fd);
-                    },
-                    _ => { unreachable!("Epoll FD Picked Up FD That Is Not In Our Interest List"); },
-                };
-            }
+    // Runs the node until there is network activity, the next heartbeat is due
+    // or timeout passes (None waits indefinitely), and returns what happened.
+    pub fn poll(&mut self, timeout: std::option::Option<std::time::Duration>) -> Vec<event::ChatEvent> {
+        self.core.tick();
+        self.flush();
+
+        let wait: i32 = match timeout {
+            Some(timeout) => self.poll_timeout().min(timeout.as_millis().min(i32::MAX as u128) as i32),
+            None => self.poll_timeout(),
+        };
+        let mut all_events: [epoll::Event; MAX_POLLS] = [epoll::Event::new(epoll::Events::EPOLLIN, 0); MAX_POLLS];
+        let num_events: usize = match epoll::wait(self.epoll_fd, wait, &mut all_events){
+            Ok(num) => num,
+            Err(ref error) if error.kind() == std::io::ErrorKind::Interrupted => 0,
+            Err(error) => {
+                self.core.warn(format!("Epoll Wait Failure: {:?}", error));
+                0
+            },
+        };
+
+        for event in all_events.iter().take(num_events) {
+            match event.data {
+                LISTENER => self.accept(),
+                DISCOVERY => self.read_discovery(),
+                key => {
+                    let ready: epoll::Events = epoll::Events::from_bits_truncate(event.events);
+                    self.finish_connect(key as chatcore::ConnId);
+                    if ready.contains(epoll::Events::EPOLLOUT) {
+                        self.write_stream(key as chatcore::ConnId);
+                    }
+                    if ready.intersects(epoll::Events::EPOLLIN | epoll::Events::EPOLLHUP | epoll::Events::EPOLLERR) {
+                        self.read_stream(key as chatcore::ConnId);
+                    }
+                },
+            };
+            self.flush();
         }
+
+        self.core.take_events()
     }
 }
 
-pub fn help() {
-    println!("\t{}\t\t\t\t\t\t\t\t\t", style("COMMANDS").cyan());
-    println!("\t{}\t\t\t\t\t\t\t\t\t\t", style("1. /help").green());
-    println!("\t{}\t\t\t\t\t\t\t\t\t", style("2. /name <NAME>").green());
-    println!("\t{}\t\t\t\t\t",style("3. /connect <CONNECT-IP> <CONNECT-PORTNO>").green());
-    println!("\t{}\t\t\t\t\t\t\t\t\t\t", style("4. /exit").green());
+impl<T: transport::Transport> Drop for ChatNode<T> {
+    fn drop(&mut self) {
+        let _ = epoll::close(self.epoll_fd);
+    }
 }
@@ -0,0 +1,88 @@
+use crate::chatlib;
+
+// Nodes on one network segment find each other by multicasting a small
+// announcement every few seconds:
+//
+//  offset  size  field
+//  0       4     magic "PRSM"
+//  4       1     protocol version
+//  5       2     listening port
+//  7       n     fields (see chatlib::pack_fields): node id, name (empty if unset)
+//
+// The sender's address comes from the datagram itself.
+
+pub const GROUP: std::net::Ipv4Addr = std::net::Ipv4Addr::new(239, 255, 80, 82);
+pub const PORT: u16 = 7479;
+pub const ANNOUNCE_INTERVAL: std::time::Duration = std::time::Duration::from_secs(5);
+
+// A neighbor that missed this many announcements is forgotten.
+const MISSED_ANNOUNCEMENTS: u32 = 3;
+
+// A node heard announcing itself on the local network.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Neighbor {
+    pub addr: std::net::SocketAddr,
+    pub node_id: String,
+    pub name: std::option::Option<String>,
+}
+
+pub fn announcement(port: u16, node_id: &str, name: std::option::Option<&str>) -> Result<Vec<u8>, chatlib::FrameError> {
+    let mut buf: Vec<u8> = Vec::new();
+    buf.extend_from_slice(&chatlib::MAGIC);
+    buf.push(chatlib::PROTOCOL_VERSION);
+    buf.extend_from_slice(&port.to_be_bytes());
+    buf.extend_from_slice(&chatlib::pack_fields(&[node_id.as_bytes(), name.unwrap_or_default().as_bytes()])?);
+    Ok(buf)
+}
+
+pub fn parse(from: std::net::SocketAddr, bytes: &[u8]) -> std::option::Option<Neighbor> {
+    if bytes.len() < 7 || bytes[..4] != chatlib::MAGIC || bytes[4] != chatlib::PROTOCOL_VERSION {
+        return None;
+    }
+
+    let port: u16 = u16::from_be_bytes([bytes[5], bytes[6]]);
+    match chatlib::unpack_fields(&bytes[7..]).ok()?.as_slice() {
+        [node_id, name] => Some(Neighbor {
+            addr: std::net::SocketAddr::new(from.ip(), port),
+            node_id: String::from_utf8_lossy(node_id).to_string(),
+            name: match name.is_empty() {
+                true => None,
+                false => Some(String::from_utf8_lossy(name).to_string()),
+            },
+        }),
+        _ => None,
+    }
+}
+
+// Who we heard lately, by node id.
+#[derive(Default)]
+pub struct Neighbors {
+    heard: std::collections::BTreeMap<String, (Neighbor, std::time::Instant)>,
+}
+
+impl Neighbors {
+    // Records an announcement, true if it came from a node we didn't know yet.
+    pub fn heard(&mut self, neighbor: Neighbor, now: std::time::Instant) -> bool {
+        self.heard.insert(neighbor.node_id.clone(), (neighbor, now)).is_none()
+    }
+
+    pub fn expire(&mut self, now: std::time::Instant) {
+        self.heard.retain(|_, (_, last)| now.saturating_duration_since(*last) < ANNOUNCE_INTERVAL * MISSED_ANNOUNCEMENTS);
+    }
+
+    pub fn list(&self) -> Vec<Neighbor> {
+        self.heard.values().map(|(neighbor, _)| neighbor.clone()).collect()
+    }
+}
+
+// A nonblocking socket in the discovery group. Several nodes on one machine
+// can each open one, and they hear each other's announcements.
+pub fn open() -> std::io::Result<std::net::UdpSocket> {
+    let socket: socket2::Socket = socket2::Socket::new(socket2::Domain::IPV4, socket2::Type::DGRAM, Some(socket2::Protocol::UDP))?;
+    socket.set_reuse_address(true)?;
+    socket.bind(&std::net::SocketAddr::from(([0, 0, 0, 0], PORT)).into())?;
+    socket.join_multicast_v4(&GROUP, &std::net::Ipv4Addr::UNSPECIFIED)?;
+    socket.set_multicast_loop_v4(true)?;
+    socket.set_nonblocking(true)?;
+    Ok(socket.into())
+}
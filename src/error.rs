@@ -0,0 +1,58 @@
+use crate::chatlib;
+use crate::secure;
+
+// Everything that can go wrong inside a ChatNode. Failures tied to one
+// connection are handled by dropping that connection, the rest of the node
+// keeps running.
+#[derive(Debug)]
+pub enum ChatError {
+    Io(std::io::Error),
+    Poll(std::io::Error),
+    Connect { addr: std::net::SocketAddr, source: std::io::Error },
+    Link(secure::LinkError),
+    Frame(chatlib::FrameError),
+    Protocol(&'static str),
+    UnknownStream(i32),
+}
+
+impl std::fmt::Display for ChatError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            ChatError::Io(error) => write!(f, "socket error: {}", error),
+            ChatError::Poll(error) => write!(f, "epoll error: {}", error),
+            ChatError::Connect { addr, source } => write!(f, "couldn't connect to {}: {}", addr, source),
+            ChatError::Link(error) => write!(f, "secure link failed: {}", error),
+            ChatError::Frame(error) => write!(f, "bad frame: {}", error),
+            ChatError::Protocol(what) => write!(f, "protocol violation: {}", what),
+            ChatError::UnknownStream(fd) => write!(f, "no connection with fd {}", fd),
+        }
+    }
+}
+
+impl std::error::Error for ChatError {
+    fn source(&self) -> std::option::Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ChatError::Io(error) | ChatError::Poll(error) => Some(error),
+            ChatError::Connect { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for ChatError {
+    fn from(error: std::io::Error) -> Self {
+        ChatError::Io(error)
+    }
+}
+
+impl From<secure::LinkError> for ChatError {
+    fn from(error: secure::LinkError) -> Self {
+        ChatError::Link(error)
+    }
+}
+
+impl From<chatlib::FrameError> for ChatError {
+    fn from(error: chatlib::FrameError) -> Self {
+        ChatError::Frame(error)
+    }
+}
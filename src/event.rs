@@ -0,0 +1,90 @@
+use crate::chatlib;
+use crate::history;
+use crate::identity;
+
+// Everything a node reports back to the program embedding it, returned by
+// ChatNode::poll. Display renders the line the prism binary prints for it.
+#[derive(Clone, Debug, PartialEq)]
+pub enum ChatEvent {
+    NameSet { name: String, node_id: String },
+    //sent is the sender's stamp, None from nodes that don't stamp; late is set
+    //when something sent after it was shown first
+    Message { from: String, text: String, trust: identity::Trust, sent: std::option::Option<chatlib::Stamp>, late: bool },
+    ChannelMessage { channel: String, from: String, text: String, trust: identity::Trust, sent: std::option::Option<chatlib::Stamp>, late: bool },
+    DirectMessage { from: String, text: String, trust: identity::Trust, sent: std::option::Option<chatlib::Stamp>, late: bool },
+    PeerJoined { name: String, node_id: std::option::Option<String>, trust: identity::Trust },
+
+    //topology
+    Accepted { addr: std::net::SocketAddr },
+    Connected { addr: std::net::SocketAddr },
+    ConnectFailed { addr: std::net::SocketAddr },
+    LinkEstablished { peer: String, node_id: String },
+    Disconnected { peer: String },
+    Unresponsive { peer: String },
+    FailedOver { addr: std::net::SocketAddr },
+    Stranded,
+    Merged { addr: std::net::SocketAddr },
+    Discovered { name: std::option::Option<String>, addr: std::net::SocketAddr },
+
+    //channels
+    ChannelCreated { channel: String },
+    Joined { channel: String },
+    Left { channel: String },
+    Switched { channel: std::option::Option<String> },
+    Invited { from: String, channel: String },
+    KeyRotated { channel: String },
+
+    //anything else worth telling the user, and things that went wrong
+    Notice(String),
+    Warning(String),
+}
+
+impl std::fmt::Display for ChatEvent {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            ChatEvent::NameSet { name, node_id } => write!(f, "Welcome {}! Your Node Id Is {}", name, node_id),
+            ChatEvent::Message { from, text, trust, sent, late } => write!(f, "{}{}> {}{}", trust.tag(), from, text, lateness(sent, *late)),
+            ChatEvent::ChannelMessage { channel, from, text, trust, sent, late } => write!(f, "#{} {}{}> {}{}", channel, trust.tag(), from, text, lateness(sent, *late)),
+            ChatEvent::DirectMessage { from, text, trust, sent, late } => write!(f, "(private) {}{}> {}{}", trust.tag(), from, text, lateness(sent, *late)),
+            ChatEvent::PeerJoined { name, node_id: Some(node_id), trust } => write!(f, "{}{} has Joined the Chat Room ({})", trust.tag(), name, node_id),
+            ChatEvent::PeerJoined { name, node_id: None, trust } => write!(f, "{}{} has Joined the Chat Room", trust.tag(), name),
+            ChatEvent::Accepted { addr } => write!(f, "Got Connection From {}", addr),
+            ChatEvent::Connected { addr } => write!(f, "Connected to {}", addr),
+            ChatEvent::ConnectFailed { addr } => write!(f, "Couldn't connect to {:?}", addr),
+            ChatEvent::LinkEstablished { peer, node_id } => write!(f, "Secure Link With {} Established ({})", peer, node_id),
+            ChatEvent::Disconnected { peer } => write!(f, "{} Closed Connection", peer),
+            ChatEvent::Unresponsive { peer } => write!(f, "{} Stopped Answering", peer),
+            ChatEvent::FailedOver { addr } => write!(f, "Failed Over To {}", addr),
+            ChatEvent::Merged { addr } => write!(f, "Found Another Network At {}, Joined It", addr),
+            ChatEvent::Discovered { name: Some(name), addr } => write!(f, "Found {} At {} On The Local Network", name, addr),
+            ChatEvent::Discovered { name: None, addr } => write!(f, "Found A Node At {} On The Local Network", addr),
+            ChatEvent::Stranded => write!(f, "No Backup Parent Answered, Use /connect To Rejoin The Network"),
+            ChatEvent::ChannelCreated { channel } => write!(f, "Channel #{} Was Created", channel),
+            ChatEvent::Joined { channel } => write!(f, "Joined #{}", channel),
+            ChatEvent::Left { channel } => write!(f, "Left #{}", channel),
+            ChatEvent::Switched { channel: Some(channel) } => write!(f, "Switched To #{}", channel),
+            ChatEvent::Switched { channel: None } => write!(f, "Switched To The Lobby"),
+            ChatEvent::Invited { from, channel } => write!(f, "{} Invited You To Private #{}, Use /join {} To Enter", from, channel, channel),
+            ChatEvent::KeyRotated { channel } => write!(f, "Key For #{} Rotated", channel),
+            ChatEvent::Notice(text) | ChatEvent::Warning(text) => write!(f, "{}", text),
+        }
+    }
+}
+
+fn lateness(sent: &std::option::Option<chatlib::Stamp>, late: bool) -> String {
+    match (sent, late) {
+        (Some(sent), true) => format!(" (Arrived Late, Sent {} UTC)", history::utc(sent.time)),
+        (None, true) => String::from(" (Arrived Late)"),
+        (_, false) => String::new(),
+    }
+}
+
+// One entry of ChatNode::channels.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ChannelInfo {
+    pub name: String,
+    pub joined: bool,
+    pub current: bool,
+    //set for private channels
+    pub owner: std::option::Option<String>,
+}
@@ -0,0 +1,177 @@
+extern crate chacha20poly1305;
+extern crate hkdf;
+extern crate x25519_dalek;
+
+use chacha20poly1305::aead::{Aead, KeyInit, Payload};
+
+use crate::chatlib;
+use crate::identity;
+
+const INVITE_INFO: &[u8] = b"prism channel invite";
+const NONCE_LEN: usize = 12;
+
+// The shared key of a private channel. Only the owner hands it out (sealed to
+// each member's identity key) and rotates it when a member leaves.
+#[derive(Clone)]
+pub struct GroupKey {
+    pub key: [u8; 32],
+    pub epoch: u32,
+    pub owner: String,
+    pub members: std::collections::BTreeSet<String>,
+}
+
+impl GroupKey {
+    pub fn create(owner: &str) -> Self {
+        let mut members = std::collections::BTreeSet::new();
+        members.insert(String::from(owner));
+        GroupKey {
+            key: rand::random::<[u8; 32]>(),
+            epoch: 0,
+            owner: String::from(owner),
+            members,
+        }
+    }
+
+    pub fn rotate(&mut self) {
+        self.key = rand::random::<[u8; 32]>();
+        self.epoch += 1;
+    }
+
+    fn aad(channel: &str, epoch: u32) -> Vec<u8> {
+        let mut aad: Vec<u8> = channel.as_bytes().to_vec();
+        aad.extend_from_slice(&epoch.to_be_bytes());
+        aad
+    }
+
+    // Returns (nonce, ciphertext) for a message in this channel.
+    pub fn encrypt(&self, channel: &str, plain: &[u8]) -> (Vec<u8>, Vec<u8>) {
+        let cipher = chacha20poly1305::ChaCha20Poly1305::new((&self.key).into());
+        let nonce: [u8; NONCE_LEN] = rand::random::<[u8; NONCE_LEN]>();
+        let ciphertext: Vec<u8> = cipher.encrypt((&nonce).into(), Payload { msg: plain, aad: &GroupKey::aad(channel, self.epoch) })
+                                        .expect("ChaCha20Poly1305 Encryption Failure");
+        (nonce.to_vec(), ciphertext)
+    }
+
+    pub fn decrypt(&self, channel: &str, epoch: u32, nonce: &[u8], ciphertext: &[u8]) -> std::option::Option<Vec<u8>> {
+        if epoch != self.epoch || nonce.len() != NONCE_LEN {
+            return None;
+        }
+        let cipher = chacha20poly1305::ChaCha20Poly1305::new((&self.key).into());
+        cipher.decrypt(nonce.into(), Payload { msg: ciphertext, aad: &GroupKey::aad(channel, epoch) }).ok()
+    }
+
+    pub fn to_bytes(&self, channel: &str) -> Result<Vec<u8>, chatlib::FrameError> {
+        let members: String = self.members.iter().cloned().collect::<Vec<String>>().join("\n");
+        chatlib::pack_fields(&[channel.as_bytes(), &self.epoch.to_be_bytes(), &self.key, self.owner.as_bytes(), members.as_bytes()])
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> std::option::Option<(String, GroupKey)> {
+        let fields: Vec<&[u8]> = chatlib::unpack_fields(bytes).ok()?;
+        if fields.len() != 5 || fields[1].len() != 4 || fields[2].len() != 32 {
+            return None;
+        }
+
+        let mut key = [0u8; 32];
+        key.copy_from_slice(fields[2]);
+        let members: std::collections::BTreeSet<String> = String::from_utf8_lossy(fields[4]).split('\n')
+                                                                .filter(|name| !name.is_empty())
+                                                                .map(String::from)
+                                                                .collect();
+        let group = GroupKey {
+            key,
+            epoch: u32::from_be_bytes([fields[1][0], fields[1][1], fields[1][2], fields[1][3]]),
+            owner: String::from_utf8_lossy(fields[3]).to_string(),
+            members,
+        };
+        Some((String::from_utf8_lossy(fields[0]).to_string(), group))
+    }
+}
+
+fn invite_cipher(shared: &[u8], ephemeral: &[u8; 32], recipient: &[u8; 32]) -> chacha20poly1305::ChaCha20Poly1305 {
+    let mut info: Vec<u8> = INVITE_INFO.to_vec();
+    info.extend_from_slice(ephemeral);
+    info.extend_from_slice(recipient);
+
+    let mut key = [0u8; 32];
+    hkdf::Hkdf::<sha2::Sha256>::new(None, shared).expand(&info, &mut key).expect("HKDF Expand Failure");
+    chacha20poly1305::ChaCha20Poly1305::new((&key).into())
+}
+
+// Encrypts to one node: ephemeral X25519 against the recipient's identity key,
+// HKDF-SHA256, ChaCha20-Poly1305. The key is fresh per invite so the nonce is zero.
+pub fn seal_to(recipient: &identity::PublicKey, plain: &[u8]) -> std::option::Option<Vec<u8>> {
+    let recipient: [u8; 32] = identity::exchange_key(recipient)?;
+    let secret = x25519_dalek::EphemeralSecret::random_from_rng(rand::rngs::OsRng);
+    let ephemeral: [u8; 32] = x25519_dalek::PublicKey::from(&secret).to_bytes();
+    let shared = secret.diffie_hellman(&x25519_dalek::PublicKey::from(recipient));
+
+    let ciphertext: Vec<u8> = invite_cipher(shared.as_bytes(), &ephemeral, &recipient).encrypt(&[0u8; NONCE_LEN].into(), plain).ok()?;
+    let mut blob: Vec<u8> = ephemeral.to_vec();
+    blob.extend_from_slice(&ciphertext);
+    Some(blob)
+}
+
+pub fn open(me: &identity::Identity, blob: &[u8]) -> std::option::Option<Vec<u8>> {
+    if blob.len() < 32 {
+        return None;
+    }
+    let mut ephemeral = [0u8; 32];
+    ephemeral.copy_from_slice(&blob[..32]);
+    let recipient: [u8; 32] = identity::exchange_key(&me.public_key())?;
+    let secret = x25519_dalek::StaticSecret::from(me.exchange_secret());
+    let shared = secret.diffie_hellman(&x25519_dalek::PublicKey::from(ephemeral));
+
+    invite_cipher(shared.as_bytes(), &ephemeral, &recipient).decrypt(&[0u8; NONCE_LEN].into(), &blob[32..]).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn channel_messages_round_trip() {
+        let group: GroupKey = GroupKey::create("alice");
+        let (nonce, ciphertext) = group.encrypt("secret", b"hello members");
+        assert_eq!(group.decrypt("secret", 0, &nonce, &ciphertext), Some(b"hello members".to_vec()));
+        //the channel name is bound in, so it can't be replayed into another one
+        assert_eq!(group.decrypt("other", 0, &nonce, &ciphertext), None);
+        assert_eq!(group.decrypt("secret", 0, &nonce[1..], &ciphertext), None);
+    }
+
+    #[test]
+    fn messages_from_before_a_rotation_are_refused() {
+        let mut group: GroupKey = GroupKey::create("alice");
+        let (nonce, ciphertext) = group.encrypt("secret", b"before bob left");
+        group.rotate();
+        assert_eq!(group.epoch, 1);
+        assert_eq!(group.decrypt("secret", 0, &nonce, &ciphertext), None);
+        assert_eq!(group.decrypt("secret", 1, &nonce, &ciphertext), None);
+
+        let (nonce, ciphertext) = group.encrypt("secret", b"after bob left");
+        assert_eq!(group.decrypt("secret", 1, &nonce, &ciphertext), Some(b"after bob left".to_vec()));
+    }
+
+    #[test]
+    fn keys_survive_being_packed() {
+        let mut group: GroupKey = GroupKey::create("alice");
+        group.members.insert(String::from("bob"));
+        group.rotate();
+        let (channel, unpacked) = GroupKey::from_bytes(&group.to_bytes("secret").unwrap()).unwrap();
+        assert_eq!(channel, "secret");
+        assert_eq!((unpacked.key, unpacked.epoch, unpacked.owner, unpacked.members), (group.key, 1, String::from("alice"), group.members));
+    }
+
+    #[test]
+    fn sealed_blobs_open_only_for_their_recipient() {
+        let bob: identity::Identity = identity::Identity::generate();
+        let blob: Vec<u8> = seal_to(&bob.public_key(), b"for bob only").unwrap();
+        assert_eq!(open(&bob, &blob), Some(b"for bob only".to_vec()));
+
+        let carol: identity::Identity = identity::Identity::generate();
+        assert_eq!(open(&carol, &blob), None);
+        assert_eq!(open(&bob, &blob[..31]), None);
+        let mut tampered: Vec<u8> = blob.clone();
+        tampered[40] ^= 1;
+        assert_eq!(open(&bob, &tampered), None);
+    }
+}
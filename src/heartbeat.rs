@@ -0,0 +1,68 @@
+// Keepalive for peer links. A link that has been quiet for an interval gets a
+// PING, any bytes from the peer count as an answer, and a link that stays quiet
+// for more than max_missed intervals in a row is treated as disconnected.
+// Times come from the caller so a simulated clock can drive it.
+pub const DEFAULT_INTERVAL_SECS: u64 = 5;
+pub const DEFAULT_MAX_MISSED: u32 = 3;
+
+#[derive(Copy, Clone, Debug)]
+pub struct Heartbeat {
+    pub interval: std::time::Duration,
+    pub max_missed: u32,
+}
+
+impl Heartbeat {
+    pub fn new(interval: std::time::Duration, max_missed: u32) -> Self {
+        Heartbeat {
+            interval,
+            max_missed,
+        }
+    }
+}
+
+impl Default for Heartbeat {
+    fn default() -> Self {
+        Heartbeat::new(std::time::Duration::from_secs(DEFAULT_INTERVAL_SECS), DEFAULT_MAX_MISSED)
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Pulse {
+    Alive,
+    Ping,
+    Dead,
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct Liveness {
+    last_heard: std::time::Instant,
+    missed: u32,
+}
+
+impl Liveness {
+    pub fn new(now: std::time::Instant) -> Self {
+        Liveness {
+            last_heard: now,
+            missed: 0,
+        }
+    }
+
+    pub fn heard(&mut self, now: std::time::Instant) {
+        self.last_heard = now;
+        self.missed = 0;
+    }
+
+    // Called once per heartbeat tick, says whether to leave the link alone,
+    // ping it, or give up on it.
+    pub fn tick(&mut self, now: std::time::Instant, heartbeat: &Heartbeat) -> Pulse {
+        if now.duration_since(self.last_heard) < heartbeat.interval {
+            return Pulse::Alive;
+        }
+
+        self.missed += 1;
+        match self.missed > heartbeat.max_missed {
+            true => Pulse::Dead,
+            false => Pulse::Ping,
+        }
+    }
+}
@@ -0,0 +1,406 @@
+use crate::addrbook;
+use crate::chatlib;
+use crate::identity;
+
+// Every message a node showed or sent, in one append-only log per channel
+// under <data dir>/history: "lobby" for the lobby, "#<channel>" for a channel
+// (or "%<hex>" if its name isn't safe as a file name). One message per line,
+// tab separated:
+//
+//  <unix millis> <message id, hex> <trust: v, u or f> <sender node id, - if unsigned> <sender name> <text> <sender's logical clock, - if unstamped>
+//
+// with backslashes, tabs and newlines in the name and text escaped. Lines
+// written before messages were stamped have no clock field.
+const HISTORY_DIR: &str = "history";
+const LOBBY_FILE: &str = "lobby";
+
+// A log keeps the newest MAX_ENTRIES messages no older than MAX_AGE. It is
+// only cut back once twice that many lines piled up, so appending stays cheap.
+const MAX_ENTRIES: usize = 1000;
+const MAX_AGE: std::time::Duration = std::time::Duration::from_secs(30 * 24 * 60 * 60);
+
+// One message as it was shown, see ChatNode::history.
+#[derive(Clone, Debug, PartialEq)]
+pub struct HistoryEntry {
+    //None for the lobby
+    pub channel: std::option::Option<String>,
+    //unix millis when we showed or sent it
+    pub time: u64,
+    //the sender's logical clock, see chatlib::Stamp
+    pub clock: std::option::Option<u64>,
+    pub id: chatlib::MsgId,
+    pub from: String,
+    pub node_id: std::option::Option<String>,
+    pub trust: identity::Trust,
+    pub text: String,
+}
+
+impl std::fmt::Display for HistoryEntry {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match &self.channel {
+            Some(channel) => write!(f, "[{}] #{} {}{}> {}", utc(self.time), channel, self.trust.tag(), self.from, self.text),
+            None => write!(f, "[{}] {}{}> {}", utc(self.time), self.trust.tag(), self.from, self.text),
+        }
+    }
+}
+
+struct Log {
+    entries: std::collections::VecDeque<HistoryEntry>,
+    //lines in the file, including the ones no longer kept in entries
+    lines: usize,
+}
+
+pub struct History {
+    //None keeps history in memory only
+    dir: std::option::Option<std::path::PathBuf>,
+    //logs read so far, by channel
+    logs: std::collections::HashMap<std::option::Option<String>, Log>,
+}
+
+impl History {
+    pub fn in_memory() -> Self {
+        History {
+            dir: None,
+            logs: std::collections::HashMap::new(),
+        }
+    }
+
+    // Logs are only read once a channel's history is first needed.
+    pub fn open(dir: &std::path::Path) -> Self {
+        History {
+            dir: Some(dir.join(HISTORY_DIR)),
+            logs: std::collections::HashMap::new(),
+        }
+    }
+
+    // Appends a message to its channel's log. A log that can't be written
+    // leaves the whole history in memory from then on.
+    pub fn record(&mut self, entry: HistoryEntry) -> std::io::Result<()> {
+        let channel: std::option::Option<String> = entry.channel.clone();
+        let line: String = to_line(&entry);
+        let loaded: std::io::Result<()> = self.load(&channel);
+
+        let log: &mut Log = self.logs.entry(channel.clone()).or_insert_with(|| Log { entries: std::collections::VecDeque::new(), lines: 0 });
+        log.entries.push_back(entry);
+        if log.entries.len() > MAX_ENTRIES {
+            log.entries.pop_front();
+        }
+        log.lines += 1;
+        let compact: bool = log.lines > 2 * MAX_ENTRIES;
+
+        let path: std::path::PathBuf = match self.path(&channel) {
+            Some(path) => path,
+            None => return loaded,
+        };
+        let written: std::io::Result<()> = match compact {
+            true => self.compact(&channel, &path),
+            false => append(&path, &line),
+        };
+        if written.is_err() {
+            self.dir = None;
+        }
+        loaded.and(written)
+    }
+
+    // The newest count messages of a channel, in the order they were sent as
+    // far as their clocks tell, oldest first.
+    pub fn recent(&mut self, channel: std::option::Option<&str>, count: usize) -> std::io::Result<Vec<HistoryEntry>> {
+        let channel: std::option::Option<String> = channel.map(String::from);
+        let loaded: std::io::Result<()> = self.load(&channel);
+        let cutoff: u64 = now_millis().saturating_sub(MAX_AGE.as_millis() as u64);
+        let entries: Vec<HistoryEntry> = match self.logs.get(&channel) {
+            Some(log) => {
+                let kept: Vec<&HistoryEntry> = log.entries.iter().filter(|entry| entry.time >= cutoff).collect();
+                let mut recent: Vec<HistoryEntry> = kept[kept.len().saturating_sub(count)..].iter().map(|entry| (*entry).clone()).collect();
+                //logical clocks never run behind the wall clock, so unstamped messages sort by when we saw them
+                recent.sort_by_key(|entry| entry.clock.unwrap_or(entry.time));
+                recent
+            },
+            None => Vec::new(),
+        };
+        loaded.map(|_| entries)
+    }
+
+    // Reads a channel's log the first time it is needed.
+    fn load(&mut self, channel: &std::option::Option<String>) -> std::io::Result<()> {
+        if self.logs.contains_key(channel) {
+            return Ok(());
+        }
+        let mut log: Log = Log {
+            entries: std::collections::VecDeque::new(),
+            lines: 0,
+        };
+        //a log we can't read still gets appended to, it just starts out empty
+        let text: std::io::Result<String> = match self.path(channel) {
+            Some(path) => std::fs::read_to_string(path),
+            None => Ok(String::new()),
+        };
+        let result: std::io::Result<()> = match text {
+            Ok(text) => {
+                let cutoff: u64 = now_millis().saturating_sub(MAX_AGE.as_millis() as u64);
+                for line in text.lines() {
+                    log.lines += 1;
+                    //lines that don't parse are skipped, the rest of the log is still good
+                    if let Some(entry) = from_line(channel, line).filter(|entry| entry.time >= cutoff) {
+                        log.entries.push_back(entry);
+                        if log.entries.len() > MAX_ENTRIES {
+                            log.entries.pop_front();
+                        }
+                    }
+                }
+                Ok(())
+            },
+            Err(ref error) if error.kind() == std::io::ErrorKind::NotFound => Ok(()),
+            Err(error) => Err(error),
+        };
+        self.logs.insert(channel.clone(), log);
+        result
+    }
+
+    // Rewrites a log with only the messages still kept.
+    fn compact(&mut self, channel: &std::option::Option<String>, path: &std::path::Path) -> std::io::Result<()> {
+        let log: &mut Log = match self.logs.get_mut(channel) {
+            Some(log) => log,
+            None => return Ok(()),
+        };
+        let cutoff: u64 = now_millis().saturating_sub(MAX_AGE.as_millis() as u64);
+        log.entries.retain(|entry| entry.time >= cutoff);
+        log.lines = log.entries.len();
+        let text: String = log.entries.iter().map(to_line).collect();
+        addrbook::write_atomic(path, &text)
+    }
+
+    fn path(&self, channel: &std::option::Option<String>) -> std::option::Option<std::path::PathBuf> {
+        let dir: &std::path::PathBuf = self.dir.as_ref()?;
+        let file: String = match channel {
+            None => String::from(LOBBY_FILE),
+            Some(channel) if !channel.is_empty() && channel.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-') => format!("#{}", channel),
+            Some(channel) => format!("%{}", hex(channel.as_bytes())),
+        };
+        Some(dir.join(file))
+    }
+}
+
+pub fn now_millis() -> u64 {
+    std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).map(|since| since.as_millis() as u64).unwrap_or(0)
+}
+
+fn append(path: &std::path::Path, line: &str) -> std::io::Result<()> {
+    use std::io::Write;
+
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    let mut file = std::fs::OpenOptions::new().create(true).append(true).open(path)?;
+    file.write_all(line.as_bytes())
+}
+
+fn to_line(entry: &HistoryEntry) -> String {
+    let trust: char = match entry.trust {
+        identity::Trust::Verified => 'v',
+        identity::Trust::Unsigned => 'u',
+        identity::Trust::Forged => 'f',
+    };
+    let clock: String = entry.clock.map(|clock| clock.to_string()).unwrap_or_else(|| String::from("-"));
+    format!("{}\t{}\t{}\t{}\t{}\t{}\t{}\n", entry.time, hex(&entry.id), trust, entry.node_id.as_deref().unwrap_or("-"), escape(&entry.from), escape(&entry.text), clock)
+}
+
+fn from_line(channel: &std::option::Option<String>, line: &str) -> std::option::Option<HistoryEntry> {
+    let fields: Vec<&str> = line.split('\t').collect();
+    if !(fields.len() == 6 || fields.len() == 7) || fields[1].len() != 32 {
+        return None;
+    }
+
+    let mut id: chatlib::MsgId = [0u8; 16];
+    for (index, byte) in id.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(fields[1].get(index * 2..index * 2 + 2)?, 16).ok()?;
+    }
+    Some(HistoryEntry {
+        channel: channel.clone(),
+        time: fields[0].parse().ok()?,
+        clock: match fields.get(6) {
+            None | Some(&"-") => None,
+            Some(clock) => Some(clock.parse().ok()?),
+        },
+        id,
+        trust: match fields[2] {
+            "v" => identity::Trust::Verified,
+            "u" => identity::Trust::Unsigned,
+            "f" => identity::Trust::Forged,
+            _ => return None,
+        },
+        node_id: match fields[3] {
+            "-" => None,
+            node_id => Some(String::from(node_id)),
+        },
+        from: unescape(fields[4]),
+        text: unescape(fields[5]),
+    })
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn escape(text: &str) -> String {
+    text.replace('\\', "\\\\").replace('\t', "\\t").replace('\n', "\\n")
+}
+
+fn unescape(text: &str) -> String {
+    let mut plain: String = String::new();
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => match chars.next() {
+                Some('t') => plain.push('\t'),
+                Some('n') => plain.push('\n'),
+                Some(other) => plain.push(other),
+                None => {},
+            },
+            c => plain.push(c),
+        };
+    }
+    plain
+}
+
+// "YYYY-MM-DD HH:MM" in UTC, from unix millis.
+pub fn utc(millis: u64) -> String {
+    let secs: u64 = millis / 1000;
+    let (hour, minute): (u64, u64) = ((secs / 3600) % 24, (secs / 60) % 60);
+    //days since 1970-01-01 to a civil date, shifted so years start in March
+    let days: u64 = secs / 86400 + 719468;
+    let era: u64 = days / 146097;
+    let day_of_era: u64 = days % 146097;
+    let year_of_era: u64 = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year: u64 = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month: u64 = (5 * day_of_year + 2) / 153;
+    let day: u64 = day_of_year - (153 * shifted_month + 2) / 5 + 1;
+    let month: u64 = if shifted_month < 10 { shifted_month + 3 } else { shifted_month - 9 };
+    let year: u64 = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+    format!("{:04}-{:02}-{:02} {:02}:{:02}", year, month, day, hour, minute)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn scratch(test: &str) -> std::path::PathBuf {
+        let dir: std::path::PathBuf = std::env::temp_dir().join(format!("prism-history-{}-{}", test, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        dir
+    }
+
+    fn entry(channel: std::option::Option<&str>, time: u64, text: &str) -> HistoryEntry {
+        HistoryEntry {
+            channel: channel.map(String::from),
+            time,
+            clock: Some(time),
+            id: chatlib::new_msg_id(),
+            from: String::from("n0"),
+            node_id: Some(String::from("0123456789abcdef")),
+            trust: identity::Trust::Verified,
+            text: String::from(text),
+        }
+    }
+
+    fn lines(history: &History, channel: std::option::Option<&str>) -> usize {
+        let path: std::path::PathBuf = history.path(&channel.map(String::from)).unwrap();
+        std::fs::read_to_string(path).unwrap().lines().count()
+    }
+
+    #[test]
+    fn entries_reload_with_tabs_newlines_and_backslashes() {
+        let dir: std::path::PathBuf = scratch("round-trip");
+        let now: u64 = now_millis();
+        let mut written: Vec<HistoryEntry> = vec![
+            entry(Some("odd name/with slash"), now - 2, "tab\there, newline\nthere, backslash \\ and a stray \\t"),
+            entry(Some("odd name/with slash"), now - 1, "plain"),
+        ];
+        written[0].from = String::from("we\\ird\tname\n");
+        written[1].clock = None;
+        written[1].node_id = None;
+        written[1].trust = identity::Trust::Unsigned;
+        let lobby: HistoryEntry = entry(None, now, "in the lobby");
+
+        let mut history: History = History::open(&dir);
+        for entry in written.iter().chain(std::iter::once(&lobby)) {
+            history.record(entry.clone()).unwrap();
+        }
+        assert_eq!(lines(&history, Some("odd name/with slash")), 2);
+
+        let mut reopened: History = History::open(&dir);
+        assert_eq!(reopened.recent(Some("odd name/with slash"), 10).unwrap(), written);
+        assert_eq!(reopened.recent(None, 10).unwrap(), vec![lobby]);
+        assert!(reopened.recent(Some("elsewhere"), 10).unwrap().is_empty());
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn logs_are_cut_back_to_the_newest_entries() {
+        let dir: std::path::PathBuf = scratch("entries");
+        let now: u64 = now_millis();
+        let mut history: History = History::open(&dir);
+        for index in 0..2 * MAX_ENTRIES {
+            history.record(entry(None, now + index as u64, &index.to_string())).unwrap();
+        }
+        assert_eq!(lines(&history, None), 2 * MAX_ENTRIES);
+
+        history.record(entry(None, now + 2 * MAX_ENTRIES as u64, "last")).unwrap();
+        assert_eq!(lines(&history, None), MAX_ENTRIES);
+
+        let kept: Vec<HistoryEntry> = History::open(&dir).recent(None, 2 * MAX_ENTRIES).unwrap();
+        assert_eq!(kept.len(), MAX_ENTRIES);
+        assert_eq!(kept[0].text, (MAX_ENTRIES + 1).to_string());
+        assert_eq!(kept[MAX_ENTRIES - 1].text, "last");
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn entries_older_than_the_limit_are_dropped() {
+        let dir: std::path::PathBuf = scratch("age");
+        let now: u64 = now_millis();
+        let old: u64 = now - MAX_AGE.as_millis() as u64 - 60_000;
+        let mut history: History = History::open(&dir);
+        history.record(entry(None, old, "too old")).unwrap();
+        history.record(entry(None, now, "recent")).unwrap();
+
+        //skipped when shown and when read back
+        let texts = |entries: Vec<HistoryEntry>| entries.into_iter().map(|entry| entry.text).collect::<Vec<String>>();
+        assert_eq!(texts(history.recent(None, 10).unwrap()), vec!["recent"]);
+        let mut reopened: History = History::open(&dir);
+        assert_eq!(texts(reopened.recent(None, 10).unwrap()), vec!["recent"]);
+
+        //and gone from the file once it's compacted
+        for index in 0..2 * MAX_ENTRIES {
+            reopened.record(entry(None, now + index as u64, "filler")).unwrap();
+        }
+        assert!(lines(&reopened, None) < 2 * MAX_ENTRIES);
+        let text: String = std::fs::read_to_string(reopened.path(&None).unwrap()).unwrap();
+        assert!(!text.contains("too old"));
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn malformed_lines_are_skipped() {
+        let dir: std::path::PathBuf = scratch("malformed");
+        let now: u64 = now_millis();
+        let mut history: History = History::open(&dir);
+        history.record(entry(None, now, "good")).unwrap();
+        append(&history.path(&None).unwrap(), "not\ta\tlog line\n").unwrap();
+        //lines from before messages were stamped have no clock
+        append(&history.path(&None).unwrap(), &format!("{}\t{}\tu\t-\tn1\told\n", now, hex(&[7u8; 16]))).unwrap();
+
+        let entries: Vec<HistoryEntry> = History::open(&dir).recent(None, 10).unwrap();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[1].text, "old");
+        assert_eq!(entries[1].clock, None);
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn utc_dates() {
+        assert_eq!(utc(0), "1970-01-01 00:00");
+        assert_eq!(utc(951_827_696_000), "2000-02-29 12:34");
+        assert_eq!(utc(1_735_689_599_000), "2024-12-31 23:59");
+    }
+}
@@ -0,0 +1,112 @@
+extern crate ed25519_dalek;
+extern crate sha2;
+
+use ed25519_dalek::{Signer, Verifier};
+use sha2::Digest;
+
+
+pub type PublicKey = [u8; 32];
+pub type Signature = [u8; 64];
+
+// How much a received message can be trusted, decided before it is shown.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Trust {
+    Verified,
+    Unsigned,
+    Forged,
+}
+
+impl Trust {
+    pub fn tag(self) -> &'static str {
+        match self {
+            Trust::Verified => "",
+            Trust::Unsigned => "[UNSIGNED] ",
+            Trust::Forged => "[FORGED] ",
+        }
+    }
+}
+
+pub struct Identity {
+    key: ed25519_dalek::SigningKey,
+}
+
+impl Identity {
+    pub fn generate() -> Self {
+        Identity {
+            key: ed25519_dalek::SigningKey::generate(&mut rand::rngs::OsRng),
+        }
+    }
+
+    pub fn from_secret(secret: [u8; 32]) -> Self {
+        Identity {
+            key: ed25519_dalek::SigningKey::from_bytes(&secret),
+        }
+    }
+
+    // Reads the secret key kept in the data directory, creating one on first
+    // run. Each port has its own, so nodes sharing a directory stay apart.
+    pub fn load_or_create(dir: &std::path::Path, port: u16) -> std::io::Result<Self> {
+        let path: std::path::PathBuf = dir.join(format!("identity-{}.key", port));
+        match std::fs::read(&path) {
+            Ok(bytes) => {
+                if bytes.len() != 32 {
+                    return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, format!("{} is not a valid key file", path.display())));
+                }
+                let mut secret = [0u8; 32];
+                secret.copy_from_slice(&bytes);
+                Ok(Identity::from_secret(secret))
+            },
+            Err(ref error) if error.kind() == std::io::ErrorKind::NotFound => {
+                let identity = Identity::generate();
+                std::fs::create_dir_all(dir)?;
+                write_private(&path, &identity.key.to_bytes())?;
+                Ok(identity)
+            },
+            Err(error) => Err(error),
+        }
+    }
+
+    pub fn public_key(&self) -> PublicKey {
+        self.key.verifying_key().to_bytes()
+    }
+
+    pub fn node_id(&self) -> String {
+        node_id(&self.public_key())
+    }
+
+    pub fn sign(&self, msg: &[u8]) -> Signature {
+        self.key.sign(msg).to_bytes()
+    }
+
+    // X25519 secret matching exchange_key(public_key()), used to open channel invites.
+    pub fn exchange_secret(&self) -> [u8; 32] {
+        self.key.to_scalar_bytes()
+    }
+}
+
+// The X25519 form of an identity key, so invites can be sealed to a node
+// knowing only the key it signs with.
+pub fn exchange_key(public_key: &PublicKey) -> std::option::Option<[u8; 32]> {
+    ed25519_dalek::VerifyingKey::from_bytes(public_key).ok().map(|key| key.to_montgomery().to_bytes())
+}
+
+// A node id is the first 8 bytes of the SHA-256 of its public key, in hex.
+pub fn node_id(public_key: &PublicKey) -> String {
+    let digest = sha2::Sha256::digest(public_key);
+    digest[..8].iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+pub fn verify(public_key: &PublicKey, msg: &[u8], signature: &Signature) -> bool {
+    match ed25519_dalek::VerifyingKey::from_bytes(public_key) {
+        Ok(key) => key.verify(msg, &ed25519_dalek::Signature::from_bytes(signature)).is_ok(),
+        Err(_) => false,
+    }
+}
+
+fn write_private(path: &std::path::Path, bytes: &[u8]) -> std::io::Result<()> {
+    use std::io::Write;
+    use std::os::unix::fs::OpenOptionsExt;
+
+    let mut file = std::fs::OpenOptions::new().write(true).create_new(true).mode(0o600).open(path)?;
+    file.write_all(bytes)
+}
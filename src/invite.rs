@@ -0,0 +1,172 @@
+extern crate base64;
+extern crate if_addrs;
+
+use base64::Engine;
+
+use crate::chatlib;
+use crate::groupkey;
+use crate::identity;
+
+// Everything a node needs to join the network through us, handed around as
+// text: "prism:" followed by the URL-safe base64 of these fields (see
+// chatlib::pack_fields):
+//
+//  field  size   contents
+//  0      1      invite version
+//  1      2      listening port
+//  2      32     public key of the inviting node
+//  3      n      channel to join, empty for none
+//  4      n      the channel's group key (see GroupKey::to_bytes), empty unless it is private
+//  5..    4/16   one IPv4 or IPv6 address per field, best first
+const PREFIX: &str = "prism:";
+const VERSION: u8 = 1;
+const MAX_ADDRS: usize = 8;
+
+pub struct Invite {
+    pub addrs: Vec<std::net::IpAddr>,
+    pub port: u16,
+    pub public_key: identity::PublicKey,
+    //a channel to join, with its key when it's private
+    pub channel: std::option::Option<(String, std::option::Option<groupkey::GroupKey>)>,
+}
+
+impl Invite {
+    pub fn to_token(&self) -> Result<String, chatlib::FrameError> {
+        let (channel, group): (&str, Vec<u8>) = match &self.channel {
+            Some((channel, Some(group))) => (channel, group.to_bytes(channel)?),
+            Some((channel, None)) => (channel, Vec::new()),
+            None => ("", Vec::new()),
+        };
+        let addrs: Vec<Vec<u8>> = self.addrs.iter().take(MAX_ADDRS).map(|addr| match addr {
+            std::net::IpAddr::V4(addr) => addr.octets().to_vec(),
+            std::net::IpAddr::V6(addr) => addr.octets().to_vec(),
+        }).collect();
+
+        let port: [u8; 2] = self.port.to_be_bytes();
+        let mut fields: Vec<&[u8]> = vec![&[VERSION], &port, &self.public_key, channel.as_bytes(), &group];
+        fields.extend(addrs.iter().map(|addr| addr.as_slice()));
+        Ok(format!("{}{}", PREFIX, base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(chatlib::pack_fields(&fields)?)))
+    }
+
+    pub fn from_token(token: &str) -> std::option::Option<Self> {
+        let bytes: Vec<u8> = base64::engine::general_purpose::URL_SAFE_NO_PAD.decode(token.trim().strip_prefix(PREFIX)?).ok()?;
+        let fields: Vec<&[u8]> = chatlib::unpack_fields(&bytes).ok()?;
+        if fields.len() < 5 || fields[0] != [VERSION] || fields[1].len() != 2 || fields[2].len() != 32 {
+            return None;
+        }
+
+        let mut public_key: identity::PublicKey = [0u8; 32];
+        public_key.copy_from_slice(fields[2]);
+        let channel: std::option::Option<(String, std::option::Option<groupkey::GroupKey>)> = match (fields[3].is_empty(), fields[4].is_empty()) {
+            (true, _) => None,
+            (false, true) => Some((String::from_utf8_lossy(fields[3]).to_string(), None)),
+            (false, false) => match groupkey::GroupKey::from_bytes(fields[4]) {
+                Some((channel, group)) if channel.as_bytes() == fields[3] => Some((channel, Some(group))),
+                _ => return None,
+            },
+        };
+        let mut addrs: Vec<std::net::IpAddr> = Vec::new();
+        for addr in &fields[5..] {
+            match addr.len() {
+                4 => {
+                    let mut octets = [0u8; 4];
+                    octets.copy_from_slice(addr);
+                    addrs.push(std::net::IpAddr::from(octets));
+                },
+                16 => {
+                    let mut octets = [0u8; 16];
+                    octets.copy_from_slice(addr);
+                    addrs.push(std::net::IpAddr::from(octets));
+                },
+                _ => return None,
+            };
+        }
+
+        Some(Invite {
+            addrs,
+            port: u16::from_be_bytes([fields[1][0], fields[1][1]]),
+            public_key,
+            channel,
+        })
+    }
+}
+
+// The addresses other machines might reach us at: IPv4 before IPv6, loopback
+// last for nodes on the same machine. Link-local IPv6 needs a scope id the
+// token can't carry, so it is left out.
+pub fn local_addrs() -> Vec<std::net::IpAddr> {
+    let mut addrs: Vec<std::net::IpAddr> = if_addrs::get_if_addrs().unwrap_or_default().into_iter()
+                                               .map(|interface| interface.ip())
+                                               .filter(|addr| match addr {
+                                                   std::net::IpAddr::V4(_) => true,
+                                                   std::net::IpAddr::V6(addr) => !addr.is_unicast_link_local(),
+                                               })
+                                               .collect();
+    addrs.sort_by_key(|addr| (addr.is_loopback(), addr.is_ipv6()));
+    addrs.dedup();
+    if addrs.is_empty() {
+        addrs.push(std::net::IpAddr::from([127, 0, 0, 1]));
+    }
+    addrs
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn invite(channel: std::option::Option<(String, std::option::Option<groupkey::GroupKey>)>) -> Invite {
+        Invite {
+            addrs: vec![std::net::IpAddr::from([192, 168, 1, 20]), "2001:db8::7".parse().unwrap(), std::net::IpAddr::from([127, 0, 0, 1])],
+            port: 7000,
+            public_key: identity::Identity::generate().public_key(),
+            channel,
+        }
+    }
+
+    fn encode(bytes: &[u8]) -> String {
+        format!("{}{}", PREFIX, base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(bytes))
+    }
+
+    #[test]
+    fn tokens_round_trip_with_both_address_families() {
+        let sent: Invite = invite(None);
+        let token: String = sent.to_token().unwrap();
+        assert!(token.starts_with("prism:"));
+        let got: Invite = Invite::from_token(&format!("  {}\n", token)).unwrap();
+        assert_eq!(got.addrs, sent.addrs);
+        assert_eq!(got.port, 7000);
+        assert_eq!(got.public_key, sent.public_key);
+        assert!(got.channel.is_none());
+
+        let got: Invite = Invite::from_token(&invite(Some((String::from("dev"), None))).to_token().unwrap()).unwrap();
+        assert!(matches!(got.channel, Some((channel, None)) if channel == "dev"));
+
+        let group: groupkey::GroupKey = groupkey::GroupKey::create("alice");
+        let got: Invite = Invite::from_token(&invite(Some((String::from("secret"), Some(group.clone())))).to_token().unwrap()).unwrap();
+        assert!(matches!(got.channel, Some((channel, Some(key))) if channel == "secret" && key.key == group.key && key.owner == "alice"));
+    }
+
+    #[test]
+    fn malformed_tokens_are_refused() {
+        let token: String = invite(None).to_token().unwrap();
+        let body: &str = token.strip_prefix(PREFIX).unwrap();
+        assert!(Invite::from_token(body).is_none());
+        assert!(Invite::from_token(&format!("chat:{}", body)).is_none());
+        assert!(Invite::from_token("prism:not base64!").is_none());
+
+        let bytes: Vec<u8> = base64::engine::general_purpose::URL_SAFE_NO_PAD.decode(body).unwrap();
+        assert!(Invite::from_token(&encode(&bytes)).is_some());
+        for len in [0, 1, 5, bytes.len() - 1] {
+            assert!(Invite::from_token(&encode(&bytes[..len])).is_none(), "{} bytes were accepted", len);
+        }
+
+        let key: identity::PublicKey = [7u8; 32];
+        let port: [u8; 2] = 7000u16.to_be_bytes();
+        let fields: Vec<&[u8]> = vec![&[VERSION + 1], &port, &key, b"", b"", &[127, 0, 0, 1]];
+        assert!(Invite::from_token(&encode(&chatlib::pack_fields(&fields).unwrap())).is_none());
+        let fields: Vec<&[u8]> = vec![&[VERSION], &port, &key, b"", b"", &[127, 0, 1]];
+        assert!(Invite::from_token(&encode(&chatlib::pack_fields(&fields).unwrap())).is_none());
+        let fields: Vec<&[u8]> = vec![&[VERSION], &port, &key, b"", b"", &[127, 0, 0, 1]];
+        assert!(Invite::from_token(&encode(&chatlib::pack_fields(&fields).unwrap())).is_some());
+    }
+}
@@ -0,0 +1,76 @@
+use crate::chatlib;
+
+// Private messages we keep for recipients that may be offline, until they
+// confirm they got them or hold time runs out. Nothing is kept while hold
+// time is zero.
+const MAX_HELD: usize = 256;
+const MAX_HELD_PER_NODE: usize = 32;
+
+struct Held {
+    node_id: String,
+    id: chatlib::MsgId,
+    //the MAIL frame exactly as it was flooded
+    frame: Vec<u8>,
+    since: std::time::Instant,
+}
+
+#[derive(Default)]
+pub struct Mailbox {
+    hold: std::time::Duration,
+    //oldest first
+    held: std::collections::VecDeque<Held>,
+}
+
+impl Mailbox {
+    pub fn set_hold(&mut self, hold: std::time::Duration) {
+        self.hold = hold;
+        if hold.is_zero() {
+            self.held.clear();
+        }
+    }
+
+    pub fn is_holding(&self) -> bool {
+        !self.hold.is_zero()
+    }
+
+    // Keeps a message for node_id, making room by dropping the oldest one for
+    // the same node, or the oldest of all once the mailbox is full.
+    pub fn hold(&mut self, node_id: &str, id: chatlib::MsgId, frame: &[u8], now: std::time::Instant) {
+        self.expire(now);
+        if !self.is_holding() || self.held.iter().any(|held| held.id == id) {
+            return;
+        }
+
+        if self.held.iter().filter(|held| held.node_id == node_id).count() >= MAX_HELD_PER_NODE {
+            if let Some(oldest) = self.held.iter().position(|held| held.node_id == node_id) {
+                self.held.remove(oldest);
+            }
+        }
+        else if self.held.len() >= MAX_HELD {
+            self.held.pop_front();
+        }
+        self.held.push_back(Held {
+            node_id: String::from(node_id),
+            id,
+            frame: frame.to_vec(),
+            since: now,
+        });
+    }
+
+    // The recipient got the message, so it needn't be kept any longer.
+    pub fn delivered(&mut self, node_id: &str, id: &chatlib::MsgId) {
+        self.held.retain(|held| !(held.node_id == node_id && held.id == *id));
+    }
+
+    // What we still keep for node_id, oldest first. It stays held until the
+    // recipient confirms it, in case it goes away again before it arrives.
+    pub fn held_for(&mut self, node_id: &str, now: std::time::Instant) -> Vec<Vec<u8>> {
+        self.expire(now);
+        self.held.iter().filter(|held| held.node_id == node_id).map(|held| held.frame.clone()).collect()
+    }
+
+    fn expire(&mut self, now: std::time::Instant) {
+        let hold: std::time::Duration = self.hold;
+        self.held.retain(|held| now.saturating_duration_since(held.since) < hold);
+    }
+}
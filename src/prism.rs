@@ -1,50 +1,500 @@
 extern crate console;
+extern crate regex;
 use console::style;
+use std::io::BufRead;
 
-pub fn welcome(port: &str) {
-    println!("\t\t\t\t\t{}\t\t\t\t", style("Welcome to Prism").blue());
-    println!("\t\t\t\t\t\t\t\t\t\t\t");
-    println!("\t{}\t", style("Prism is a multi-chat service provided via a shared network between your peers.").magenta());
-    println!("\t{}\t", style("It allows you to create, join, leave, and change chat channels with ease!").magenta());
-    println!("\t\t\t\t\t\t\t\t\t\t\t");
-    println!("{}", style("\t\t\t\t\t\t\t\t\t\t\t"));
-    chat::help();
-    println!("\t\t\t\t\t\t\t\t\t\t\t");
-    println!("\t\t\t\t\t\t\t\t\t\t\t");
-    println!("\t{}\t\t\t\t\t\t\t", style(format!("You are Hosting Prism At Port {}", port)).yellow());
-    println!("\t{}\t\t", style("**Reminder: Set your name first, this tells everyone who you are!").cyan());
-    println!("\t{}\t\t", style("**Reminder: Share your connectivity information with discretion!").cyan());
-    println!("\t{}\t\t\n\n", style("**Reminder: run $ifconfig for more information of your connection details.").cyan());
+mod tui;
+
+const INPUT_POLL_MS: u64 = 50;
+const HISTORY_LINES: usize = 20;
+
+// Where output goes: printed as it comes, or into the full-screen UI.
+enum Screen {
+    //what's typed on stdin, line by line
+    Lines(std::sync::mpsc::Receiver<String>),
+    Full(tui::Tui),
+}
+
+impl Screen {
+    fn show<D: std::fmt::Display>(&mut self, line: D) {
+        match self {
+            Screen::Lines(_) => println!("{}", line),
+            Screen::Full(tui) => tui.push(&line.to_string()),
+        };
+    }
+
+    fn typed(&mut self) -> Vec<String> {
+        match self {
+            Screen::Lines(input) => input.try_iter().collect(),
+            Screen::Full(tui) => tui.typed(),
+        }
+    }
+
+    fn draw<T: chat::Transport>(&mut self, node: &chat::ChatNode<T>, port: &str) {
+        if let Screen::Full(tui) = self {
+            let channels: Vec<chat::ChannelInfo> = node.channels().into_iter().map(|channel| chat::ChannelInfo {
+                name: clean(&channel.name),
+                owner: channel.owner.as_deref().map(clean),
+                ..channel
+            }).collect();
+            let sidebar: tui::Sidebar = tui::Sidebar {
+                port: String::from(port),
+                name: node.name().map(clean),
+                channels,
+                current: node.current_channel().map(clean),
+                peers: node.peers().iter().map(|peer| clean(peer)).collect(),
+            };
+            //a terminal we can't write to leaves nothing to report the error on
+            let _ = tui.draw(&sidebar);
+        }
+    }
+}
+
+const COMMANDS: [(&str, &str); 15] = [
+    ("/help", "show these commands"),
+    ("/name <NAME>", "tell everyone who you are"),
+    ("/connect <CONNECT-IP> <CONNECT-PORTNO>", "join the network through a peer"),
+    ("/create <CHANNEL>", "start a channel"),
+    ("/join <CHANNEL>", "join a channel"),
+    ("/leave [CHANNEL]", "leave a channel, the one you are in by default"),
+    ("/switch [CHANNEL]", "talk in a channel, or in the lobby"),
+    ("/channels", "list the channels you know of"),
+    ("/msg <NAME> <MESSAGE>", "message one peer privately"),
+    ("/create-private <CHANNEL>", "start an end-to-end encrypted channel"),
+    ("/invite [[NAME] <CHANNEL>]", "make an invite token, or invite a member"),
+    ("/join-invite <TOKEN>", "join through an invite token"),
+    ("/discover [N]", "list nodes on the local network, or connect to one"),
+    ("/history [N]", "replay the last N messages said here"),
+    ("/exit", "quit"),
+];
+
+const OPTIONS: [(&str, &str); 13] = [
+    ("--plaintext", "disable link encryption (debugging only, every peer must use it)"),
+    ("--heartbeat=<SECS>", "ping quiet peers this often (default 5)"),
+    ("--heartbeat-misses=<N>", "drop a peer after this many unanswered pings (default 3)"),
+    ("--max-children=<N>", "send newcomers further down once this many children are connected (default 3)"),
+    ("--rebalance=<STRATEGY>", "round-robin, least-loaded (default) or shallowest"),
+    ("--connect-timeout=<SECS>", "give up on a peer that doesn't answer within this long (default 5)"),
+    ("--probe-interval=<SECS>", "look for a split-off part of the network this often (default 30)"),
+    ("--sync=<N>", "ask each new neighbor for up to N messages of the last hour (default 50, 0 to turn off)"),
+    ("--hold=<MINUTES>", "keep private messages for offline peers this long and pass them on when they're back (default 0, off)"),
+    ("--discover", "announce this node on the local network and list the others with /discover"),
+    ("--unix=<DIR>", "use unix sockets in DIR instead of TCP, for nodes on one machine"),
+    ("--tui", "full-screen mode with a channel sidebar, peer list and an input line of its own"),
+    ("--help", "show this"),
+];
+
+// Takes the control characters out of text a peer sent us, names and
+// channels included, so an escape sequence in it can't move the cursor,
+// recolor the screen or retitle the terminal, and a line break can't pass
+// off the rest as a line of its own. Only our own text gets styled.
+fn clean(text: &str) -> String {
+    text.chars().filter(|c| !c.is_control()).collect()
+}
+
+// Notices and warnings are ours and may run over lines, but can quote a peer's name.
+fn clean_lines(text: &str) -> String {
+    text.split('\n').map(clean).collect::<Vec<String>>().join("\n")
+}
+
+fn clean_event(event: chat::ChatEvent) -> chat::ChatEvent {
+    match event {
+        chat::ChatEvent::Message { from, text, trust, sent, late } => chat::ChatEvent::Message { from: clean(&from), text: clean(&text), trust, sent, late },
+        chat::ChatEvent::ChannelMessage { channel, from, text, trust, sent, late } => {
+            chat::ChatEvent::ChannelMessage { channel: clean(&channel), from: clean(&from), text: clean(&text), trust, sent, late }
+        },
+        chat::ChatEvent::DirectMessage { from, text, trust, sent, late } => chat::ChatEvent::DirectMessage { from: clean(&from), text: clean(&text), trust, sent, late },
+        chat::ChatEvent::PeerJoined { name, node_id, trust } => chat::ChatEvent::PeerJoined { name: clean(&name), node_id, trust },
+        chat::ChatEvent::LinkEstablished { peer, node_id } => chat::ChatEvent::LinkEstablished { peer: clean(&peer), node_id },
+        chat::ChatEvent::Disconnected { peer } => chat::ChatEvent::Disconnected { peer: clean(&peer) },
+        chat::ChatEvent::Unresponsive { peer } => chat::ChatEvent::Unresponsive { peer: clean(&peer) },
+        chat::ChatEvent::Discovered { name, addr } => chat::ChatEvent::Discovered { name: name.as_deref().map(clean), addr },
+        chat::ChatEvent::ChannelCreated { channel } => chat::ChatEvent::ChannelCreated { channel: clean(&channel) },
+        chat::ChatEvent::Joined { channel } => chat::ChatEvent::Joined { channel: clean(&channel) },
+        chat::ChatEvent::Left { channel } => chat::ChatEvent::Left { channel: clean(&channel) },
+        chat::ChatEvent::Switched { channel } => chat::ChatEvent::Switched { channel: channel.as_deref().map(clean) },
+        chat::ChatEvent::Invited { from, channel } => chat::ChatEvent::Invited { from: clean(&from), channel: clean(&channel) },
+        chat::ChatEvent::KeyRotated { channel } => chat::ChatEvent::KeyRotated { channel: clean(&channel) },
+        chat::ChatEvent::Notice(text) => chat::ChatEvent::Notice(clean_lines(&text)),
+        chat::ChatEvent::Warning(text) => chat::ChatEvent::Warning(clean_lines(&text)),
+        event => event,
+    }
+}
+
+// Two lined up columns, whatever the widths.
+fn table(entries: &[(&str, &str)]) -> Vec<String> {
+    let width: usize = entries.iter().map(|(entry, _)| entry.len()).max().unwrap_or(0);
+    entries.iter().map(|(entry, about)| format!("  {}  {}", style(format!("{:<width$}", entry, width = width)).green(), about)).collect()
+}
+
+fn welcome(screen: &mut Screen, port: &str) {
+    screen.show(style("Welcome to Prism").blue().bold());
+    screen.show(style("Prism is a multi-chat service provided via a shared network between your peers.").magenta());
+    screen.show(style("It allows you to create, join, leave, and change chat channels with ease!").magenta());
+    screen.show("");
+    help(screen);
+    screen.show("");
+    screen.show(style(format!("You are Hosting Prism At Port {}", port)).yellow());
+    screen.show(style("**Reminder: Set your name first, this tells everyone who you are!").cyan());
+    screen.show(style("**Reminder: Share your connectivity information with discretion!").cyan());
+    screen.show(style("**Reminder: run $ifconfig for more information of your connection details.").cyan());
+    screen.show("");
+}
+
+fn help(screen: &mut Screen) {
+    screen.show(style("COMMANDS").cyan());
+    for row in table(&COMMANDS) {
+        screen.show(row);
+    }
+}
+
+fn usage() {
+    println!("Usage: ./prism [OPTIONS] <HOST-PORT>");
+    println!("Usage: ./prism [OPTIONS] <HOST-PORT> <CONNECT-IP> <CONNECT-PORTNO>");
+    println!("Usage: ./prism [OPTIONS] <HOST-PORT> <INVITE-TOKEN>");
+    for row in table(&OPTIONS) {
+        println!("{}", row);
+    }
+}
+
+// Value of a --name=value flag, exits with usage if it doesn't parse.
+fn flag_value<T: std::str::FromStr>(name: &str) -> std::option::Option<T> {
+    let prefix: String = format!("--{}=", name);
+    let arg: String = std::env::args().find(|arg| arg.starts_with(&prefix))?;
+    match arg[prefix.len()..].trim().parse() {
+        Ok(value) => Some(value),
+        Err(_) => {
+            println!("Invalid Value For --{}", name);
+            usage();
+            std::process::exit(0);
+        },
+    }
+}
+
+fn parse_channel(arg: &str) -> std::option::Option<String> {
+    let re = regex::Regex::new(r"^#?(?P<channel>[A-Za-z0-9_\-]{1,32})$").unwrap();
+    re.captures(arg.trim()).map(|c| c.name("channel").unwrap().as_str().to_string())
+}
+
+fn list_channels<T: chat::Transport>(node: &chat::ChatNode<T>, screen: &mut Screen) {
+    let channels: Vec<chat::ChannelInfo> = node.channels();
+    if channels.is_empty() {
+        screen.show("No Channels Yet, Use /create <CHANNEL> To Start One");
+        return;
+    }
+
+    for channel in channels {
+        let marker: &str = if channel.current { "*" }
+                           else if channel.joined { "+" }
+                           else { " " };
+        match channel.owner {
+            Some(owner) => screen.show(format!("{} #{} (private, owner {})", marker, clean(&channel.name), clean(&owner))),
+            None => screen.show(format!("{} #{}", marker, clean(&channel.name))),
+        };
+    }
 }
 
+fn print_invite(screen: &mut Screen, token: std::option::Option<String>) {
+    if let Some(token) = token {
+        screen.show("Share This Token, Others Join With /join-invite <TOKEN> Or ./prism <HOST-PORT> <TOKEN>:");
+        screen.show(token);
+    }
+}
 
-pub fn usage() {
-    println!("Usage: ./prism <HOST-PORT>");
-    println!("Usage: ./prism <HOST-PORT> <CONNECT-IP> <CONNECT-PORTNO>");
+// Replays the newest messages of the channel we're in, or of the lobby.
+fn show_history<T: chat::Transport>(node: &mut chat::ChatNode<T>, screen: &mut Screen, count: usize) {
+    let channel: std::option::Option<String> = node.current_channel().map(String::from);
+    let entries: Vec<chat::HistoryEntry> = node.history(channel.as_deref(), count);
+    if entries.is_empty() {
+        screen.show("Nothing Said Here Yet");
+    }
+    for entry in entries {
+        screen.show(style(clean(&entry.to_string())).dim());
+    }
+}
+
+// Lists the nodes heard on the local network, or connects to the Nth of them.
+fn discover<T: chat::Transport>(node: &mut chat::ChatNode<T>, screen: &mut Screen, arg: &str) {
+    let neighbors: Vec<chat::Neighbor> = node.neighbors();
+    if arg.is_empty() {
+        if neighbors.is_empty() {
+            screen.show("No Nodes Found On The Local Network Yet (Discovery Needs --discover)");
+        }
+        for (index, neighbor) in neighbors.iter().enumerate() {
+            match &neighbor.name {
+                Some(name) => screen.show(format!("{}. {} At {}", index + 1, clean(name), neighbor.addr)),
+                None => screen.show(format!("{}. Node {} At {}", index + 1, neighbor.node_id, neighbor.addr)),
+            };
+        }
+        return;
+    }
+
+    match arg.parse::<usize>().ok().and_then(|number| neighbors.get(number.wrapping_sub(1))) {
+        Some(neighbor) => connect(node, screen, neighbor.addr),
+        None => screen.show("Please enter in the correct format!\n/discover [N], N from the /discover list"),
+    };
+}
+
+//a refused connection also comes back from poll as ConnectFailed, so only print the rest
+fn connect<T: chat::Transport>(node: &mut chat::ChatNode<T>, screen: &mut Screen, addr: std::net::SocketAddr) {
+    match node.connect(addr) {
+        Ok(_) | Err(chat::ChatError::Connect { .. }) => {},
+        Err(error) => screen.show(error),
+    };
+}
+
+// Turns a line typed by the user into calls on the node, false once they asked to quit.
+fn handle_input<T: chat::Transport>(node: &mut chat::ChatNode<T>, screen: &mut Screen, msg: &str) -> bool {
+    let re = regex::Regex::new(r"^/(?P<cmd>[^\s\t\r\n]+)(?x)(?P<arg>[^\r\n]*)").unwrap();
+    let cap = re.captures(msg);
+    match cap {
+        None => node.send(msg),
+        Some(c) => {
+            let arg: &str = c.name("arg").unwrap().as_str().trim();
+            match c.name("cmd").unwrap().as_str() {
+                "name" => {
+                    match arg.len() {
+                        0 => screen.show("Enter Valid Name!"),
+                        _ => node.set_name(arg),
+                    };
+                },
+                "exit" => {
+                    return false;
+                },
+                "connect" => {
+                    let con_re = regex::Regex::new(r"(?P<ip>[^\s\t\r\n]+)(?:[\s\t\r\n]*)(?P<port>[^\r\n]+)").unwrap();
+                    let addr = con_re.captures(arg).and_then(|c2| {
+                        let ip = c2.name("ip").unwrap().as_str().parse::<std::net::IpAddr>().ok()?;
+                        let port = c2.name("port").unwrap().as_str().trim().parse::<u16>().ok()?;
+                        Some(std::net::SocketAddr::new(ip, port))
+                    });
+                    match addr {
+                        Some(addr) => {
+                            connect(node, screen, addr);
+                        },
+                        None => screen.show("Please enter in the correct format!"),
+                    };
+                },
+                "create" => {
+                    match parse_channel(arg) {
+                        Some(channel) => node.create_channel(&channel),
+                        None => screen.show("Enter Valid Channel Name!"),
+                    };
+                },
+                "create-private" => {
+                    match parse_channel(arg) {
+                        Some(channel) => node.create_private_channel(&channel),
+                        None => screen.show("Enter Valid Channel Name!"),
+                    };
+                },
+                "invite" => {
+                    let invite_re = regex::Regex::new(r"^(?:(?P<to>[^\s]+)\s+)?(?P<channel>[^\s]+)$").unwrap();
+                    match (arg.len(), invite_re.captures(arg)) {
+                        (0, _) => print_invite(screen, node.invite(None)),
+                        (_, Some(c2)) => {
+                            match (c2.name("to"), parse_channel(c2.name("channel").unwrap().as_str())) {
+                                (Some(to), Some(channel)) => node.invite_member(to.as_str(), &channel),
+                                (None, Some(channel)) => print_invite(screen, node.invite(Some(&channel))),
+                                (_, None) => screen.show("Enter Valid Channel Name!"),
+                            };
+                        },
+                        (_, None) => screen.show("Please enter in the correct format!\n/invite [[NAME] <CHANNEL>]"),
+                    };
+                },
+                "join-invite" => {
+                    match arg.len() {
+                        0 => screen.show("Please enter in the correct format!\n/join-invite <TOKEN>"),
+                        _ => node.join_invite(arg),
+                    };
+                },
+                "join" => {
+                    match parse_channel(arg) {
+                        Some(channel) => node.join_channel(&channel),
+                        None => screen.show("Enter Valid Channel Name!"),
+                    };
+                },
+                "leave" => {
+                    match (arg.len(), node.current_channel().map(String::from)) {
+                        (0, Some(channel)) => node.leave_channel(&channel),
+                        (0, None) => screen.show("You Are In The Lobby, Nothing To Leave!"),
+                        _ => {
+                            match parse_channel(arg) {
+                                Some(channel) => node.leave_channel(&channel),
+                                None => screen.show("Enter Valid Channel Name!"),
+                            };
+                        },
+                    };
+                },
+                "switch" => {
+                    match arg.len() {
+                        0 => node.switch_channel(None),
+                        _ => {
+                            match parse_channel(arg) {
+                                Some(channel) => node.switch_channel(Some(channel)),
+                                None => screen.show("Enter Valid Channel Name!"),
+                            };
+                        },
+                    };
+                },
+                "channels" => {
+                    list_channels(node, screen);
+                },
+                "discover" => {
+                    discover(node, screen, arg);
+                },
+                "history" => {
+                    match (arg.len(), arg.parse::<usize>()) {
+                        (0, _) => show_history(node, screen, HISTORY_LINES),
+                        (_, Ok(count)) => show_history(node, screen, count),
+                        (_, Err(_)) => screen.show("Please enter in the correct format!\n/history [N]"),
+                    };
+                },
+                "msg" => {
+                    let msg_re = regex::Regex::new(r"^(?P<to>[^\s]+)\s+(?P<text>.+)$").unwrap();
+                    match msg_re.captures(arg) {
+                        Some(c2) => node.send_direct(c2.name("to").unwrap().as_str(), c2.name("text").unwrap().as_str()),
+                        None => screen.show("Please enter in the correct format!\n/msg <NAME> <MESSAGE>"),
+                    };
+                },
+                "help" => {
+                    help(screen);
+                },
+                _ => {/*  Ignore cmd */ },
+            }
+        },
+    };
+    true
+}
+
+// Reads stdin on its own thread so the node never blocks on the terminal.
+fn spawn_input() -> std::sync::mpsc::Receiver<String> {
+    let (tx, rx) = std::sync::mpsc::channel::<String>();
+    std::thread::spawn(move || {
+        for line in std::io::stdin().lock().lines() {
+            match line {
+                Ok(line) => {
+                    if tx.send(line).is_err() {
+                        break;
+                    }
+                },
+                Err(_) => break,
+            };
+        }
+    });
+    rx
 }
 
 fn main() {
-    let argv: Vec<String> = std::env::args().collect();
-    if argv.len() < 2 {
+    let plaintext: bool = std::env::args().any(|arg| arg == "--plaintext");
+    let argv: Vec<String> = std::env::args().filter(|arg| !arg.starts_with("--")).collect();
+    if argv.len() < 2 || std::env::args().any(|arg| arg == "--help") {
         usage();
         std::process::exit(0);
     }
-    welcome(&argv[1]);
-    
-    let port: u16 = argv[1].trim().parse().unwrap();
-    let mut node: chat::ChatNode = chat::ChatNode::new(std::net::SocketAddr::from(([0, 0, 0, 0], port)), port);
-    if argv.len() >= 4 {
-        let upstream: String = String::from(&argv[2]) + ":" + &argv[3];
-        match std::net::TcpStream::connect(&upstream) {
-            Ok(connection) => {
-                println!("Connected to {:?}", upstream);
-                node.up_stream = Some(connection).take();
-                node.up_stream_port = argv[3].trim().parse().unwrap();
+
+    let port: u16 = match argv[1].trim().parse() {
+        Ok(port) => port,
+        Err(_) => {
+            usage();
+            std::process::exit(0);
+        },
+    };
+    let addr: std::net::SocketAddr = std::net::SocketAddr::from(([0, 0, 0, 0], port));
+    match flag_value::<String>("unix") {
+        Some(dir) => run(chat::ChatNode::with_transport(chat::UnixTransport::new(dir), addr, port, &chat::data_dir()), &argv, plaintext),
+        None => run(chat::ChatNode::new(addr, port), &argv, plaintext),
+    };
+}
+
+fn run<T: chat::Transport>(node: Result<chat::ChatNode<T>, chat::ChatError>, argv: &[String], plaintext: bool) {
+    let port: &str = &argv[1];
+    let mut node: chat::ChatNode<T> = match node {
+        Ok(node) => node,
+        Err(error) => {
+            println!("Couldn't Start Prism On Port {}: {}", port, error);
+            std::process::exit(-1);
+        },
+    };
+    if plaintext {
+        node.set_plaintext(true);
+    }
+    let interval: u64 = flag_value("heartbeat").unwrap_or(5);
+    let misses: u32 = flag_value("heartbeat-misses").unwrap_or(3);
+    if interval == 0 {
+        println!("--heartbeat Must Be At Least 1 Second");
+        std::process::exit(0);
+    }
+    node.set_heartbeat(std::time::Duration::from_secs(interval), misses);
+    if let Some(max_children) = flag_value::<usize>("max-children") {
+        node.set_max_children(max_children);
+    }
+    if let Some(rebalance) = flag_value::<chat::Rebalance>("rebalance") {
+        node.set_rebalance(rebalance);
+    }
+    if let Some(timeout) = flag_value::<u64>("connect-timeout") {
+        node.set_connect_timeout(std::time::Duration::from_secs(timeout.max(1)));
+    }
+    if let Some(interval) = flag_value::<u64>("probe-interval") {
+        node.set_probe_interval(std::time::Duration::from_secs(interval.max(1)));
+    }
+    if let Some(messages) = flag_value::<usize>("sync") {
+        node.set_sync(messages, std::time::Duration::from_secs(60 * 60));
+    }
+    if let Some(minutes) = flag_value::<u64>("hold") {
+        node.set_hold(std::time::Duration::from_secs(minutes * 60));
+    }
+    let discovery: Result<(), chat::ChatError> = match std::env::args().any(|arg| arg == "--discover") {
+        true => node.set_discovery(true),
+        false => Ok(()),
+    };
+
+    //every flag is read by now, nothing exits from here on with the terminal taken over
+    let full: bool = std::env::args().any(|arg| arg == "--tui");
+    let mut screen: Screen = match (full, console::Term::stdout().is_term()) {
+        (true, true) => match tui::Tui::open() {
+            Ok(tui) => Screen::Full(tui),
+            Err(error) => {
+                println!("Couldn't Start The Full-Screen Mode: {}", error);
+                Screen::Lines(spawn_input())
             },
-            Err(_) => {
-                println!("Couldn't connect to {:?}", upstream);  
+        },
+        (true, false) => {
+            println!("--tui Needs A Terminal, Printing Lines Instead");
+            Screen::Lines(spawn_input())
+        },
+        (false, _) => Screen::Lines(spawn_input()),
+    };
+    welcome(&mut screen, port);
+    if plaintext {
+        screen.show(style("**Warning: link encryption is disabled (--plaintext)").red());
+    }
+    if let Err(error) = discovery {
+        screen.show(format!("Couldn't Start Local Discovery: {}", error));
+    }
+
+    if argv.len() == 3 {
+        node.join_invite(&argv[2]);
+    } else if argv.len() >= 4 {
+        let upstream: String = String::from(&argv[2]) + ":" + &argv[3];
+        match std::net::ToSocketAddrs::to_socket_addrs(&upstream).ok().and_then(|mut addrs| addrs.next()) {
+            Some(addr) => {
+                connect(&mut node, &mut screen, addr);
             },
+            None => screen.show(format!("Couldn't connect to {:?}", upstream)),
         };
+    } else {
+        node.bootstrap();
+    }
+
+    loop {
+        for event in node.poll(Some(std::time::Duration::from_millis(INPUT_POLL_MS))) {
+            screen.show(clean_event(event));
+        }
+        for line in screen.typed() {
+            if !handle_input(&mut node, &mut screen, line.trim()) {
+                return;
+            }
+        }
+        screen.draw(&node, port);
     }
-    node.start_routine();
 }
@@ -0,0 +1,293 @@
+extern crate snow;
+
+use crate::identity;
+
+// Every link runs a Noise XX handshake before any ChatHeader frame is exchanged.
+// The static Noise key is generated per process; in the second and third
+// handshake messages each side sends its ed25519 identity key and a signature
+// over its Noise static key, so the link is bound to the peer's node id.
+//
+// On the wire every Noise message is prefixed with its length as a u16.
+const NOISE_PARAMS: &str = "Noise_XX_25519_ChaChaPoly_BLAKE2s";
+const MAX_NOISE_MSG: usize = 65535;
+const TAG_LEN: usize = 16;
+const MAX_CHUNK: usize = MAX_NOISE_MSG - TAG_LEN;
+const AUTH_LEN: usize = 32 + 64;
+const STATIC_CONTEXT: &[u8] = b"prism noise static key";
+
+#[derive(Debug)]
+pub enum LinkError {
+    Noise(snow::Error),
+    BadIdentity,
+}
+
+impl std::fmt::Display for LinkError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            LinkError::Noise(error) => write!(f, "noise error: {}", error),
+            LinkError::BadIdentity => write!(f, "peer did not prove its identity"),
+        }
+    }
+}
+
+impl From<snow::Error> for LinkError {
+    fn from(error: snow::Error) -> Self {
+        LinkError::Noise(error)
+    }
+}
+
+// This node's Noise static key and the proof that it belongs to our identity.
+#[derive(Clone)]
+pub struct Keys {
+    private: Vec<u8>,
+    auth: Vec<u8>,
+}
+
+impl Keys {
+    pub fn new(identity: &identity::Identity) -> Self {
+        let keypair = snow::Builder::new(NOISE_PARAMS.parse().unwrap()).generate_keypair().unwrap();
+        let mut auth: Vec<u8> = identity.public_key().to_vec();
+        auth.extend_from_slice(&identity.sign(&static_proof(&keypair.public)));
+        Keys {
+            private: keypair.private,
+            auth,
+        }
+    }
+}
+
+fn static_proof(static_key: &[u8]) -> Vec<u8> {
+    let mut buf: Vec<u8> = STATIC_CONTEXT.to_vec();
+    buf.extend_from_slice(static_key);
+    buf
+}
+
+enum State {
+    Plain,
+    Handshake(Box<snow::HandshakeState>),
+    Transport(Box<snow::TransportState>),
+}
+
+pub struct SecureLink {
+    state: State,
+    auth: Vec<u8>,
+    incoming: Vec<u8>,
+    pending: Vec<u8>,
+    remote: std::option::Option<identity::PublicKey>,
+}
+
+impl SecureLink {
+    // Passes bytes through untouched, only for debugging with --plaintext.
+    pub fn plain() -> Self {
+        SecureLink {
+            state: State::Plain,
+            auth: Vec::new(),
+            incoming: Vec::new(),
+            pending: Vec::new(),
+            remote: None,
+        }
+    }
+
+    // The connecting side, returns the first handshake message to send.
+    pub fn initiator(keys: &Keys) -> Result<(Self, Vec<u8>), LinkError> {
+        let mut hs = snow::Builder::new(NOISE_PARAMS.parse().unwrap()).local_private_key(&keys.private).build_initiator()?;
+        let mut msg = vec![0u8; MAX_NOISE_MSG];
+        let len: usize = hs.write_message(&[], &mut msg)?;
+
+        let link = SecureLink {
+            state: State::Handshake(Box::new(hs)),
+            auth: keys.auth.clone(),
+            incoming: Vec::new(),
+            pending: Vec::new(),
+            remote: None,
+        };
+        Ok((link, frame(&msg[..len])))
+    }
+
+    pub fn responder(keys: &Keys) -> Result<Self, LinkError> {
+        let hs = snow::Builder::new(NOISE_PARAMS.parse().unwrap()).local_private_key(&keys.private).build_responder()?;
+        Ok(SecureLink {
+            state: State::Handshake(Box::new(hs)),
+            auth: keys.auth.clone(),
+            incoming: Vec::new(),
+            pending: Vec::new(),
+            remote: None,
+        })
+    }
+
+    pub fn is_established(&self) -> bool {
+        matches!(self.state, State::Transport(_))
+    }
+
+    pub fn remote_identity(&self) -> std::option::Option<identity::PublicKey> {
+        self.remote
+    }
+
+    // Returns the bytes to put on the wire. Anything written before the
+    // handshake completes is held and flushed by read() once it does.
+    pub fn write(&mut self, plain: &[u8]) -> Result<Vec<u8>, LinkError> {
+        match &mut self.state {
+            State::Plain => Ok(plain.to_vec()),
+            State::Handshake(_) => {
+                self.pending.extend_from_slice(plain);
+                Ok(Vec::new())
+            },
+            State::Transport(ts) => encrypt(ts, plain),
+        }
+    }
+
+    // Takes raw bytes from the socket, returns the decrypted plaintext and
+    // any bytes that have to be sent back (handshake replies, held data).
+    pub fn read(&mut self, bytes: &[u8]) -> Result<(Vec<u8>, Vec<u8>), LinkError> {
+        if let State::Plain = self.state {
+            return Ok((bytes.to_vec(), Vec::new()));
+        }
+
+        self.incoming.extend_from_slice(bytes);
+        let mut plain: Vec<u8> = Vec::new();
+        let mut reply: Vec<u8> = Vec::new();
+        while let Some(b) = self.incoming.get(..2) {
+            let len: usize = u16::from_be_bytes([b[0], b[1]]) as usize;
+            if self.incoming.len() < 2 + len {
+                break;
+            }
+            let msg: Vec<u8> = self.incoming.drain(..2 + len).skip(2).collect();
+
+            let mut out = vec![0u8; MAX_NOISE_MSG];
+            let mut finished: bool = false;
+            match &mut self.state {
+                State::Transport(ts) => {
+                    let count: usize = ts.read_message(&msg, &mut out)?;
+                    plain.extend_from_slice(&out[..count]);
+                },
+                State::Handshake(hs) => {
+                    let count: usize = hs.read_message(&msg, &mut out)?;
+                    if count > 0 {
+                        self.remote = Some(check_auth(&out[..count], hs.get_remote_static())?);
+                    }
+
+                    if !hs.is_handshake_finished() && hs.is_my_turn() {
+                        let len: usize = hs.write_message(&self.auth, &mut out)?;
+                        reply.extend_from_slice(&frame(&out[..len]));
+                    }
+                    finished = hs.is_handshake_finished();
+                },
+                State::Plain => unreachable!("plain links never buffer"),
+            };
+
+            if finished {
+                self.finish_handshake()?;
+                let pending: Vec<u8> = std::mem::take(&mut self.pending);
+                reply.extend_from_slice(&self.write(&pending)?);
+            }
+        }
+
+        Ok((plain, reply))
+    }
+
+    fn finish_handshake(&mut self) -> Result<(), LinkError> {
+        if self.remote.is_none() {
+            return Err(LinkError::BadIdentity);
+        }
+        if let State::Handshake(hs) = std::mem::replace(&mut self.state, State::Plain) {
+            self.state = State::Transport(Box::new(hs.into_transport_mode()?));
+        }
+        Ok(())
+    }
+}
+
+fn frame(msg: &[u8]) -> Vec<u8> {
+    let mut buf: Vec<u8> = (msg.len() as u16).to_be_bytes().to_vec();
+    buf.extend_from_slice(msg);
+    buf
+}
+
+fn encrypt(ts: &mut snow::TransportState, plain: &[u8]) -> Result<Vec<u8>, LinkError> {
+    let mut wire: Vec<u8> = Vec::new();
+    let mut out = vec![0u8; MAX_NOISE_MSG];
+    for chunk in plain.chunks(MAX_CHUNK) {
+        let len: usize = ts.write_message(chunk, &mut out)?;
+        wire.extend_from_slice(&frame(&out[..len]));
+    }
+    Ok(wire)
+}
+
+fn check_auth(payload: &[u8], remote_static: std::option::Option<&[u8]>) -> Result<identity::PublicKey, LinkError> {
+    let remote_static: &[u8] = remote_static.ok_or(LinkError::BadIdentity)?;
+    if payload.len() != AUTH_LEN {
+        return Err(LinkError::BadIdentity);
+    }
+
+    let mut public_key: identity::PublicKey = [0u8; 32];
+    let mut signature: identity::Signature = [0u8; 64];
+    public_key.copy_from_slice(&payload[..32]);
+    signature.copy_from_slice(&payload[32..]);
+    if !identity::verify(&public_key, &static_proof(remote_static), &signature) {
+        return Err(LinkError::BadIdentity);
+    }
+    Ok(public_key)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Runs the three handshake messages between an initiator and a responder.
+    fn handshake(initiator: &Keys, responder: &Keys) -> Result<(SecureLink, SecureLink), LinkError> {
+        let (mut a, hello) = SecureLink::initiator(initiator)?;
+        let mut b: SecureLink = SecureLink::responder(responder)?;
+        let (_, reply) = b.read(&hello)?;
+        let (_, last) = a.read(&reply)?;
+        let (early, _) = b.read(&last)?;
+        assert!(early.is_empty());
+        Ok((a, b))
+    }
+
+    #[test]
+    fn handshake_binds_each_side_to_the_other_identity() {
+        let (alice, bob) = (identity::Identity::generate(), identity::Identity::generate());
+        let (mut a, mut b) = handshake(&Keys::new(&alice), &Keys::new(&bob)).unwrap();
+        assert!(a.is_established() && b.is_established());
+        assert_eq!(a.remote_identity(), Some(bob.public_key()));
+        assert_eq!(b.remote_identity(), Some(alice.public_key()));
+
+        let wire: Vec<u8> = a.write(b"hello bob").unwrap();
+        assert_eq!(b.read(&wire).unwrap(), (b"hello bob".to_vec(), Vec::new()));
+        let wire: Vec<u8> = b.write(b"hello alice").unwrap();
+        assert_eq!(a.read(&wire).unwrap().0, b"hello alice");
+    }
+
+    #[test]
+    fn writes_before_the_handshake_go_out_once_it_is_done() {
+        let (mut a, hello) = SecureLink::initiator(&Keys::new(&identity::Identity::generate())).unwrap();
+        let mut b: SecureLink = SecureLink::responder(&Keys::new(&identity::Identity::generate())).unwrap();
+        assert!(a.write(b"too early").unwrap().is_empty());
+
+        let (_, reply) = b.read(&hello).unwrap();
+        let (_, last) = a.read(&reply).unwrap();
+        //split across reads, the way a socket may hand it over
+        let (first, rest) = last.split_at(3);
+        assert!(b.read(first).unwrap().0.is_empty());
+        assert_eq!(b.read(rest).unwrap().0, b"too early");
+    }
+
+    #[test]
+    fn static_key_not_signed_by_the_identity_is_refused() {
+        let alice: Keys = Keys::new(&identity::Identity::generate());
+        let other: Keys = Keys::new(&identity::Identity::generate());
+        //alice's static key, with a proof made for someone else's
+        let forged: Keys = Keys { private: alice.private.clone(), auth: other.auth.clone() };
+
+        let bob: Keys = Keys::new(&identity::Identity::generate());
+        assert!(matches!(handshake(&forged, &bob), Err(LinkError::BadIdentity)));
+        assert!(matches!(handshake(&bob, &forged), Err(LinkError::BadIdentity)));
+    }
+
+    #[test]
+    fn tampered_frames_fail_to_decrypt() {
+        let (mut a, mut b) = handshake(&Keys::new(&identity::Identity::generate()), &Keys::new(&identity::Identity::generate())).unwrap();
+        let mut wire: Vec<u8> = a.write(b"hello bob").unwrap();
+        let last: usize = wire.len() - 1;
+        wire[last] ^= 1;
+        assert!(matches!(b.read(&wire), Err(LinkError::Noise(_))));
+    }
+}
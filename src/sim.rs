@@ -0,0 +1,379 @@
+use rand::{Rng, SeedableRng};
+
+use crate::chatcore;
+use crate::identity;
+use crate::event;
+
+// Nodes listen on FIRST_PORT + their index.
+const FIRST_PORT: u16 = 7000;
+
+// How long a dropped packet takes to be sent again, TCP's minimum timeout.
+const RETRANSMIT: std::time::Duration = std::time::Duration::from_millis(200);
+
+// A whole network of ChatCores in one process, on a virtual clock. Every
+// random choice (latency, drops, node identities) comes from one seeded rng,
+// so a seed always plays out the same way.
+//
+// Connections behave like TCP: bytes arrive in order or not at all. A dropped
+// packet is resent after RETRANSMIT, so drops show up as delay. Across a
+// partition nothing arrives until it heals, and a crashed node goes silent
+// without closing anything, so its peers only notice through heartbeats.
+pub struct Simulation {
+    rng: rand::rngs::StdRng,
+    clock: std::sync::Arc<std::sync::atomic::AtomicU64>,
+    base: std::time::Instant,
+    nodes: Vec<SimNode>,
+
+    //connections by the end that writes into them, carried out in order
+    pipes: std::collections::BTreeMap<Endpoint, Pipe>,
+    dials: Vec<Dial>,
+    seq: u64,
+
+    latency: (std::time::Duration, std::time::Duration),
+    drop_rate: f64,
+    cuts: std::collections::BTreeSet<(usize, usize)>,
+}
+
+type Endpoint = (usize, chatcore::ConnId);
+
+struct SimNode {
+    core: chatcore::ChatCore,
+    alive: bool,
+    events: Vec<event::ChatEvent>,
+}
+
+struct Pipe {
+    //None until the other side accepted
+    to: std::option::Option<Endpoint>,
+    queue: std::collections::VecDeque<Packet>,
+    last: std::time::Duration,
+}
+
+struct Packet {
+    at: std::time::Duration,
+    seq: u64,
+    data: std::option::Option<Vec<u8>>,
+}
+
+struct Dial {
+    at: std::time::Duration,
+    seq: u64,
+    from: Endpoint,
+    addr: std::net::SocketAddr,
+}
+
+// What runs next, in time order. Ties go to the network in the order things
+// were sent, then to the timers in node order.
+enum Step {
+    Dial(std::time::Duration, u64, usize),
+    Packet(std::time::Duration, u64, Endpoint),
+    Tick(std::time::Duration, usize),
+}
+
+impl Step {
+    fn at(&self) -> std::time::Duration {
+        match self {
+            Step::Dial(at, _, _) | Step::Packet(at, _, _) | Step::Tick(at, _) => *at,
+        }
+    }
+
+    fn key(&self) -> (std::time::Duration, u8, u64) {
+        match self {
+            Step::Dial(at, seq, _) | Step::Packet(at, seq, _) => (*at, 0, *seq),
+            Step::Tick(at, node) => (*at, 1, *node as u64),
+        }
+    }
+}
+
+impl Simulation {
+    pub fn new(seed: u64) -> Self {
+        Simulation {
+            rng: rand::rngs::StdRng::seed_from_u64(seed),
+            clock: std::sync::Arc::new(std::sync::atomic::AtomicU64::new(0)),
+            base: std::time::Instant::now(),
+            nodes: Vec::new(),
+            pipes: std::collections::BTreeMap::new(),
+            dials: Vec::new(),
+            seq: 0,
+            latency: (std::time::Duration::from_millis(5), std::time::Duration::from_millis(15)),
+            drop_rate: 0.0,
+            cuts: std::collections::BTreeSet::new(),
+        }
+    }
+
+    // One way delay of every packet, picked uniformly from min..=max.
+    pub fn set_latency(&mut self, min: std::time::Duration, max: std::time::Duration) {
+        self.latency = (min, max.max(min));
+    }
+
+    // Chance that a packet has to be resent.
+    pub fn set_drop_rate(&mut self, rate: f64) {
+        self.drop_rate = rate.clamp(0.0, 0.99);
+    }
+
+    // Starts a node with a fresh identity, returns its index.
+    pub fn add_node(&mut self) -> usize {
+        let index: usize = self.nodes.len();
+        let mut core: chatcore::ChatCore = chatcore::ChatCore::with_identity(FIRST_PORT + index as u16, identity::Identity::from_secret(self.rng.gen()));
+        core.set_instance(self.rng.gen());
+        let clock = self.clock.clone();
+        let base: std::time::Instant = self.base;
+        core.set_clock(move || base + std::time::Duration::from_micros(clock.load(std::sync::atomic::Ordering::SeqCst)));
+
+        self.nodes.push(SimNode {
+            core,
+            alive: true,
+            events: Vec::new(),
+        });
+        index
+    }
+
+    pub fn addr(&self, node: usize) -> std::net::SocketAddr {
+        std::net::SocketAddr::from(([127, 0, 0, 1], FIRST_PORT + node as u16))
+    }
+
+    // The node itself, to call its API. What it does runs on the next run_for.
+    pub fn node(&mut self, node: usize) -> &mut chatcore::ChatCore {
+        &mut self.nodes[node].core
+    }
+
+    pub fn connect(&mut self, node: usize, to: usize) {
+        let addr: std::net::SocketAddr = self.addr(to);
+        let _ = self.nodes[node].core.connect(addr);
+    }
+
+    // Virtual time since the simulation started.
+    pub fn now(&self) -> std::time::Duration {
+        std::time::Duration::from_micros(self.clock.load(std::sync::atomic::Ordering::SeqCst))
+    }
+
+    // Nothing gets between the two sides until heal().
+    pub fn partition(&mut self, side: &[usize], other: &[usize]) {
+        for a in side {
+            for b in other {
+                self.cuts.insert((*a, *b));
+                self.cuts.insert((*b, *a));
+            }
+        }
+    }
+
+    pub fn heal(&mut self) {
+        self.cuts.clear();
+    }
+
+    // Stops the node without closing its connections, like a power cut.
+    pub fn crash(&mut self, node: usize) {
+        self.nodes[node].alive = false;
+        self.pipes.retain(|from, pipe| from.0 != node && pipe.to.map(|to| to.0) != Some(node));
+        self.dials.retain(|dial| dial.from.0 != node);
+    }
+
+    pub fn is_alive(&self, node: usize) -> bool {
+        self.nodes[node].alive
+    }
+
+    // Everything the node reported so far.
+    pub fn events(&self, node: usize) -> &[event::ChatEvent] {
+        &self.nodes[node].events
+    }
+
+    pub fn take_events(&mut self, node: usize) -> Vec<event::ChatEvent> {
+        std::mem::take(&mut self.nodes[node].events)
+    }
+
+    // Whether the node was shown a message with this text.
+    pub fn delivered(&self, node: usize, text: &str) -> bool {
+        self.nodes[node].events.iter().any(|event| match event {
+            event::ChatEvent::Message { text: shown, .. }
+            | event::ChatEvent::ChannelMessage { text: shown, .. }
+            | event::ChatEvent::DirectMessage { text: shown, .. } => shown == text,
+            _ => false,
+        })
+    }
+
+    // The node's parent in the tree, once the connection to it is up.
+    pub fn parent(&self, node: usize) -> std::option::Option<usize> {
+        if !self.nodes[node].alive {
+            return None;
+        }
+        let up_stream: chatcore::ConnId = self.nodes[node].core.up_stream()?;
+        self.pipes.get(&(node, up_stream))?.to.map(|to| to.0)
+    }
+
+    pub fn children(&self, node: usize) -> Vec<usize> {
+        (0..self.nodes.len()).filter(|child| self.parent(*child) == Some(node)).collect()
+    }
+
+    // Runs everything due within the next duration of virtual time.
+    pub fn run_for(&mut self, duration: std::time::Duration) {
+        let end: std::time::Duration = self.now() + duration;
+        self.flush();
+        while let Some(step) = self.next_step() {
+            if step.at() > end {
+                break;
+            }
+            self.set_now(step.at().max(self.now()));
+            match step {
+                Step::Dial(_, _, index) => {
+                    let dial: Dial = self.dials.remove(index);
+                    self.dial(dial);
+                },
+                Step::Packet(_, _, from) => self.deliver(from),
+                Step::Tick(_, node) => self.nodes[node].core.tick(),
+            };
+            self.flush();
+        }
+        self.set_now(end);
+    }
+
+    //rounded up, so a timer set finer than the clock still comes due
+    fn set_now(&mut self, now: std::time::Duration) {
+        self.clock.store(now.as_nanos().div_ceil(1000) as u64, std::sync::atomic::Ordering::SeqCst);
+    }
+
+    fn next_step(&self) -> std::option::Option<Step> {
+        let dials = self.dials.iter().enumerate().map(|(index, dial)| Step::Dial(dial.at, dial.seq, index));
+        let packets = self.pipes.iter().filter_map(|(from, pipe)| {
+            let to: Endpoint = pipe.to?;
+            if self.cuts.contains(&(from.0, to.0)) {
+                return None;
+            }
+            pipe.queue.front().map(|packet| Step::Packet(packet.at, packet.seq, *from))
+        });
+        let ticks = self.nodes.iter().enumerate().filter(|(_, node)| node.alive).map(|(index, node)| {
+            Step::Tick(node.core.next_tick().saturating_duration_since(self.base), index)
+        });
+        dials.chain(packets).chain(ticks).min_by_key(|step| step.key())
+    }
+
+    fn delay(&mut self) -> std::time::Duration {
+        let (min, max) = self.latency;
+        let mut delay: std::time::Duration = std::time::Duration::from_micros(self.rng.gen_range(min.as_micros() as u64..=max.as_micros() as u64));
+        while self.drop_rate > 0.0 && self.rng.gen_bool(self.drop_rate) {
+            delay += RETRANSMIT;
+        }
+        delay
+    }
+
+    fn next_seq(&mut self) -> u64 {
+        self.seq += 1;
+        self.seq
+    }
+
+    // Carries out what every node queued, until nothing new comes up.
+    fn flush(&mut self) {
+        loop {
+            let mut idle: bool = true;
+            for node in 0..self.nodes.len() {
+                let output: Vec<chatcore::Output> = self.nodes[node].core.take_output();
+                let events: Vec<event::ChatEvent> = self.nodes[node].core.take_events();
+                self.nodes[node].events.extend(events);
+                if !self.nodes[node].alive {
+                    continue;
+                }
+                for out in output {
+                    idle = false;
+                    self.output(node, out);
+                }
+            }
+            if idle {
+                return;
+            }
+        }
+    }
+
+    fn output(&mut self, node: usize, out: chatcore::Output) {
+        match out {
+            chatcore::Output::Connect(id, addr) => {
+                self.pipes.insert((node, id), Pipe { to: None, queue: std::collections::VecDeque::new(), last: self.now() });
+                let at: std::time::Duration = self.now() + self.delay();
+                let seq: u64 = self.next_seq();
+                self.dials.push(Dial { at, seq, from: (node, id), addr });
+            },
+            chatcore::Output::Write(id, bytes) => self.send((node, id), Some(bytes)),
+            chatcore::Output::Announce(bytes) => {
+                //multicast on one segment is as good as instant, but stops at a partition
+                let from: std::net::SocketAddr = self.addr(node);
+                for other in 0..self.nodes.len() {
+                    if other != node && self.nodes[other].alive && !self.cuts.contains(&(node, other)) {
+                        self.nodes[other].core.discovered(from, &bytes);
+                    }
+                }
+            },
+            chatcore::Output::Close(id) => {
+                //a dial nobody answered is just abandoned
+                if self.pipes.get(&(node, id)).is_some_and(|pipe| pipe.to.is_none()) {
+                    self.pipes.remove(&(node, id));
+                    return;
+                }
+                //the other side gets whatever is still in flight, then the close
+                self.send((node, id), None);
+                self.pipes.retain(|_, pipe| pipe.to != Some((node, id)));
+            },
+        };
+    }
+
+    fn send(&mut self, from: Endpoint, data: std::option::Option<Vec<u8>>) {
+        if !self.pipes.contains_key(&from) {
+            return;
+        }
+        let at: std::time::Duration = self.now() + self.delay();
+        let seq: u64 = self.next_seq();
+        if let Some(pipe) = self.pipes.get_mut(&from) {
+            pipe.last = pipe.last.max(at);
+            pipe.queue.push_back(Packet { at: pipe.last, seq, data });
+        }
+    }
+
+    fn dial(&mut self, dial: Dial) {
+        let (node, id) = dial.from;
+        if !self.pipes.contains_key(&dial.from) {
+            return;
+        }
+
+        let target: std::option::Option<usize> = dial.addr.port().checked_sub(FIRST_PORT).map(usize::from)
+                                                    .filter(|target| *target < self.nodes.len());
+        match target {
+            //like a host that lost power or sits across a partition, the dial
+            //hangs until the node gives up on it
+            Some(target) if !self.nodes[target].alive || self.cuts.contains(&(node, target)) => {},
+            Some(target) => {
+                let dialer: std::net::SocketAddr = self.addr(node);
+                let their_id: chatcore::ConnId = self.nodes[target].core.accept(dialer);
+                let last: std::time::Duration = self.now();
+                self.pipes.insert((target, their_id), Pipe { to: Some(dial.from), queue: std::collections::VecDeque::new(), last });
+                if let Some(pipe) = self.pipes.get_mut(&dial.from) {
+                    pipe.to = Some((target, their_id));
+                    //anything written while connecting leaves now
+                    for packet in pipe.queue.iter_mut() {
+                        packet.at = packet.at.max(last);
+                    }
+                }
+                self.nodes[node].core.connected(id, dial.addr);
+            },
+            None => {
+                self.pipes.remove(&dial.from);
+                let error: std::io::Error = std::io::Error::from(std::io::ErrorKind::ConnectionRefused);
+                self.nodes[node].core.connect_failed(id, dial.addr, error);
+            },
+        };
+    }
+
+    fn deliver(&mut self, from: Endpoint) {
+        let (to, packet) = match self.pipes.get_mut(&from) {
+            Some(pipe) => match (pipe.to, pipe.queue.pop_front()) {
+                (Some(to), Some(packet)) => (to, packet),
+                _ => return,
+            },
+            None => return,
+        };
+
+        match packet.data {
+            Some(bytes) => self.nodes[to.0].core.receive(to.1, &bytes),
+            None => {
+                self.pipes.remove(&from);
+                self.nodes[to.0].core.closed(to.1);
+            },
+        };
+    }
+}
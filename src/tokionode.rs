@@ -0,0 +1,411 @@
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+use crate::chatcore;
+use crate::chatlib;
+use crate::balance;
+use crate::discovery;
+use crate::invite;
+use crate::history;
+use crate::event;
+use crate::error::ChatError;
+
+type Command = Box<dyn FnOnce(&mut chatcore::ChatCore) + Send>;
+type Socket = std::option::Option<std::sync::Arc<tokio::net::UdpSocket>>;
+
+// What connection tasks report back to the node task.
+enum Inbound {
+    Connected(chatcore::ConnId, std::net::SocketAddr),
+    ConnectFailed(chatcore::ConnId, std::net::SocketAddr, std::io::Error),
+    Data(chatcore::ConnId, Vec<u8>),
+    Closed(chatcore::ConnId),
+    Failed(chatcore::ConnId, std::io::Error),
+}
+
+// Runs a ChatCore as a tokio task, one more task per connection. The handle
+// only sends it commands and receives its events, dropping it stops the node.
+pub struct AsyncChatNode {
+    node_id: String,
+    commands: tokio::sync::mpsc::UnboundedSender<Command>,
+    sockets: tokio::sync::mpsc::UnboundedSender<Socket>,
+    events: tokio::sync::mpsc::UnboundedReceiver<event::ChatEvent>,
+}
+
+impl AsyncChatNode {
+    // Has to be called from inside a tokio runtime, the node runs on it.
+    pub async fn bind(addr: std::net::SocketAddr, port: u16) -> Result<Self, ChatError> {
+        AsyncChatNode::bind_in(addr, port, &chatlib::data_dir()).await
+    }
+
+    // Like bind, but the node keeps its identity, address book and history in dir.
+    pub async fn bind_in(addr: std::net::SocketAddr, port: u16, dir: &std::path::Path) -> Result<Self, ChatError> {
+        let listener: tokio::net::TcpListener = tokio::net::TcpListener::bind(addr).await?;
+        let core: chatcore::ChatCore = chatcore::ChatCore::new(port, dir);
+        let node_id: String = core.node_id();
+        let (commands, commands_rx) = tokio::sync::mpsc::unbounded_channel();
+        let (sockets, sockets_rx) = tokio::sync::mpsc::unbounded_channel();
+        let (events_tx, events) = tokio::sync::mpsc::unbounded_channel();
+        tokio::spawn(run(listener, core, commands_rx, sockets_rx, events_tx));
+
+        Ok(AsyncChatNode {
+            node_id,
+            commands,
+            sockets,
+            events,
+        })
+    }
+
+    // The next thing that happened, None once the node has stopped.
+    pub async fn next_event(&mut self) -> std::option::Option<event::ChatEvent> {
+        self.events.recv().await
+    }
+
+    fn command<F: FnOnce(&mut chatcore::ChatCore) + Send + 'static>(&self, command: F) {
+        let _ = self.commands.send(Box::new(command));
+    }
+
+    async fn query<T: Send + 'static, F: FnOnce(&chatcore::ChatCore) -> T + Send + 'static>(&self, query: F) -> std::option::Option<T> {
+        let (reply, answer) = tokio::sync::oneshot::channel();
+        self.command(move |core| {
+            let _ = reply.send(query(core));
+        });
+        answer.await.ok()
+    }
+
+    // Skips the encrypted handshake on new connections, both ends have to agree.
+    pub fn set_plaintext(&self, plaintext: bool) {
+        self.command(move |core| core.set_plaintext(plaintext));
+    }
+
+    // How often quiet links are pinged and how many pings can go unanswered.
+    pub fn set_heartbeat(&self, interval: std::time::Duration, max_missed: u32) {
+        self.command(move |core| core.set_heartbeat(interval, max_missed));
+    }
+
+    // How many children we take before sending newcomers further down.
+    pub fn set_max_children(&self, max_children: usize) {
+        self.command(move |core| core.set_max_children(max_children));
+    }
+
+    // How the child a newcomer is sent on to gets picked.
+    pub fn set_rebalance(&self, rebalance: balance::Rebalance) {
+        self.command(move |core| core.set_rebalance(rebalance));
+    }
+
+    // How long a connect, including each backup parent tried on failover, may take.
+    pub fn set_connect_timeout(&self, timeout: std::time::Duration) {
+        self.command(move |core| core.set_connect_timeout(timeout));
+    }
+
+    // How often the node, while it is a root, looks for other islands to merge with.
+    pub fn set_probe_interval(&self, interval: std::time::Duration) {
+        self.command(move |core| core.set_probe_interval(interval));
+    }
+
+    // How many of the messages said before we linked up, from how far back,
+    // we ask each new neighbor for. Zero doesn't ask.
+    pub fn set_sync(&self, messages: usize, window: std::time::Duration) {
+        self.command(move |core| core.set_sync(messages, window));
+    }
+
+    // How long we keep private messages for recipients that may be offline,
+    // handing them on when they announce their name again. Zero keeps none.
+    pub fn set_hold(&self, hold: std::time::Duration) {
+        self.command(move |core| core.set_hold(hold));
+    }
+
+    // Announces the node on the local network and listens for others doing
+    // the same, see neighbors().
+    pub fn set_discovery(&self, discovery: bool) -> Result<(), ChatError> {
+        let socket: Socket = match discovery {
+            true => Some(std::sync::Arc::new(tokio::net::UdpSocket::from_std(discovery::open()?)?)),
+            false => None,
+        };
+        let _ = self.sockets.send(socket);
+        Ok(())
+    }
+
+    pub fn set_name(&self, name: &str) {
+        let name: String = String::from(name);
+        self.command(move |core| core.set_name(&name));
+    }
+
+    // Sends a message to the channel we're in, or to the lobby.
+    pub fn send(&self, msg: &str) {
+        let msg: String = String::from(msg);
+        self.command(move |core| core.send(&msg));
+    }
+
+    // Makes addr our upstream, replacing the current one. Whether that worked
+    // comes back as a Connected or ConnectFailed event.
+    pub fn connect(&self, addr: std::net::SocketAddr) {
+        self.command(move |core| {
+            let _ = core.connect(addr);
+        });
+    }
+
+    // Tries the peers this node heard of in earlier runs, most recent first.
+    pub fn bootstrap(&self) {
+        self.command(|core| core.bootstrap());
+    }
+
+    pub async fn name(&self) -> std::option::Option<String> {
+        self.query(|core| core.name().map(String::from)).await.flatten()
+    }
+
+    pub fn node_id(&self) -> String {
+        self.node_id.clone()
+    }
+
+    pub async fn current_channel(&self) -> std::option::Option<String> {
+        self.query(|core| core.current_channel().map(String::from)).await.flatten()
+    }
+
+    // The newest count messages of a channel, or of the lobby, oldest first.
+    pub async fn history(&self, channel: std::option::Option<&str>, count: usize) -> Vec<history::HistoryEntry> {
+        let channel: std::option::Option<String> = channel.map(String::from);
+        let (reply, answer) = tokio::sync::oneshot::channel();
+        self.command(move |core| {
+            let _ = reply.send(core.history(channel.as_deref(), count));
+        });
+        answer.await.unwrap_or_default()
+    }
+
+    pub async fn neighbors(&self) -> Vec<discovery::Neighbor> {
+        self.query(|core| core.neighbors()).await.unwrap_or_default()
+    }
+
+    pub async fn peers(&self) -> Vec<String> {
+        self.query(|core| core.peers()).await.unwrap_or_default()
+    }
+
+    pub async fn channels(&self) -> Vec<event::ChannelInfo> {
+        self.query(|core| core.channels()).await.unwrap_or_default()
+    }
+
+    pub fn create_channel(&self, channel: &str) {
+        let channel: String = String::from(channel);
+        self.command(move |core| core.create_channel(&channel));
+    }
+
+    pub fn create_private_channel(&self, channel: &str) {
+        let channel: String = String::from(channel);
+        self.command(move |core| core.create_private_channel(&channel));
+    }
+
+    pub fn invite_member(&self, to: &str, channel: &str) {
+        let to: String = String::from(to);
+        let channel: String = String::from(channel);
+        self.command(move |core| core.invite_member(&to, &channel));
+    }
+
+    // A token others can join the network through us with, optionally into
+    // channel. None if channel can't be handed out, the reason comes as an event.
+    pub async fn invite(&self, channel: std::option::Option<&str>) -> std::option::Option<String> {
+        let addrs: Vec<std::net::IpAddr> = invite::local_addrs();
+        let channel: std::option::Option<String> = channel.map(String::from);
+        let (reply, answer) = tokio::sync::oneshot::channel();
+        self.command(move |core| {
+            let _ = reply.send(core.invite(&addrs, channel.as_deref()));
+        });
+        answer.await.ok().flatten()
+    }
+
+    // Joins the network through the node that made token, and its channel if it names one.
+    pub fn join_invite(&self, token: &str) {
+        let token: String = String::from(token);
+        self.command(move |core| core.join_invite(&token));
+    }
+
+    pub fn join_channel(&self, channel: &str) {
+        let channel: String = String::from(channel);
+        self.command(move |core| core.join_channel(&channel));
+    }
+
+    pub fn leave_channel(&self, channel: &str) {
+        let channel: String = String::from(channel);
+        self.command(move |core| core.leave_channel(&channel));
+    }
+
+    pub fn switch_channel(&self, channel: std::option::Option<String>) {
+        self.command(move |core| core.switch_channel(channel));
+    }
+
+    pub fn send_direct(&self, to: &str, msg: &str) {
+        let to: String = String::from(to);
+        let msg: String = String::from(msg);
+        self.command(move |core| core.send_direct(&to, &msg));
+    }
+}
+
+// The node task, owns the core and stops when the handle is dropped.
+async fn run(listener: tokio::net::TcpListener,
+             mut core: chatcore::ChatCore,
+             mut commands: tokio::sync::mpsc::UnboundedReceiver<Command>,
+             mut sockets: tokio::sync::mpsc::UnboundedReceiver<Socket>,
+             events: tokio::sync::mpsc::UnboundedSender<event::ChatEvent>) {
+    let (inbound_tx, mut inbound) = tokio::sync::mpsc::unbounded_channel::<Inbound>();
+    let mut conns: std::collections::HashMap<chatcore::ConnId, tokio::sync::mpsc::UnboundedSender<Vec<u8>>> = std::collections::HashMap::new();
+    let mut discovery: Socket = None;
+
+    loop {
+        let deadline: tokio::time::Instant = tokio::time::Instant::from_std(core.next_tick());
+        let listening: Socket = discovery.clone();
+        tokio::select! {
+            accepted = listener.accept() => {
+                match accepted {
+                    Ok((stream, addr)) => {
+                        let id: chatcore::ConnId = core.accept(addr);
+                        let (outgoing_tx, outgoing) = tokio::sync::mpsc::unbounded_channel();
+                        conns.insert(id, outgoing_tx);
+                        tokio::spawn(connection(id, addr, Some(stream), outgoing, inbound_tx.clone()));
+                    },
+                    Err(error) => core.warn(format!("Couldn't Accept New Connection: {:?}", error)),
+                };
+            },
+            command = commands.recv() => {
+                match command {
+                    Some(command) => command(&mut core),
+                    None => return,
+                };
+            },
+            Some(message) = inbound.recv() => {
+                receive(&mut core, &mut conns, message);
+            },
+            Some(socket) = sockets.recv() => {
+                discovery = socket;
+                core.set_discovery(discovery.is_some());
+            },
+            Some((from, bytes)) = announcement(listening) => {
+                core.discovered(from, &bytes);
+            },
+            _ = tokio::time::sleep_until(deadline) => {
+                core.tick();
+            },
+        };
+
+        flush(&mut core, &mut conns, &inbound_tx, discovery.as_deref());
+        for event in core.take_events() {
+            if events.send(event).is_err() {
+                return;
+            }
+        }
+    }
+}
+
+// The next datagram on the discovery socket, never ready while discovery is off.
+async fn announcement(socket: Socket) -> std::option::Option<(std::net::SocketAddr, Vec<u8>)> {
+    let socket: std::sync::Arc<tokio::net::UdpSocket> = match socket {
+        Some(socket) => socket,
+        None => return std::future::pending().await,
+    };
+    let mut datagram = [0u8; 512];
+    match socket.recv_from(&mut datagram).await {
+        Ok((count, from)) => Some((from, datagram[..count].to_vec())),
+        Err(_) => None,
+    }
+}
+
+// Hands a connection task's report to the core, unless the core already
+// closed that connection.
+fn receive(core: &mut chatcore::ChatCore,
+           conns: &mut std::collections::HashMap<chatcore::ConnId, tokio::sync::mpsc::UnboundedSender<Vec<u8>>>,
+           message: Inbound) {
+    match message {
+        Inbound::Connected(id, addr) if conns.contains_key(&id) => core.connected(id, addr),
+        Inbound::ConnectFailed(id, addr, error) if conns.remove(&id).is_some() => core.connect_failed(id, addr, error),
+        Inbound::Data(id, bytes) if conns.contains_key(&id) => core.receive(id, &bytes),
+        Inbound::Closed(id) if conns.remove(&id).is_some() => core.closed(id),
+        Inbound::Failed(id, error) if conns.remove(&id).is_some() => core.failed(id, ChatError::Io(error)),
+        _ => {},
+    };
+}
+
+// Carries out what the core queued. Writes go to the connection's task, which
+// holds them until it is connected.
+fn flush(core: &mut chatcore::ChatCore,
+         conns: &mut std::collections::HashMap<chatcore::ConnId, tokio::sync::mpsc::UnboundedSender<Vec<u8>>>,
+         inbound: &tokio::sync::mpsc::UnboundedSender<Inbound>,
+         discovery: std::option::Option<&tokio::net::UdpSocket>) {
+    for out in core.take_output() {
+        match out {
+            chatcore::Output::Connect(id, addr) => {
+                let (outgoing_tx, outgoing) = tokio::sync::mpsc::unbounded_channel();
+                conns.insert(id, outgoing_tx);
+                tokio::spawn(connection(id, addr, None, outgoing, inbound.clone()));
+            },
+            chatcore::Output::Write(id, bytes) => {
+                if let Some(outgoing) = conns.get(&id) {
+                    let _ = outgoing.send(bytes);
+                }
+            },
+            chatcore::Output::Close(id) => {
+                //the connection task shuts the socket down once its sender is gone
+                conns.remove(&id);
+            },
+            chatcore::Output::Announce(bytes) => {
+                //a network without multicast just means nobody hears us
+                if let Some(socket) = discovery {
+                    let _ = socket.try_send_to(&bytes, (discovery::GROUP, discovery::PORT).into());
+                }
+            },
+        };
+    }
+}
+
+// One connection, dialing addr first when there's no stream yet.
+async fn connection(id: chatcore::ConnId,
+                    addr: std::net::SocketAddr,
+                    stream: std::option::Option<tokio::net::TcpStream>,
+                    mut outgoing: tokio::sync::mpsc::UnboundedReceiver<Vec<u8>>,
+                    inbound: tokio::sync::mpsc::UnboundedSender<Inbound>) {
+    let stream: tokio::net::TcpStream = match stream {
+        Some(stream) => stream,
+        None => match tokio::net::TcpStream::connect(addr).await {
+            Ok(stream) => {
+                let _ = inbound.send(Inbound::Connected(id, addr));
+                stream
+            },
+            Err(error) => {
+                let _ = inbound.send(Inbound::ConnectFailed(id, addr, error));
+                return;
+            },
+        },
+    };
+    let _ = stream.set_nodelay(true);
+
+    let (mut reader, mut writer) = stream.into_split();
+    let mut chunk = [0u8; 1400];
+    loop {
+        tokio::select! {
+            read = reader.read(&mut chunk) => {
+                match read {
+                    Ok(0) => {
+                        let _ = inbound.send(Inbound::Closed(id));
+                        return;
+                    },
+                    Ok(count) => {
+                        let _ = inbound.send(Inbound::Data(id, chunk[..count].to_vec()));
+                    },
+                    Err(error) => {
+                        let _ = inbound.send(Inbound::Failed(id, error));
+                        return;
+                    },
+                };
+            },
+            bytes = outgoing.recv() => {
+                match bytes {
+                    Some(bytes) => {
+                        if let Err(error) = writer.write_all(&bytes).await {
+                            let _ = inbound.send(Inbound::Failed(id, error));
+                            return;
+                        }
+                    },
+                    None => {
+                        let _ = writer.shutdown().await;
+                        return;
+                    },
+                };
+            },
+        };
+    }
+}
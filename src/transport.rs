@@ -0,0 +1,221 @@
+use std::io::{Read, Write};
+use std::os::unix::io::{AsRawFd, RawFd};
+
+// How a ChatNode reaches its peers. Peers are always named by socket address,
+// since that is what the protocol passes around for failover, and transports
+// that aren't TCP map those addresses onto their own endpoints. Everything
+// handed back has to be pollable, the node waits on it with epoll.
+pub trait Transport {
+    type Listener: AsRawFd;
+    type Stream: Connection;
+
+    fn listen(&mut self, addr: std::net::SocketAddr) -> std::io::Result<Self::Listener>;
+
+    // A pending connection, and the address to report it under.
+    fn accept(&mut self, listener: &Self::Listener) -> std::io::Result<(Self::Stream, std::net::SocketAddr)>;
+
+    // Starts connecting without waiting for an answer. The stream turns
+    // writable once the connection is up or has failed, take_error tells which;
+    // the core gives up on it if neither happens in time.
+    fn connect(&mut self, addr: std::net::SocketAddr) -> std::io::Result<Self::Stream>;
+}
+
+// One nonblocking connection of a Transport.
+pub trait Connection: Read + Write + AsRawFd {
+    fn shutdown(&self) -> std::io::Result<()>;
+
+    // Why the connection failed to open, if it did.
+    fn take_error(&self) -> std::io::Result<std::option::Option<std::io::Error>>;
+}
+
+impl Connection for std::net::TcpStream {
+    fn shutdown(&self) -> std::io::Result<()> {
+        std::net::TcpStream::shutdown(self, std::net::Shutdown::Both)
+    }
+
+    fn take_error(&self) -> std::io::Result<std::option::Option<std::io::Error>> {
+        std::net::TcpStream::take_error(self)
+    }
+}
+
+impl Connection for std::os::unix::net::UnixStream {
+    fn shutdown(&self) -> std::io::Result<()> {
+        std::os::unix::net::UnixStream::shutdown(self, std::net::Shutdown::Both)
+    }
+
+    fn take_error(&self) -> std::io::Result<std::option::Option<std::io::Error>> {
+        std::os::unix::net::UnixStream::take_error(self)
+    }
+}
+
+//peers that don't come with an ip address are reported as local
+fn local(port: u16) -> std::net::SocketAddr {
+    std::net::SocketAddr::from(([127, 0, 0, 1], port))
+}
+
+
+#[derive(Copy, Clone, Debug, Default)]
+pub struct TcpTransport;
+
+impl Transport for TcpTransport {
+    type Listener = std::net::TcpListener;
+    type Stream = std::net::TcpStream;
+
+    fn listen(&mut self, addr: std::net::SocketAddr) -> std::io::Result<Self::Listener> {
+        std::net::TcpListener::bind(addr)
+    }
+
+    fn accept(&mut self, listener: &Self::Listener) -> std::io::Result<(Self::Stream, std::net::SocketAddr)> {
+        let (stream, addr) = listener.accept()?;
+        stream.set_nonblocking(true)?;
+        stream.set_nodelay(true)?;
+        Ok((stream, addr))
+    }
+
+    fn connect(&mut self, addr: std::net::SocketAddr) -> std::io::Result<Self::Stream> {
+        let socket: socket2::Socket = socket2::Socket::new(socket2::Domain::for_address(addr), socket2::Type::STREAM, Some(socket2::Protocol::TCP))?;
+        socket.set_nonblocking(true)?;
+        socket.set_tcp_nodelay(true)?;
+        match socket.connect(&addr.into()) {
+            Ok(_) => {},
+            Err(ref error) if error.raw_os_error() == Some(libc::EINPROGRESS) => {},
+            Err(error) => return Err(error),
+        };
+        Ok(socket.into())
+    }
+}
+
+
+// Unix-domain sockets in one directory, for nodes sharing a machine. Only the
+// port picks the socket, prism-<PORT>.sock, the ip address is ignored.
+#[derive(Clone, Debug)]
+pub struct UnixTransport {
+    dir: std::path::PathBuf,
+}
+
+impl UnixTransport {
+    pub fn new<P: Into<std::path::PathBuf>>(dir: P) -> Self {
+        UnixTransport {
+            dir: dir.into(),
+        }
+    }
+
+    fn path(&self, port: u16) -> std::path::PathBuf {
+        self.dir.join(format!("prism-{}.sock", port))
+    }
+}
+
+impl Transport for UnixTransport {
+    type Listener = std::os::unix::net::UnixListener;
+    type Stream = std::os::unix::net::UnixStream;
+
+    fn listen(&mut self, addr: std::net::SocketAddr) -> std::io::Result<Self::Listener> {
+        let path: std::path::PathBuf = self.path(addr.port());
+        //a socket nobody answers on is left over from a node that exited
+        if path.exists() && std::os::unix::net::UnixStream::connect(&path).is_err() {
+            std::fs::remove_file(&path)?;
+        }
+        std::os::unix::net::UnixListener::bind(&path)
+    }
+
+    fn accept(&mut self, listener: &Self::Listener) -> std::io::Result<(Self::Stream, std::net::SocketAddr)> {
+        let (stream, _) = listener.accept()?;
+        stream.set_nonblocking(true)?;
+        Ok((stream, local(0)))
+    }
+
+    //local sockets answer or refuse right away
+    fn connect(&mut self, addr: std::net::SocketAddr) -> std::io::Result<Self::Stream> {
+        let stream: std::os::unix::net::UnixStream = std::os::unix::net::UnixStream::connect(self.path(addr.port()))?;
+        stream.set_nonblocking(true)?;
+        Ok(stream)
+    }
+}
+
+
+// Nodes in one process that find each other through a shared registry instead
+// of the network, clone the transport to hand it to every node. Like the unix
+// transport only the port matters. Connections are socket pairs so they can be
+// polled like any other, a listener is told about new ones through a doorbell
+// pair.
+#[derive(Clone, Debug, Default)]
+pub struct MemoryTransport {
+    listeners: std::sync::Arc<std::sync::Mutex<std::collections::HashMap<u16, Backlog>>>,
+}
+
+#[derive(Debug)]
+struct Backlog {
+    pending: std::collections::VecDeque<std::os::unix::net::UnixStream>,
+    bell: std::os::unix::net::UnixStream,
+}
+
+pub struct MemoryListener {
+    port: u16,
+    bell: std::os::unix::net::UnixStream,
+    listeners: std::sync::Arc<std::sync::Mutex<std::collections::HashMap<u16, Backlog>>>,
+}
+
+impl MemoryTransport {
+    pub fn new() -> Self {
+        MemoryTransport::default()
+    }
+}
+
+impl Transport for MemoryTransport {
+    type Listener = MemoryListener;
+    type Stream = std::os::unix::net::UnixStream;
+
+    fn listen(&mut self, addr: std::net::SocketAddr) -> std::io::Result<Self::Listener> {
+        let mut listeners = self.listeners.lock().map_err(|_| std::io::Error::other("memory transport poisoned"))?;
+        if listeners.contains_key(&addr.port()) {
+            return Err(std::io::Error::from(std::io::ErrorKind::AddrInUse));
+        }
+
+        let (bell, ringer) = std::os::unix::net::UnixStream::pair()?;
+        bell.set_nonblocking(true)?;
+        listeners.insert(addr.port(), Backlog { pending: std::collections::VecDeque::new(), bell: ringer });
+        Ok(MemoryListener {
+            port: addr.port(),
+            bell,
+            listeners: self.listeners.clone(),
+        })
+    }
+
+    fn accept(&mut self, listener: &Self::Listener) -> std::io::Result<(Self::Stream, std::net::SocketAddr)> {
+        let mut ring = [0u8; 1];
+        (&listener.bell).read_exact(&mut ring)?;
+
+        let mut listeners = self.listeners.lock().map_err(|_| std::io::Error::other("memory transport poisoned"))?;
+        let stream: std::os::unix::net::UnixStream = listeners.get_mut(&listener.port)
+                                                              .and_then(|backlog| backlog.pending.pop_front())
+                                                              .ok_or_else(|| std::io::Error::from(std::io::ErrorKind::WouldBlock))?;
+        stream.set_nonblocking(true)?;
+        Ok((stream, local(0)))
+    }
+
+    fn connect(&mut self, addr: std::net::SocketAddr) -> std::io::Result<Self::Stream> {
+        let mut listeners = self.listeners.lock().map_err(|_| std::io::Error::other("memory transport poisoned"))?;
+        let backlog: &mut Backlog = listeners.get_mut(&addr.port())
+                                             .ok_or_else(|| std::io::Error::from(std::io::ErrorKind::ConnectionRefused))?;
+
+        let (stream, theirs) = std::os::unix::net::UnixStream::pair()?;
+        stream.set_nonblocking(true)?;
+        backlog.pending.push_back(theirs);
+        (&backlog.bell).write_all(&[1])?;
+        Ok(stream)
+    }
+}
+
+impl AsRawFd for MemoryListener {
+    fn as_raw_fd(&self) -> RawFd {
+        self.bell.as_raw_fd()
+    }
+}
+
+impl Drop for MemoryListener {
+    fn drop(&mut self) {
+        if let Ok(mut listeners) = self.listeners.lock() {
+            listeners.remove(&self.port);
+        }
+    }
+}
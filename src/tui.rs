@@ -0,0 +1,453 @@
+use console::style;
+use console::Key;
+
+// The full-screen front end (--tui): messages scroll by in a pane on the left,
+// channels and peers sit in a sidebar on the right, and the line being typed
+// keeps the bottom row to itself, so nothing arriving meanwhile garbles it.
+//
+//  +---------------------------------+------------+
+//  | messages                        | CHANNELS   |
+//  |                                 | PEERS      |
+//  +---------------------------------+------------+
+//  | status                                       |
+//  | > input                                      |
+//  +----------------------------------------------+
+
+const SIDEBAR_WIDTH: usize = 24;
+const PROMPT: &str = "> ";
+const MAX_SCROLLBACK: usize = 5000;
+const MAX_TYPED: usize = 100;
+const TAB_WIDTH: usize = 4;
+
+// Switches the terminal to its alternate screen, so whatever was on it before
+// comes back once we quit.
+const ENTER_SCREEN: &str = "\x1b[?1049h";
+const LEAVE_SCREEN: &str = "\x1b[?1049l";
+
+// What the sidebar and status line show, gathered from the node before every draw.
+pub struct Sidebar {
+    pub port: String,
+    pub name: std::option::Option<String>,
+    pub channels: Vec<chat::ChannelInfo>,
+    pub current: std::option::Option<String>,
+    pub peers: Vec<String>,
+}
+
+pub struct Tui {
+    term: console::Term,
+    keys: std::sync::mpsc::Receiver<Key>,
+
+    //every line shown, oldest first, and how many wrapped rows up from the
+    //bottom the pane is scrolled
+    scrollback: std::collections::VecDeque<String>,
+    scroll: usize,
+
+    //the line being typed, and the cursor in it
+    input: Vec<char>,
+    cursor: usize,
+
+    //lines typed before, oldest first, and which one is up while browsing them
+    typed: Vec<String>,
+    browsing: std::option::Option<usize>,
+    draft: Vec<char>,
+
+    //what's on screen now, nothing is redrawn until one of them changes
+    dirty: bool,
+    size: (u16, u16),
+    side: Vec<String>,
+}
+
+impl Tui {
+    pub fn open() -> std::io::Result<Self> {
+        let term: console::Term = console::Term::stdout();
+        term.write_str(ENTER_SCREEN)?;
+        term.clear_screen()?;
+        Ok(Tui::new(term.clone(), spawn_keys(term)))
+    }
+
+    // Draws to term and takes its keys from keys, the terminal is left as it is.
+    fn new(term: console::Term, keys: std::sync::mpsc::Receiver<Key>) -> Self {
+        Tui {
+            keys,
+            term,
+            scrollback: std::collections::VecDeque::new(),
+            scroll: 0,
+            input: Vec::new(),
+            cursor: 0,
+            typed: Vec::new(),
+            browsing: None,
+            draft: Vec::new(),
+            dirty: true,
+            size: (0, 0),
+            side: Vec::new(),
+        }
+    }
+
+    // Adds to the message pane. A pane scrolled back stays where it is. ESC
+    // is let through for our own colors, prism cleans what peers sent first.
+    pub fn push(&mut self, text: &str) {
+        for line in text.split('\n') {
+            let line: String = line.replace('\t', &" ".repeat(TAB_WIDTH)).replace(|c: char| c.is_control() && c != '\x1b', "");
+            if self.scroll > 0 {
+                self.scroll += wrap(&line, self.pane_width()).len();
+            }
+            self.scrollback.push_back(line);
+            if self.scrollback.len() > MAX_SCROLLBACK {
+                self.scrollback.pop_front();
+            }
+        }
+        self.dirty = true;
+    }
+
+    // The lines entered since last time, after handling every key pressed.
+    // Ctrl-C comes back as /exit.
+    pub fn typed(&mut self) -> Vec<String> {
+        let mut lines: Vec<String> = Vec::new();
+        while let Ok(key) = self.keys.try_recv() {
+            self.dirty = true;
+            match key {
+                Key::Enter => {
+                    let line: String = self.input.drain(..).collect();
+                    self.cursor = 0;
+                    self.browsing = None;
+                    if line.trim().is_empty() {
+                        continue;
+                    }
+                    if self.typed.last() != Some(&line) {
+                        self.typed.push(line.clone());
+                        if self.typed.len() > MAX_TYPED {
+                            self.typed.remove(0);
+                        }
+                    }
+                    self.scroll = 0;
+                    self.push(&style(&line).bold().to_string());
+                    lines.push(line);
+                },
+                Key::CtrlC => lines.push(String::from("/exit")),
+                Key::Char('\u{1}') | Key::Home => self.cursor = 0,
+                Key::Char('\u{5}') | Key::End => self.cursor = self.input.len(),
+                Key::Char('\u{15}') => {
+                    self.input.drain(..self.cursor);
+                    self.cursor = 0;
+                },
+                Key::Char(c) if !c.is_control() => {
+                    self.input.insert(self.cursor, c);
+                    self.cursor += 1;
+                },
+                Key::Backspace if self.cursor > 0 => {
+                    self.cursor -= 1;
+                    self.input.remove(self.cursor);
+                },
+                Key::Del if self.cursor < self.input.len() => {
+                    self.input.remove(self.cursor);
+                },
+                Key::ArrowLeft => self.cursor = self.cursor.saturating_sub(1),
+                Key::ArrowRight => self.cursor = (self.cursor + 1).min(self.input.len()),
+                Key::ArrowUp => self.browse_back(),
+                Key::ArrowDown => self.browse_forward(),
+                Key::PageUp => self.scroll += self.pane_height().saturating_sub(1).max(1),
+                Key::PageDown => self.scroll = self.scroll.saturating_sub(self.pane_height().saturating_sub(1).max(1)),
+                Key::Escape => {
+                    self.input.clear();
+                    self.cursor = 0;
+                    self.browsing = None;
+                },
+                _ => {},
+            };
+        }
+        lines
+    }
+
+    fn browse_back(&mut self) {
+        let at: usize = match self.browsing {
+            Some(0) => return,
+            Some(at) => at - 1,
+            None if self.typed.is_empty() => return,
+            None => {
+                self.draft = self.input.clone();
+                self.typed.len() - 1
+            },
+        };
+        self.browsing = Some(at);
+        self.input = self.typed[at].chars().collect();
+        self.cursor = self.input.len();
+    }
+
+    fn browse_forward(&mut self) {
+        match self.browsing {
+            Some(at) if at + 1 < self.typed.len() => {
+                self.browsing = Some(at + 1);
+                self.input = self.typed[at + 1].chars().collect();
+            },
+            Some(_) => {
+                self.browsing = None;
+                self.input = std::mem::take(&mut self.draft);
+            },
+            None => return,
+        };
+        self.cursor = self.input.len();
+    }
+
+    fn pane_width(&self) -> usize {
+        let cols: usize = self.size.1 as usize;
+        cols.saturating_sub(sidebar_width(cols) + 1).max(1)
+    }
+
+    fn pane_height(&self) -> usize {
+        (self.size.0 as usize).saturating_sub(2)
+    }
+
+    // Redraws the whole screen if anything changed since the last draw.
+    pub fn draw(&mut self, sidebar: &Sidebar) -> std::io::Result<()> {
+        let size: (u16, u16) = self.term.size();
+        let side: Vec<String> = sidebar_lines(sidebar);
+        if !self.dirty && size == self.size && side == self.side {
+            return Ok(());
+        }
+        self.size = size;
+        self.dirty = false;
+
+        let (rows, cols): (usize, usize) = (size.0 as usize, size.1 as usize);
+        let (pane_width, pane_height): (usize, usize) = (self.pane_width(), self.pane_height());
+        let side_width: usize = sidebar_width(cols);
+
+        //only as many lines from the end as the pane can show
+        let mut wrapped: std::collections::VecDeque<String> = std::collections::VecDeque::new();
+        for line in self.scrollback.iter().rev() {
+            for row in wrap(line, pane_width).into_iter().rev() {
+                wrapped.push_front(row);
+            }
+            if wrapped.len() >= pane_height + self.scroll {
+                break;
+            }
+        }
+        self.scroll = self.scroll.min(wrapped.len().saturating_sub(pane_height));
+        let end: usize = wrapped.len() - self.scroll;
+        let start: usize = end.saturating_sub(pane_height);
+        let pane: Vec<&String> = wrapped.range(start..end).collect();
+
+        let mut screen: String = String::from("\x1b[H");
+        for row in 0..pane_height {
+            //messages sit at the bottom of the pane until there are enough to fill it
+            let message: &str = match (row + pane.len()).checked_sub(pane_height) {
+                Some(index) => pane[index],
+                None => "",
+            };
+            let entry: &str = side.get(row).map(|line| line.as_str()).unwrap_or("");
+            screen.push_str(&fit(message, pane_width));
+            screen.push_str(&style("│").dim().to_string());
+            screen.push_str(&fit(entry, side_width));
+            screen.push_str("\r\n");
+        }
+
+        let place: String = match &sidebar.current {
+            Some(channel) => format!("#{}", channel),
+            None => String::from("lobby"),
+        };
+        let mut status: String = format!(" prism :{}  {}  {}", sidebar.port, sidebar.name.as_deref().unwrap_or("(no name, /name <NAME>)"), place);
+        if self.scroll > 0 {
+            status.push_str(&format!("  [{} more below, PgDn]", self.scroll));
+        }
+        screen.push_str(&style(fit(&status, cols)).reverse().to_string());
+        screen.push_str("\r\n");
+
+        //the input scrolls sideways to keep the cursor in view
+        let room: usize = cols.saturating_sub(PROMPT.len()).max(1);
+        let offset: usize = (self.cursor + 1).saturating_sub(room);
+        let visible: String = self.input.iter().skip(offset).take(room).collect();
+        screen.push_str(&fit(&format!("{}{}", PROMPT, visible), cols.saturating_sub(1)));
+
+        self.side = side;
+        self.term.write_str(&screen)?;
+        self.term.move_cursor_to(PROMPT.len() + self.cursor - offset, rows.saturating_sub(1))?;
+        self.term.flush()
+    }
+}
+
+impl Drop for Tui {
+    fn drop(&mut self) {
+        let _ = self.term.show_cursor();
+        let _ = self.term.write_str(LEAVE_SCREEN);
+    }
+}
+
+fn sidebar_width(cols: usize) -> usize {
+    SIDEBAR_WIDTH.min(cols / 3)
+}
+
+fn sidebar_lines(sidebar: &Sidebar) -> Vec<String> {
+    let marker = |current: bool, joined: bool| -> &str {
+        if current { "*" }
+        else if joined { "+" }
+        else { " " }
+    };
+
+    let mut lines: Vec<String> = vec![style(" CHANNELS").cyan().to_string()];
+    lines.push(format!(" {} lobby", marker(sidebar.current.is_none(), true)));
+    for channel in &sidebar.channels {
+        let private: &str = if channel.owner.is_some() { " (private)" } else { "" };
+        lines.push(format!(" {} #{}{}", marker(channel.current, channel.joined), channel.name, private));
+    }
+    lines.push(String::new());
+    lines.push(style(format!(" PEERS ({})", sidebar.peers.len())).cyan().to_string());
+    for peer in &sidebar.peers {
+        lines.push(format!("   {}", peer));
+    }
+    lines
+}
+
+// Cuts or pads a line to exactly width columns, color codes aside.
+fn fit(line: &str, width: usize) -> String {
+    let cut: std::borrow::Cow<str> = console::truncate_str(line, width, "");
+    console::pad_str(&cut, width, console::Alignment::Left, None).to_string()
+}
+
+// Splits a line into rows of at most width columns. Colors carry on across
+// the split and are reset at the end of every row.
+fn wrap(line: &str, width: usize) -> Vec<String> {
+    let mut rows: Vec<String> = Vec::new();
+    let mut row: String = String::new();
+    let mut used: usize = 0;
+    //the color codes in effect at this point of the line
+    let mut active: String = String::new();
+
+    let mut chars = line.chars();
+    while let Some(c) = chars.next() {
+        if c == '\x1b' {
+            let mut code: String = String::from(c);
+            for next in chars.by_ref() {
+                code.push(next);
+                if next.is_ascii_alphabetic() {
+                    break;
+                }
+            }
+            match code.as_str() {
+                "\x1b[0m" => active.clear(),
+                _ => active.push_str(&code),
+            };
+            row.push_str(&code);
+            continue;
+        }
+
+        let columns: usize = console::measure_text_width(c.encode_utf8(&mut [0u8; 4]));
+        if used + columns > width && used > 0 {
+            if !active.is_empty() {
+                row.push_str("\x1b[0m");
+            }
+            rows.push(std::mem::replace(&mut row, active.clone()));
+            used = 0;
+        }
+        row.push(c);
+        used += columns;
+    }
+    rows.push(row);
+    rows
+}
+
+// Reads keys on their own thread, the terminal is only raw while waiting on
+// one. Raw reads hand Ctrl-C over as a key instead of killing us with the
+// terminal still taken over.
+fn spawn_keys(term: console::Term) -> std::sync::mpsc::Receiver<Key> {
+    let (tx, rx) = std::sync::mpsc::channel::<Key>();
+    std::thread::spawn(move || {
+        while let Ok(key) = term.read_key_raw() {
+            if tx.send(key).is_err() {
+                break;
+            }
+        }
+    });
+    rx
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn widths(rows: &[String]) -> Vec<usize> {
+        rows.iter().map(|row| console::measure_text_width(row)).collect()
+    }
+
+    #[test]
+    fn wrap_splits_lines_into_rows_no_wider_than_the_pane() {
+        assert_eq!(wrap("abcdefgh", 3), vec!["abc", "def", "gh"]);
+        assert_eq!(wrap("abcdef", 3), vec!["abc", "def"]);
+        assert_eq!(wrap("abc", 10), vec!["abc"]);
+        assert_eq!(wrap("", 10), vec![""]);
+    }
+
+    #[test]
+    fn wrap_carries_colors_over_to_the_next_row() {
+        let rows: Vec<String> = wrap("\x1b[31mabcdef\x1b[0mgh", 4);
+        assert_eq!(rows, vec!["\x1b[31mabcd\x1b[0m", "\x1b[31mef\x1b[0mgh"]);
+        assert_eq!(widths(&rows), vec![4, 4]);
+
+        //codes pile up until a reset, and every row ends reset
+        let rows: Vec<String> = wrap("\x1b[1m\x1b[32mabc", 2);
+        assert_eq!(rows, vec!["\x1b[1m\x1b[32mab\x1b[0m", "\x1b[1m\x1b[32mc"]);
+    }
+
+    #[test]
+    fn wrap_keeps_wide_characters_whole() {
+        assert_eq!(wrap("日本語", 4), vec!["日本", "語"]);
+        assert_eq!(wrap("日本語", 3), vec!["日", "本", "語"]);
+        assert_eq!(wrap("a日b", 2), vec!["a", "日", "b"]);
+        //a pane narrower than one character still gets it, one per row
+        assert_eq!(wrap("日本", 1), vec!["日", "本"]);
+    }
+
+    #[test]
+    fn fit_cuts_or_pads_to_the_width() {
+        assert_eq!(fit("hello", 3), "hel");
+        assert_eq!(fit("hi", 4), "hi  ");
+        assert_eq!(fit("", 2), "  ");
+        assert_eq!(console::measure_text_width(&fit("日本語", 3)), 3);
+
+        let colored: String = fit("\x1b[31mhello\x1b[0m", 3);
+        assert_eq!(console::measure_text_width(&colored), 3);
+        assert_eq!(console::strip_ansi_codes(&colored), "hel");
+    }
+
+    #[test]
+    fn browsing_typed_lines_gives_the_draft_back() {
+        let (keys, rx) = std::sync::mpsc::channel::<Key>();
+        //buffered and never flushed, so nothing reaches the real terminal
+        let mut tui: Tui = Tui::new(console::Term::buffered_stdout(), rx);
+        let press = |text: &str| {
+            for c in text.chars() {
+                keys.send(Key::Char(c)).unwrap();
+            }
+        };
+
+        press("one");
+        keys.send(Key::Enter).unwrap();
+        press("two");
+        keys.send(Key::Enter).unwrap();
+        press("two");
+        keys.send(Key::Enter).unwrap();
+        press("dr");
+        assert_eq!(tui.typed(), vec!["one", "two", "two"]);
+        assert_eq!(tui.typed, vec!["one", "two"]);
+
+        tui.browse_back();
+        assert_eq!(tui.input.iter().collect::<String>(), "two");
+        tui.browse_back();
+        tui.browse_back();
+        assert_eq!(tui.input.iter().collect::<String>(), "one");
+        assert_eq!(tui.cursor, 3);
+
+        tui.browse_forward();
+        assert_eq!(tui.input.iter().collect::<String>(), "two");
+        tui.browse_forward();
+        assert_eq!(tui.input.iter().collect::<String>(), "dr");
+        assert_eq!(tui.cursor, 2);
+        assert_eq!(tui.browsing, None);
+        tui.browse_forward();
+        assert_eq!(tui.input.iter().collect::<String>(), "dr");
+
+        //the same through the arrow keys
+        keys.send(Key::ArrowUp).unwrap();
+        keys.send(Key::ArrowDown).unwrap();
+        assert!(tui.typed().is_empty());
+        assert_eq!(tui.input.iter().collect::<String>(), "dr");
+    }
+}
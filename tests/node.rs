@@ -0,0 +1,235 @@
+use chat::{ChatEvent, ChatNode, MemoryTransport};
+
+// A fresh directory for a node's identity, address book and history.
+fn scratch(test: &str, node: &str) -> std::path::PathBuf {
+    let dir: std::path::PathBuf = std::env::temp_dir().join(format!("prism-{}-{}-{}", test, std::process::id(), node));
+    let _ = std::fs::remove_dir_all(&dir);
+    dir
+}
+
+fn addr(port: u16) -> std::net::SocketAddr {
+    std::net::SocketAddr::from(([127, 0, 0, 1], port))
+}
+
+fn start(net: &MemoryTransport, port: u16, dir: &std::path::Path, name: &str) -> ChatNode<MemoryTransport> {
+    let mut node: ChatNode<MemoryTransport> = ChatNode::with_transport(net.clone(), addr(port), port, dir).unwrap();
+    node.set_name(name);
+    node
+}
+
+// Polls every node in turn until one of them reports an event matching found,
+// or gives up after a few seconds.
+fn wait_for(nodes: &mut [ChatNode<MemoryTransport>], found: impl Fn(usize, &ChatEvent) -> bool) -> bool {
+    let deadline: std::time::Instant = std::time::Instant::now() + std::time::Duration::from_secs(5);
+    while std::time::Instant::now() < deadline {
+        for (index, node) in nodes.iter_mut().enumerate() {
+            for event in node.poll(Some(std::time::Duration::from_millis(5))) {
+                if found(index, &event) {
+                    return true;
+                }
+            }
+        }
+    }
+    false
+}
+
+#[test]
+fn message_crosses_a_chain_of_nodes_in_memory() {
+    let net: MemoryTransport = MemoryTransport::new();
+    let dirs: Vec<std::path::PathBuf> = ["a", "b", "c"].iter().map(|node| scratch("chain", node)).collect();
+    let mut nodes: Vec<ChatNode<MemoryTransport>> = vec![
+        start(&net, 1, &dirs[0], "a"),
+        start(&net, 2, &dirs[1], "b"),
+        start(&net, 3, &dirs[2], "c"),
+    ];
+    nodes[1].connect(addr(1)).unwrap();
+    nodes[2].connect(addr(2)).unwrap();
+    assert!(wait_for(&mut nodes, |index, event| index == 0 && matches!(event, ChatEvent::PeerJoined { name, .. } if name == "c")));
+
+    nodes[2].send("hello over memory");
+    assert!(wait_for(&mut nodes, |index, event| {
+        index == 0 && matches!(event, ChatEvent::Message { from, text, .. } if from == "c" && text == "hello over memory")
+    }));
+
+    drop(nodes);
+    for dir in dirs {
+        let _ = std::fs::remove_dir_all(dir);
+    }
+}
+
+#[test]
+fn nodes_in_separate_dirs_have_separate_identities() {
+    let net: MemoryTransport = MemoryTransport::new();
+    let dirs: Vec<std::path::PathBuf> = ["a", "b"].iter().map(|node| scratch("identity", node)).collect();
+    let mut nodes: Vec<ChatNode<MemoryTransport>> = vec![
+        start(&net, 1, &dirs[0], "a"),
+        start(&net, 2, &dirs[1], "b"),
+    ];
+    assert_ne!(nodes[0].node_id(), nodes[1].node_id());
+
+    nodes[1].connect(addr(1)).unwrap();
+    assert!(wait_for(&mut nodes, |index, event| index == 1 && matches!(event, ChatEvent::LinkEstablished { .. })));
+    nodes[0].send("hello b");
+    assert!(wait_for(&mut nodes, |index, event| {
+        index == 1 && matches!(event, ChatEvent::Message { text, .. } if text == "hello b")
+    }));
+
+    drop(nodes);
+    for dir in dirs {
+        let _ = std::fs::remove_dir_all(dir);
+    }
+}
+
+#[test]
+fn nodes_sharing_a_dir_still_talk() {
+    let net: MemoryTransport = MemoryTransport::new();
+    let dir: std::path::PathBuf = scratch("shared", "ab");
+    let mut nodes: Vec<ChatNode<MemoryTransport>> = vec![
+        start(&net, 1, &dir, "a"),
+        start(&net, 2, &dir, "b"),
+    ];
+    assert_ne!(nodes[0].node_id(), nodes[1].node_id());
+
+    nodes[1].connect(addr(1)).unwrap();
+    assert!(wait_for(&mut nodes, |index, event| index == 0 && matches!(event, ChatEvent::PeerJoined { name, .. } if name == "b")));
+    nodes[1].send("hello a");
+    assert!(wait_for(&mut nodes, |index, event| {
+        index == 0 && matches!(event, ChatEvent::Message { text, .. } if text == "hello a")
+    }));
+
+    //a restart on the same port comes back as the same node
+    let node_id: String = nodes[1].node_id();
+    drop(nodes);
+    let again: ChatNode<MemoryTransport> = start(&MemoryTransport::new(), 2, &dir, "b");
+    assert_eq!(again.node_id(), node_id);
+
+    drop(again);
+    let _ = std::fs::remove_dir_all(dir);
+}
+
+// A port nothing listens on, as far as anyone can tell.
+fn free_port() -> u16 {
+    std::net::TcpListener::bind(addr(0)).unwrap().local_addr().unwrap().port()
+}
+
+#[test]
+fn tcp_connects_finish_in_the_poll_loop() {
+    let dirs: Vec<std::path::PathBuf> = ["a", "b"].iter().map(|node| scratch("tcp", node)).collect();
+    let ports: [u16; 2] = [free_port(), free_port()];
+    let mut a: ChatNode = ChatNode::with_transport(chat::TcpTransport, addr(ports[0]), ports[0], &dirs[0]).unwrap();
+    let mut b: ChatNode = ChatNode::with_transport(chat::TcpTransport, addr(ports[1]), ports[1], &dirs[1]).unwrap();
+    a.set_name("a");
+    b.set_name("b");
+
+    //refusals come back as events rather than from connect
+    let nobody: u16 = free_port();
+    b.connect(addr(nobody)).unwrap();
+    let mut refused: bool = false;
+    let deadline: std::time::Instant = std::time::Instant::now() + std::time::Duration::from_secs(5);
+    while !refused && std::time::Instant::now() < deadline {
+        refused = b.poll(Some(std::time::Duration::from_millis(5))).iter()
+                   .any(|event| matches!(event, ChatEvent::ConnectFailed { addr } if addr.port() == nobody));
+    }
+    assert!(refused);
+
+    b.connect(addr(ports[0])).unwrap();
+    let mut joined: bool = false;
+    let deadline: std::time::Instant = std::time::Instant::now() + std::time::Duration::from_secs(5);
+    while !joined && std::time::Instant::now() < deadline {
+        b.poll(Some(std::time::Duration::from_millis(5)));
+        joined = a.poll(Some(std::time::Duration::from_millis(5))).iter()
+                  .any(|event| matches!(event, ChatEvent::PeerJoined { name, .. } if name == "b"));
+    }
+    assert!(joined);
+
+    drop((a, b));
+    for dir in dirs {
+        let _ = std::fs::remove_dir_all(dir);
+    }
+}
+
+#[test]
+fn bootstrap_tries_the_most_recent_peer_but_never_itself() {
+    let dir: std::path::PathBuf = scratch("bootstrap", "a");
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::write(dir.join("peers"), "10.0.0.1:7000 100\n127.0.0.1:7000 400\n10.0.0.2:7000 300\n").unwrap();
+
+    let mut core: chat::ChatCore = chat::ChatCore::new(7000, &dir);
+    core.bootstrap();
+    let connects: Vec<std::net::SocketAddr> = core.take_output().into_iter().filter_map(|out| match out {
+        chat::Output::Connect(_, addr) => Some(addr),
+        _ => None,
+    }).collect();
+    assert_eq!(connects, vec!["10.0.0.2:7000".parse().unwrap()]);
+    assert!(core.take_events().iter().any(|event| matches!(event, ChatEvent::Notice(text) if text == "Trying 2 Known Peer(s)")));
+
+    let _ = std::fs::remove_dir_all(dir);
+}
+
+// Drives the epoll node and lets the tokio one run in between, until one of
+// them reports an event matching found. The tokio node is index 0.
+#[cfg(feature = "tokio")]
+async fn wait_for_both(a: &mut chat::AsyncChatNode, b: &mut ChatNode, found: impl Fn(usize, &ChatEvent) -> bool) -> bool {
+    let deadline: std::time::Instant = std::time::Instant::now() + std::time::Duration::from_secs(5);
+    while std::time::Instant::now() < deadline {
+        for event in b.poll(Some(std::time::Duration::from_millis(5))) {
+            if found(1, &event) {
+                return true;
+            }
+        }
+        while let Ok(Some(event)) = tokio::time::timeout(std::time::Duration::from_millis(5), a.next_event()).await {
+            if found(0, &event) {
+                return true;
+            }
+        }
+    }
+    false
+}
+
+#[cfg(feature = "tokio")]
+#[tokio::test]
+async fn tokio_and_epoll_nodes_talk() {
+    let dirs: Vec<std::path::PathBuf> = ["a", "b"].iter().map(|node| scratch("tokio", node)).collect();
+    let ports: [u16; 2] = [free_port(), free_port()];
+    let mut a: chat::AsyncChatNode = chat::AsyncChatNode::bind_in(addr(ports[0]), ports[0], &dirs[0]).await.unwrap();
+    let mut b: ChatNode = ChatNode::with_transport(chat::TcpTransport, addr(ports[1]), ports[1], &dirs[1]).unwrap();
+    a.set_name("tokio");
+    b.set_name("epoll");
+
+    //the link comes up over the encrypted handshake, plaintext is off by default
+    b.connect(addr(ports[0])).unwrap();
+    assert!(wait_for_both(&mut a, &mut b, |index, event| index == 0 && matches!(event, ChatEvent::PeerJoined { name, .. } if name == "epoll")).await);
+
+    //nothing signed by a has reached b yet, so b has no key for it and sends a DIRECT frame
+    //rather than sealed mail
+    b.send_direct("tokio", "psst");
+    assert!(wait_for_both(&mut a, &mut b, |index, event| {
+        index == 0 && matches!(event, ChatEvent::DirectMessage { from, text, .. } if from == "epoll" && text == "psst")
+    }).await);
+
+    a.send("hello epoll");
+    assert!(wait_for_both(&mut a, &mut b, |index, event| {
+        index == 1 && matches!(event, ChatEvent::Message { from, text, .. } if from == "tokio" && text == "hello epoll")
+    }).await);
+    b.send("hello tokio");
+    assert!(wait_for_both(&mut a, &mut b, |index, event| {
+        index == 0 && matches!(event, ChatEvent::Message { from, text, .. } if from == "epoll" && text == "hello tokio")
+    }).await);
+
+    a.create_channel("room");
+    assert!(wait_for_both(&mut a, &mut b, |index, event| index == 1 && matches!(event, ChatEvent::ChannelCreated { channel } if channel == "room")).await);
+    b.join_channel("room");
+    b.send("in the room");
+    assert!(wait_for_both(&mut a, &mut b, |index, event| {
+        index == 0 && matches!(event, ChatEvent::ChannelMessage { channel, from, text, .. } if channel == "room" && from == "epoll" && text == "in the room")
+    }).await);
+    a.send("also in the room");
+    assert!(wait_for_both(&mut a, &mut b, |index, event| {
+        index == 1 && matches!(event, ChatEvent::ChannelMessage { channel, from, text, .. } if channel == "room" && from == "tokio" && text == "also in the room")
+    }).await);
+
+    drop((a, b));
+    for dir in dirs {
+        let _ = std::fs::remove_dir_all(dir);
+    }
+}
@@ -0,0 +1,625 @@
+use chat::{ChatEvent, Rebalance, Simulation};
+
+fn secs(secs: u64) -> std::time::Duration {
+    std::time::Duration::from_secs(secs)
+}
+
+// Starts count nodes named n0, n1, ..., each connecting to the one picked by parent_of.
+fn tree(seed: u64, count: usize, parent_of: fn(usize) -> usize) -> Simulation {
+    let mut sim: Simulation = Simulation::new(seed);
+    for node in 0..count {
+        sim.add_node();
+        sim.node(node).set_name(&format!("n{}", node));
+        if node > 0 {
+            sim.connect(node, parent_of(node));
+        }
+        sim.run_for(secs(1));
+    }
+    sim
+}
+
+fn chain(seed: u64, count: usize) -> Simulation {
+    tree(seed, count, |node| node - 1)
+}
+
+#[test]
+fn message_reaches_every_node() {
+    let mut sim: Simulation = tree(1, 7, |node| (node - 1) / 2);
+    sim.node(6).send("hello from the bottom");
+    sim.run_for(secs(1));
+
+    for node in 0..6 {
+        assert!(sim.delivered(node, "hello from the bottom"), "n{} missed it", node);
+    }
+    assert!(!sim.delivered(6, "hello from the bottom"));
+    assert_eq!(sim.children(0), vec![1, 2]);
+    assert_eq!(sim.children(1), vec![3, 4]);
+    assert_eq!(sim.children(2), vec![5, 6]);
+}
+
+#[test]
+fn messages_too_long_to_send_are_refused_without_cutting_links() {
+    let mut sim: Simulation = chain(21, 3);
+    sim.node(1).send(&"x".repeat(70 * 1024));
+    sim.node(1).send_direct("n2", &"y".repeat(70 * 1024));
+    sim.run_for(secs(1));
+
+    let refused: usize = sim.events(1).iter().filter(|event| matches!(event, ChatEvent::Notice(text) if text.contains("Too Long"))).count();
+    assert_eq!(refused, 2);
+    assert!(!sim.events(0).iter().chain(sim.events(2)).any(|event| matches!(event, ChatEvent::Warning(_))));
+
+    sim.node(1).send("short enough");
+    sim.run_for(secs(1));
+    assert_eq!(sim.parent(1), Some(0));
+    assert_eq!(sim.parent(2), Some(1));
+    assert!(sim.delivered(0, "short enough"));
+    assert!(sim.delivered(2, "short enough"));
+}
+
+#[test]
+fn full_node_sends_newcomers_down_the_tree() {
+    let mut sim: Simulation = tree(2, 5, |_| 0);
+
+    assert_eq!(sim.children(0), vec![1, 2, 3]);
+    let parent: usize = sim.parent(4).expect("n4 should have been sent on to a child");
+    assert_eq!(sim.parent(parent), Some(0));
+
+    sim.node(4).send("still connected");
+    sim.run_for(secs(1));
+    for node in 0..4 {
+        assert!(sim.delivered(node, "still connected"), "n{} missed it", node);
+    }
+}
+
+// A root taking two children, and count more nodes all connecting to it.
+fn crowded_root(seed: u64, count: usize, rebalance: Rebalance) -> Simulation {
+    let mut sim: Simulation = Simulation::new(seed);
+    for node in 0..count {
+        sim.add_node();
+        sim.node(node).set_max_children(2);
+        sim.node(node).set_rebalance(rebalance);
+        sim.node(node).set_name(&format!("n{}", node));
+        if node > 0 {
+            sim.connect(node, 0);
+        }
+        sim.run_for(secs(1));
+    }
+    sim
+}
+
+#[test]
+fn round_robin_takes_children_in_turn() {
+    let sim: Simulation = crowded_root(8, 7, Rebalance::RoundRobin);
+
+    assert_eq!(sim.children(0), vec![1, 2]);
+    assert_eq!(sim.children(1), vec![3, 5]);
+    assert_eq!(sim.children(2), vec![4, 6]);
+}
+
+#[test]
+fn least_loaded_keeps_the_tree_balanced() {
+    let sim: Simulation = crowded_root(9, 15, Rebalance::LeastLoaded);
+
+    for node in 0..7 {
+        assert_eq!(sim.children(node).len(), 2, "n{} should be full", node);
+    }
+    for node in 7..15 {
+        assert!(sim.children(node).is_empty());
+    }
+}
+
+#[test]
+fn least_loaded_and_shallowest_look_at_whole_subtrees() {
+    //n1 gets a wide subtree (4 nodes, 2 deep), n2 a narrow one (3 nodes, 3 deep)
+    let build = |rebalance: Rebalance| -> Simulation {
+        let mut sim: Simulation = tree(10, 8, |node| match node {
+            1 | 2 => 0,
+            3..=5 => 1,
+            6 => 2,
+            _ => 6,
+        });
+        sim.node(0).set_max_children(2);
+        sim.node(0).set_rebalance(rebalance);
+        sim.add_node();
+        sim.connect(8, 0);
+        sim.run_for(secs(1));
+        sim
+    };
+
+    assert_eq!(build(Rebalance::LeastLoaded).parent(8), Some(2));
+    let shallowest: Simulation = build(Rebalance::Shallowest);
+    let parent: usize = shallowest.parent(8).unwrap();
+    assert!(parent == 1 || shallowest.parent(parent) == Some(1));
+}
+
+#[test]
+fn crashed_parent_fails_over_to_grandparent() {
+    let mut sim: Simulation = chain(3, 3);
+    sim.crash(1);
+    sim.run_for(secs(30));
+
+    assert_eq!(sim.parent(2), Some(0));
+    assert!(sim.events(2).iter().any(|event| matches!(event, ChatEvent::Unresponsive { .. })));
+
+    sim.node(2).send("after failover");
+    sim.run_for(secs(1));
+    assert!(sim.delivered(0, "after failover"));
+}
+
+#[test]
+fn failover_walks_past_dead_ancestors() {
+    let mut sim: Simulation = chain(11, 4);
+    sim.crash(1);
+    sim.crash(2);
+    sim.run_for(secs(40));
+
+    assert_eq!(sim.parent(3), Some(0));
+    assert!(sim.events(3).iter().any(|event| matches!(event, ChatEvent::ConnectFailed { .. })));
+    assert!(sim.events(3).iter().any(|event| matches!(event, ChatEvent::FailedOver { .. })));
+
+    sim.node(3).send("two hops up");
+    sim.run_for(secs(1));
+    assert!(sim.delivered(0, "two hops up"));
+}
+
+#[test]
+fn orphans_regroup_under_the_first_sibling_left() {
+    let mut sim: Simulation = tree(12, 4, |_| 0);
+    sim.crash(0);
+    sim.crash(1);
+    sim.run_for(secs(40));
+
+    assert_eq!(sim.parent(2), None);
+    assert!(sim.events(2).iter().any(|event| matches!(event, ChatEvent::Stranded)));
+    assert_eq!(sim.parent(3), Some(2));
+
+    sim.node(3).send("regrouped");
+    sim.run_for(secs(1));
+    assert!(sim.delivered(2, "regrouped"));
+}
+
+#[test]
+fn partition_cuts_links_until_heartbeats_give_up() {
+    let mut sim: Simulation = chain(5, 2);
+    sim.node(1).set_heartbeat(secs(1), 2);
+    sim.partition(&[0], &[1]);
+    sim.node(1).send("lost in the partition");
+    sim.run_for(secs(10));
+
+    assert!(!sim.delivered(0, "lost in the partition"));
+    assert_eq!(sim.parent(1), None);
+    assert!(sim.events(1).iter().any(|event| matches!(event, ChatEvent::Unresponsive { .. })));
+
+    sim.heal();
+    sim.connect(1, 0);
+    sim.node(1).send("back together");
+    sim.run_for(secs(1));
+    assert_eq!(sim.parent(1), Some(0));
+    assert!(sim.delivered(0, "back together"));
+}
+
+#[test]
+fn islands_merge_after_the_partition_heals() {
+    let mut sim: Simulation = tree(13, 5, |node| match node {
+        1 | 2 => 0,
+        _ => 1,
+    });
+    for node in 0..5 {
+        sim.node(node).set_heartbeat(secs(1), 2);
+        sim.node(node).set_probe_interval(secs(5));
+    }
+    sim.partition(&[0, 2], &[1, 3, 4]);
+    sim.run_for(secs(15));
+    assert_eq!(sim.parent(1), None);
+    assert!(sim.events(1).iter().any(|event| matches!(event, ChatEvent::Stranded)));
+
+    sim.heal();
+    sim.run_for(secs(20));
+    let roots: Vec<usize> = (0..5).filter(|node| sim.parent(*node).is_none()).collect();
+    assert_eq!(roots.len(), 1, "still split, roots {:?}", roots);
+
+    sim.node(4).send("one network again");
+    sim.run_for(secs(1));
+    for node in 0..4 {
+        assert!(sim.delivered(node, "one network again"), "n{} missed it", node);
+    }
+}
+
+#[test]
+fn connecting_into_own_subtree_breaks_the_loop() {
+    let mut sim: Simulation = chain(14, 4);
+    sim.connect(1, 3);
+    sim.run_for(secs(2));
+
+    assert!(sim.events(1).iter().any(|event| matches!(event, ChatEvent::Notice(text) if text.contains("Loop"))));
+    assert_eq!(sim.parent(1), None);
+    assert_eq!(sim.parent(2), Some(1));
+    assert_eq!(sim.parent(3), Some(2));
+
+    sim.node(3).send("no echo");
+    sim.run_for(secs(1));
+    assert!(sim.delivered(1, "no echo"));
+    let shown: usize = sim.events(1).iter().filter(|event| matches!(event, ChatEvent::Message { text, .. } if text == "no echo")).count();
+    assert_eq!(shown, 1);
+}
+
+#[test]
+fn nodes_find_each_other_on_the_local_network() {
+    let mut sim: Simulation = Simulation::new(15);
+    for node in 0..3 {
+        sim.add_node();
+        sim.node(node).set_discovery(node != 2);
+        if node < 2 {
+            sim.node(node).set_name(&format!("n{}", node));
+        }
+    }
+    sim.run_for(secs(6));
+
+    let heard: Vec<chat::Neighbor> = sim.node(1).neighbors();
+    assert_eq!(heard.len(), 1, "n2 never announced itself, n1 only hears n0");
+    assert_eq!(heard[0].name.as_deref(), Some("n0"));
+    assert!(sim.events(1).iter().any(|event| matches!(event, ChatEvent::Discovered { .. })));
+    assert!(sim.node(2).neighbors().is_empty());
+
+    sim.node(1).connect(heard[0].addr).unwrap();
+    sim.run_for(secs(1));
+    assert_eq!(sim.parent(1), Some(0));
+
+    sim.crash(0);
+    sim.run_for(secs(20));
+    assert!(sim.node(1).neighbors().is_empty());
+}
+
+#[test]
+fn invite_token_joins_the_network_and_a_private_channel() {
+    let mut sim: Simulation = chain(16, 2);
+    sim.node(0).create_private_channel("secret");
+    let localhost: std::net::IpAddr = std::net::IpAddr::from([127, 0, 0, 1]);
+    let token: String = sim.node(0).invite(&[localhost], Some("secret")).expect("the owner can invite");
+    assert!(sim.node(1).invite(&[localhost], Some("secret")).is_none());
+
+    sim.add_node();
+    sim.node(2).set_name("n2");
+    sim.node(2).join_invite(&token);
+    sim.run_for(secs(1));
+    assert_eq!(sim.parent(2), Some(0));
+    assert_eq!(sim.node(2).current_channel(), Some("secret"));
+
+    sim.node(0).send("only for token holders");
+    sim.run_for(secs(1));
+    assert!(sim.delivered(2, "only for token holders"));
+    assert!(!sim.delivered(1, "only for token holders"));
+
+    sim.add_node();
+    sim.node(3).join_invite("prism:not-a-token");
+    sim.run_for(secs(1));
+    assert!(sim.events(3).iter().any(|event| matches!(event, ChatEvent::Notice(text) if text.contains("Isn't Valid"))));
+}
+
+#[test]
+fn token_joiners_are_members_through_key_rotations() {
+    let mut sim: Simulation = chain(22, 2);
+    sim.node(1).send("hi");
+    sim.run_for(secs(1));
+    sim.node(0).create_private_channel("secret");
+    sim.node(0).invite_member("n1", "secret");
+    let localhost: std::net::IpAddr = std::net::IpAddr::from([127, 0, 0, 1]);
+    let token: String = sim.node(0).invite(&[localhost], Some("secret")).expect("the owner can invite");
+
+    sim.add_node();
+    sim.node(2).set_name("n2");
+    sim.node(2).join_invite(&token);
+    sim.run_for(secs(1));
+    assert!(sim.events(0).iter().any(|event| matches!(event, ChatEvent::Notice(text) if text == "n2 Joined #secret With An Invite")));
+
+    //n1 leaving rotates the key, and n2 gets the new one
+    sim.node(1).join_channel("secret");
+    sim.run_for(secs(1));
+    sim.node(1).leave_channel("secret");
+    sim.run_for(secs(1));
+    assert!(sim.events(0).iter().any(|event| matches!(event, ChatEvent::Notice(text) if text == "n1 Left #secret, Rotating Its Key")));
+    assert!(sim.events(2).iter().any(|event| matches!(event, ChatEvent::KeyRotated { channel } if channel == "secret")));
+    sim.node(0).send("after the rotation");
+    sim.run_for(secs(1));
+    assert!(sim.delivered(2, "after the rotation"));
+    assert!(!sim.delivered(1, "after the rotation"));
+
+    //and n2 leaving rotates it too
+    sim.node(2).leave_channel("secret");
+    sim.run_for(secs(1));
+    assert!(sim.events(0).iter().any(|event| matches!(event, ChatEvent::Notice(text) if text == "n2 Left #secret, Rotating Its Key")));
+}
+
+#[test]
+fn channel_messages_reach_only_members_through_relays_that_are_not() {
+    let mut sim: Simulation = chain(24, 4);
+    sim.node(0).create_channel("dev");
+    sim.run_for(secs(1));
+    for node in 1..4 {
+        assert!(sim.events(node).iter().any(|event| matches!(event, ChatEvent::ChannelCreated { channel } if channel == "dev")), "n{} missed it", node);
+    }
+
+    //n1 and n2 never join, they only pass it along
+    sim.node(3).join_channel("dev");
+    sim.node(0).send("only for dev");
+    sim.node(3).send("dev from the end");
+    sim.run_for(secs(1));
+    assert!(sim.events(3).iter().any(|event| {
+        matches!(event, ChatEvent::ChannelMessage { channel, from, text, .. } if channel == "dev" && from == "n0" && text == "only for dev")
+    }));
+    assert!(sim.events(0).iter().any(|event| {
+        matches!(event, ChatEvent::ChannelMessage { channel, from, text, .. } if channel == "dev" && from == "n3" && text == "dev from the end")
+    }));
+    for node in 1..3 {
+        assert!(!sim.delivered(node, "only for dev"), "n{} saw it", node);
+        assert!(!sim.delivered(node, "dev from the end"), "n{} saw it", node);
+    }
+}
+
+#[test]
+fn leaving_and_switching_channels_decides_what_is_shown_and_where_sends_go() {
+    let mut sim: Simulation = chain(25, 2);
+    sim.node(0).create_channel("dev");
+    sim.run_for(secs(1));
+    sim.node(1).join_channel("dev");
+    assert_eq!(sim.node(1).current_channel(), Some("dev"));
+
+    //switching to the lobby sends there, without leaving dev
+    sim.node(1).switch_channel(None);
+    sim.node(1).send("back in the lobby");
+    sim.node(0).send("still in dev");
+    sim.run_for(secs(1));
+    assert!(sim.events(1).iter().any(|event| matches!(event, ChatEvent::Switched { channel: None })));
+    assert!(sim.events(0).iter().any(|event| matches!(event, ChatEvent::Message { text, .. } if text == "back in the lobby")));
+    assert!(sim.events(1).iter().any(|event| matches!(event, ChatEvent::ChannelMessage { channel, text, .. } if channel == "dev" && text == "still in dev")));
+
+    sim.node(1).switch_channel(Some(String::from("dev")));
+    sim.node(1).switch_channel(Some(String::from("ops")));
+    sim.run_for(secs(1));
+    assert_eq!(sim.node(1).current_channel(), Some("dev"));
+    assert!(sim.events(1).iter().any(|event| matches!(event, ChatEvent::Notice(text) if text == "Join #ops First!")));
+
+    sim.node(1).leave_channel("dev");
+    assert_eq!(sim.node(1).current_channel(), None);
+    sim.node(0).send("after you left");
+    sim.run_for(secs(1));
+    assert!(!sim.delivered(1, "after you left"));
+    sim.node(1).leave_channel("dev");
+    sim.run_for(secs(1));
+    assert!(sim.events(1).iter().any(|event| matches!(event, ChatEvent::Notice(text) if text == "You Are Not In #dev")));
+}
+
+#[test]
+fn history_keeps_messages_per_channel() {
+    let mut sim: Simulation = chain(17, 3);
+    sim.node(2).send("first");
+    sim.node(2).send("second");
+    sim.node(0).create_channel("dev");
+    sim.run_for(secs(1));
+    sim.node(1).join_channel("dev");
+    sim.node(0).send("in dev");
+    sim.run_for(secs(1));
+
+    let lobby: Vec<chat::HistoryEntry> = sim.node(1).history(None, 10);
+    let texts: Vec<&str> = lobby.iter().map(|entry| entry.text.as_str()).collect();
+    assert_eq!(texts, vec!["first", "second"]);
+    assert_eq!(lobby[0].node_id, Some(sim.node(2).node_id()));
+    assert_ne!(lobby[0].id, lobby[1].id);
+    assert_eq!(sim.node(1).history(None, 1)[0].text, "second");
+
+    let dev: Vec<chat::HistoryEntry> = sim.node(1).history(Some("dev"), 10);
+    assert_eq!(dev.len(), 1);
+    assert_eq!(dev[0].from, "n0");
+    let sent: Vec<chat::HistoryEntry> = sim.node(0).history(Some("dev"), 10);
+    assert_eq!(sent.len(), 1);
+    assert_eq!(sent[0].id, dev[0].id);
+    assert!(sim.node(2).history(Some("dev"), 10).is_empty());
+}
+
+#[test]
+fn late_joiners_catch_up_on_what_was_said() {
+    let mut sim: Simulation = chain(18, 2);
+    sim.node(0).send("before anyone else");
+    sim.node(1).send("still early");
+    sim.run_for(secs(1));
+
+    sim.add_node();
+    sim.node(2).set_name("n2");
+    sim.connect(2, 1);
+    sim.run_for(secs(1));
+    assert!(sim.delivered(2, "before anyone else"));
+    assert!(sim.delivered(2, "still early"));
+    assert!(sim.events(2).iter().any(|event| matches!(event, ChatEvent::Notice(text) if text.contains("Catching Up On 2"))));
+
+    //n1 asked n2 for its backlog too, but had all of it already, so nothing went round twice
+    let shown: usize = sim.events(0).iter().filter(|event| matches!(event, ChatEvent::Message { text, .. } if text == "still early")).count();
+    assert_eq!(shown, 1);
+    assert_eq!(sim.node(2).history(None, 10).len(), 2);
+
+    sim.add_node();
+    sim.node(3).set_sync(0, secs(60));
+    sim.connect(3, 0);
+    sim.run_for(secs(1));
+    assert!(!sim.delivered(3, "before anyone else"));
+}
+
+#[test]
+fn private_messages_follow_learned_names_across_hops() {
+    //n3 under n1, n4 under n2, both under n0
+    let mut sim: Simulation = tree(26, 5, |node| match node {
+        3 => 1,
+        4 => 2,
+        _ => 0,
+    });
+    sim.node(3).send_direct("n4", "across the tree");
+    sim.node(4).send_direct("n3", "and back");
+    sim.run_for(secs(1));
+
+    assert!(sim.events(4).iter().any(|event| {
+        matches!(event, ChatEvent::DirectMessage { from, text, trust: chat::Trust::Verified, .. } if from == "n3" && text == "across the tree")
+    }));
+    assert!(sim.events(3).iter().any(|event| {
+        matches!(event, ChatEvent::DirectMessage { from, text, trust: chat::Trust::Verified, .. } if from == "n4" && text == "and back")
+    }));
+    //the nodes it passed through only relayed it
+    for node in 0..3 {
+        assert!(!sim.events(node).iter().any(|event| matches!(event, ChatEvent::DirectMessage { .. })), "n{} was shown it", node);
+    }
+}
+
+#[test]
+fn private_messages_to_unknown_names_reach_nobody() {
+    let mut sim: Simulation = chain(27, 3);
+    sim.node(0).send_direct("nobody", "is anyone there");
+    sim.node(0).send_direct("n0", "talking to myself");
+    sim.run_for(secs(1));
+
+    for node in 0..3 {
+        assert!(!sim.events(node).iter().any(|event| matches!(event, ChatEvent::DirectMessage { .. })), "n{} was shown one", node);
+        assert!(!sim.events(node).iter().any(|event| matches!(event, ChatEvent::Warning(_))), "n{} warned", node);
+    }
+    assert!(sim.events(0).iter().any(|event| matches!(event, ChatEvent::Notice(text) if text == "You Can't Message Yourself!")));
+    assert_eq!(sim.parent(2), Some(1));
+
+    sim.node(0).send_direct("n2", "someone is");
+    sim.run_for(secs(1));
+    assert!(sim.delivered(2, "someone is"));
+}
+
+#[test]
+fn held_private_messages_reach_peers_that_come_back() {
+    let mut sim: Simulation = chain(19, 3);
+    for node in 0..3 {
+        sim.node(node).set_heartbeat(secs(1), 2);
+    }
+    sim.node(1).set_hold(secs(600));
+    sim.partition(&[0, 1], &[2]);
+    sim.run_for(secs(10));
+    assert_eq!(sim.parent(2), None);
+
+    sim.node(0).send_direct("n2", "while you were away");
+    sim.run_for(secs(1));
+    assert!(!sim.delivered(2, "while you were away"));
+
+    sim.heal();
+    sim.connect(2, 1);
+    sim.run_for(secs(2));
+    assert!(sim.delivered(2, "while you were away"));
+    assert!(sim.events(2).iter().any(|event| matches!(event, ChatEvent::DirectMessage { from, trust: chat::Trust::Verified, .. } if from == "n0")));
+    assert!(sim.events(1).iter().any(|event| matches!(event, ChatEvent::Notice(text) if text.contains("Passing On 1 Held"))));
+
+    //n2's receipt emptied n1's mailbox, so coming back again brings nothing twice
+    sim.partition(&[0, 1], &[2]);
+    sim.run_for(secs(10));
+    sim.heal();
+    sim.connect(2, 1);
+    sim.run_for(secs(2));
+    let shown: usize = sim.events(2).iter().filter(|event| matches!(event, ChatEvent::DirectMessage { text, .. } if text == "while you were away")).count();
+    assert_eq!(shown, 1);
+    let passed: usize = sim.events(1).iter().filter(|event| matches!(event, ChatEvent::Notice(text) if text.contains("Held"))).count();
+    assert_eq!(passed, 1);
+}
+
+#[test]
+fn private_messages_too_big_to_pass_on_are_not_held() {
+    let mut sim: Simulation = chain(23, 3);
+    for node in 0..3 {
+        sim.node(node).set_heartbeat(secs(1), 2);
+    }
+    sim.node(1).set_hold(secs(600));
+    sim.partition(&[0, 1], &[2]);
+    sim.run_for(secs(10));
+
+    //fits in a MAIL frame, but not once wrapped in a HELD one
+    let text: String = "x".repeat(64 * 1024 - 226);
+    sim.node(0).send_direct("n2", &text);
+    sim.run_for(secs(1));
+    assert!(!sim.events(0).iter().any(|event| matches!(event, ChatEvent::Notice(text) if text.contains("Too Long"))));
+
+    sim.heal();
+    sim.connect(2, 1);
+    sim.run_for(secs(2));
+    assert!(!sim.events(1).iter().any(|event| matches!(event, ChatEvent::Notice(text) if text.contains("Held"))));
+    assert!(!sim.events(2).iter().any(|event| matches!(event, ChatEvent::Warning(_))));
+    assert_eq!(sim.parent(2), Some(1));
+
+    sim.node(0).send_direct("n2", "short enough");
+    sim.run_for(secs(1));
+    assert!(sim.delivered(2, "short enough"));
+}
+
+#[test]
+fn messages_that_arrive_out_of_order_are_marked_late() {
+    let mut sim: Simulation = chain(20, 2);
+    sim.node(0).send("anyone around?");
+    sim.run_for(secs(1));
+
+    //n1 answers the moment n2 links up, before n2 caught up on the question
+    sim.add_node();
+    sim.node(2).set_name("n2");
+    sim.connect(2, 1);
+    while !sim.events(2).iter().any(|event| matches!(event, ChatEvent::LinkEstablished { .. })) {
+        sim.run_for(std::time::Duration::from_millis(1));
+    }
+    sim.node(1).send("right here");
+    sim.run_for(secs(1));
+
+    let shown: Vec<(String, bool)> = sim.events(2).iter().filter_map(|event| match event {
+        ChatEvent::Message { text, late, sent: Some(_), .. } => Some((text.clone(), *late)),
+        _ => None,
+    }).collect();
+    assert_eq!(shown, vec![(String::from("right here"), false), (String::from("anyone around?"), true)]);
+
+    //history replays them in the order they were said
+    let said: Vec<String> = sim.node(2).history(None, 10).into_iter().map(|entry| entry.text).collect();
+    assert_eq!(said, vec!["anyone around?", "right here"]);
+    assert!(!sim.events(1).iter().any(|event| matches!(event, ChatEvent::Message { late: true, .. })));
+}
+
+#[test]
+fn drops_and_latency_only_delay_messages() {
+    let mut sim: Simulation = Simulation::new(6);
+    sim.set_latency(std::time::Duration::from_millis(20), std::time::Duration::from_millis(80));
+    sim.set_drop_rate(0.3);
+    for node in 0..4 {
+        sim.add_node();
+        sim.node(node).set_name(&format!("n{}", node));
+        if node > 0 {
+            sim.connect(node, node - 1);
+        }
+        sim.run_for(secs(3));
+    }
+
+    for count in 0..10 {
+        sim.node(3).send(&format!("message {}", count));
+    }
+    sim.run_for(secs(10));
+
+    let shown: Vec<String> = sim.events(0).iter().filter_map(|event| match event {
+        ChatEvent::Message { text, .. } => Some(text.clone()),
+        _ => None,
+    }).collect();
+    let sent: Vec<String> = (0..10).map(|count| format!("message {}", count)).collect();
+    assert_eq!(shown, sent);
+}
+
+#[test]
+fn same_seed_plays_out_the_same() {
+    let run = |seed: u64| -> Vec<Vec<String>> {
+        let mut sim: Simulation = Simulation::new(seed);
+        sim.set_drop_rate(0.2);
+        for node in 0..6 {
+            sim.add_node();
+            sim.node(node).set_name(&format!("n{}", node));
+            if node > 0 {
+                sim.connect(node, 0);
+            }
+        }
+        sim.run_for(secs(5));
+        sim.crash(0);
+        sim.run_for(secs(30));
+        (0..6).map(|node| sim.events(node).iter().map(|event| event.to_string()).collect()).collect()
+    };
+
+    assert_eq!(run(7), run(7));
+}